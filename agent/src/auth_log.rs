@@ -0,0 +1,447 @@
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDateTime, TimeZone, Utc};
+use regex::{Captures, Regex};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::OnceLock;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use tracing::{info, warn, debug};
+
+const AUTH_LOG_PATHS: &[&str] = &["/var/log/auth.log", "/var/log/secure"];
+
+// These log their own failure lines, so their pam_unix lines would count every attempt twice
+const SELF_REPORTING_PROGRAMS: &[&str] = &["sshd", "sudo", "su", "login"];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuthFailure {
+    pub service: String,
+    pub username: Option<String>,
+    pub source_ip: Option<IpAddr>,
+    pub reason: String,
+    pub count: u32,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl AuthFailure {
+    pub fn describe(&self) -> String {
+        let mut description = if self.count == 1 {
+            format!("{}: failed authentication ({})", self.service, self.reason)
+        } else {
+            format!("{}: {} failed authentications ({})", self.service, self.count, self.reason)
+        };
+
+        if let Some(source_ip) = self.source_ip {
+            description.push_str(&format!(" from {}", source_ip));
+        }
+
+        description
+    }
+
+    fn new(service: &str, username: Option<&str>, source_ip: Option<&str>, reason: String, timestamp: DateTime<Utc>) -> Self {
+        Self {
+            service: service.to_string(),
+            username: username.map(str::trim).filter(|name| !name.is_empty()).map(str::to_string),
+            source_ip: source_ip.and_then(|ip| ip.trim().parse().ok()),
+            reason,
+            count: 1,
+            timestamp,
+        }
+    }
+}
+
+pub fn is_supported() -> bool {
+    cfg!(target_os = "linux")
+}
+
+fn regex(cell: &'static OnceLock<Regex>, pattern: &str) -> &'static Regex {
+    cell.get_or_init(|| Regex::new(pattern).expect("invalid auth log pattern"))
+}
+
+// Parses one line of /var/log/auth.log or /var/log/secure, with either the traditional
+// "Oct 16 09:12:01" timestamp or the RFC 3339 one newer rsyslog versions write
+pub fn parse_syslog_line(line: &str, now: DateTime<Utc>) -> Option<AuthFailure> {
+    static RFC3339: OnceLock<Regex> = OnceLock::new();
+    static BSD: OnceLock<Regex> = OnceLock::new();
+
+    let (timestamp, rest) = if let Some(caps) = regex(&RFC3339, r"^(\d{4}-\d{2}-\d{2}T\S+)\s+(.*)$").captures(line) {
+        let timestamp = DateTime::parse_from_rfc3339(&caps[1]).ok()?.with_timezone(&Utc);
+        (timestamp, caps.get(2)?.as_str())
+    } else {
+        let caps = regex(&BSD, r"^([A-Z][a-z]{2}\s+\d{1,2} \d{2}:\d{2}:\d{2})\s+(.*)$").captures(line)?;
+        (parse_bsd_timestamp(&caps[1], now).unwrap_or(now), caps.get(2)?.as_str())
+    };
+
+    // "<host> <program>[<pid>]: <message>"
+    let (_host, rest) = rest.split_once(' ')?;
+    let (tag, message) = rest.split_once(": ")?;
+    let program = tag.split('[').next().unwrap_or(tag);
+
+    parse_message(program, message, timestamp)
+}
+
+// Syslog timestamps carry no year or zone; they are local time within the last year
+fn parse_bsd_timestamp(timestamp: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let year = now.with_timezone(&Local).year();
+
+    for year in [year, year - 1] {
+        let naive = NaiveDateTime::parse_from_str(&format!("{} {}", year, timestamp), "%Y %b %e %H:%M:%S").ok()?;
+        let parsed = Local.from_local_datetime(&naive).earliest()?.with_timezone(&Utc);
+
+        if parsed <= now + Duration::days(1) {
+            return Some(parsed);
+        }
+    }
+
+    None
+}
+
+pub fn parse_message(program: &str, message: &str, timestamp: DateTime<Utc>) -> Option<AuthFailure> {
+    static REPEATED: OnceLock<Regex> = OnceLock::new();
+    static PAM: OnceLock<Regex> = OnceLock::new();
+
+    // OpenSSH 9.8 moved authentication into sshd-session
+    let program = if program.starts_with("sshd") { "sshd" } else { program };
+    let message = message.trim();
+
+    // rsyslog collapses identical lines
+    if let Some(caps) = regex(&REPEATED, r"^message repeated (\d+) times: \[ ?(.*?) ?\]$").captures(message) {
+        let mut failure = parse_message(program, &caps[2], timestamp)?;
+        failure.count *= caps[1].parse::<u32>().ok()?;
+        return Some(failure);
+    }
+
+    if let Some(caps) = regex(&PAM, r"^pam_\w+\(([^:]+):auth\): authentication failure;(.*)$").captures(message) {
+        if SELF_REPORTING_PROGRAMS.contains(&program) {
+            return None;
+        }
+
+        let fields = pam_fields(&caps[2]);
+        let username = fields.get("user").or_else(|| fields.get("logname")).map(String::as_str);
+        return Some(AuthFailure::new(&caps[1], username, fields.get("rhost").map(String::as_str), "PAM authentication failure".to_string(), timestamp));
+    }
+
+    match program {
+        "sshd" => parse_sshd(message, timestamp),
+        "sudo" => parse_sudo(message, timestamp),
+        "su" => parse_su(message, timestamp),
+        "login" => parse_login(message, timestamp),
+        _ => None,
+    }
+}
+
+// "logname= uid=0 euid=0 tty=ssh ruser= rhost=203.0.113.9  user=root"
+fn pam_fields(fields: &str) -> HashMap<String, String> {
+    fields.split_whitespace()
+        .filter_map(|field| field.split_once('='))
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+fn parse_sshd(message: &str, timestamp: DateTime<Utc>) -> Option<AuthFailure> {
+    static FAILED: OnceLock<Regex> = OnceLock::new();
+
+    let caps = regex(&FAILED, r"^Failed (\S+) for (invalid user )?(.*?) from (\S+) port \d+").captures(message)?;
+    let reason = if caps.get(2).is_some() {
+        format!("{}, invalid user", &caps[1])
+    } else {
+        caps[1].to_string()
+    };
+
+    Some(AuthFailure::new("sshd", Some(&caps[3]), Some(&caps[4]), reason, timestamp))
+}
+
+fn parse_sudo(message: &str, timestamp: DateTime<Utc>) -> Option<AuthFailure> {
+    static ATTEMPTS: OnceLock<Regex> = OnceLock::new();
+    static DENIED: OnceLock<Regex> = OnceLock::new();
+
+    // sudo logs one line once the user gives up or runs out of attempts
+    if let Some(caps) = regex(&ATTEMPTS, r"^(\S+) : (\d+) incorrect password attempts?").captures(message) {
+        let mut failure = AuthFailure::new("sudo", Some(&caps[1]), None, "incorrect password".to_string(), timestamp);
+        failure.count = caps[2].parse().ok()?;
+        return Some(failure);
+    }
+
+    let caps = regex(&DENIED, r"^(\S+) : (user NOT in sudoers|user NOT authorized on host)").captures(message)?;
+    Some(AuthFailure::new("sudo", Some(&caps[1]), None, caps[2].to_string(), timestamp))
+}
+
+fn parse_su(message: &str, timestamp: DateTime<Utc>) -> Option<AuthFailure> {
+    static UTIL_LINUX: OnceLock<Regex> = OnceLock::new();
+    static SHADOW: OnceLock<Regex> = OnceLock::new();
+
+    // The username is the account su tried to switch to
+    let caps = regex(&UTIL_LINUX, r"^FAILED SU \(to (\S+)\) (\S+) on (\S+)").captures(message)
+        .or_else(|| regex(&SHADOW, r"^FAILED su for (\S+) by (\S+)").captures(message))?;
+
+    let mut reason = format!("su by {}", &caps[2]);
+    if let Some(tty) = caps.get(3) {
+        reason.push_str(&format!(" on {}", tty.as_str()));
+    }
+
+    Some(AuthFailure::new("su", Some(&caps[1]), None, reason, timestamp))
+}
+
+fn parse_login(message: &str, timestamp: DateTime<Utc>) -> Option<AuthFailure> {
+    static SHADOW: OnceLock<Regex> = OnceLock::new();
+    static UTIL_LINUX: OnceLock<Regex> = OnceLock::new();
+
+    // shadow: FAILED LOGIN (1) on '/dev/tty1' [from 'host'] FOR 'bob', Authentication failure
+    if let Some(caps) = regex(&SHADOW, r"^FAILED LOGIN \(\d+\) on '([^']*)'(?: from '([^']*)')? FOR '([^']*)', (.+)$").captures(message) {
+        return Some(login_failure(&caps, 1, 2, 3, 4, timestamp));
+    }
+
+    // util-linux: FAILED LOGIN 1 FROM tty1 FOR bob, Authentication failure
+    let caps = regex(&UTIL_LINUX, r"^FAILED LOGIN \d+ FROM (\S+) FOR (.+?), (.+)$").captures(message)?;
+    Some(login_failure(&caps, 1, 1, 2, 3, timestamp))
+}
+
+fn login_failure(caps: &Captures, tty: usize, from: usize, user: usize, reason: usize, timestamp: DateTime<Utc>) -> AuthFailure {
+    let reason = format!("{} on {}", &caps[reason], &caps[tty]);
+    AuthFailure::new("login", Some(&caps[user]), caps.get(from).map(|from| from.as_str()), reason, timestamp)
+}
+
+// Collapses a batch into one failure per service, user, source and reason
+pub fn aggregate(failures: Vec<AuthFailure>) -> Vec<AuthFailure> {
+    let mut aggregated: Vec<AuthFailure> = Vec::new();
+
+    for failure in failures {
+        let existing = aggregated.iter_mut().find(|existing| {
+            existing.service == failure.service
+                && existing.username == failure.username
+                && existing.source_ip == failure.source_ip
+                && existing.reason == failure.reason
+        });
+
+        match existing {
+            Some(existing) => existing.count += failure.count,
+            None => aggregated.push(failure),
+        }
+    }
+
+    aggregated
+}
+
+// Entries from `journalctl -o export`: "KEY=value" lines, or "KEY\n" followed by a
+// little-endian u64 length and the raw value for binary fields, ending with a blank line
+#[derive(Debug, Default)]
+pub struct JournalExportParser {
+    buffer: Vec<u8>,
+    fields: HashMap<String, String>,
+}
+
+impl JournalExportParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> Vec<HashMap<String, String>> {
+        self.buffer.extend_from_slice(data);
+
+        let mut entries = Vec::new();
+        let mut position = 0;
+
+        while let Some(newline) = self.buffer[position..].iter().position(|&byte| byte == b'\n') {
+            let line = &self.buffer[position..position + newline];
+
+            if line.is_empty() {
+                if !self.fields.is_empty() {
+                    entries.push(std::mem::take(&mut self.fields));
+                }
+                position += 1;
+                continue;
+            }
+
+            if let Some(separator) = line.iter().position(|&byte| byte == b'=') {
+                let key = String::from_utf8_lossy(&line[..separator]).to_string();
+                let value = String::from_utf8_lossy(&line[separator + 1..]).to_string();
+                self.fields.insert(key, value);
+                position += newline + 1;
+                continue;
+            }
+
+            let value_start = position + newline + 1 + 8;
+            if self.buffer.len() < value_start {
+                break;
+            }
+
+            let length = u64::from_le_bytes(self.buffer[value_start - 8..value_start].try_into().unwrap_or_default()) as usize;
+            if self.buffer.len() < value_start + length + 1 {
+                break;
+            }
+
+            let key = String::from_utf8_lossy(line).to_string();
+            let value = String::from_utf8_lossy(&self.buffer[value_start..value_start + length]).to_string();
+            self.fields.insert(key, value);
+            position = value_start + length + 1;
+        }
+
+        self.buffer.drain(..position);
+        entries
+    }
+}
+
+pub fn parse_journal_entry(entry: &HashMap<String, String>) -> Option<AuthFailure> {
+    let program = entry.get("SYSLOG_IDENTIFIER").or_else(|| entry.get("_COMM"))?;
+    let message = entry.get("MESSAGE")?;
+
+    let timestamp = entry.get("__REALTIME_TIMESTAMP")
+        .and_then(|micros| micros.parse::<i64>().ok())
+        .and_then(|micros| Utc.timestamp_micros(micros).single())
+        .unwrap_or_else(Utc::now);
+
+    parse_message(program, message, timestamp)
+}
+
+// Follows new failures in the auth log, or in the journal on systems without one
+pub enum AuthLogReader {
+    File(FileTail),
+    Journal {
+        failures: mpsc::UnboundedReceiver<AuthFailure>,
+        _child: tokio::process::Child,
+    },
+}
+
+impl AuthLogReader {
+    pub async fn open() -> Result<Self, Box<dyn std::error::Error>> {
+        if !is_supported() {
+            return Err("Auth log monitoring is not supported on this platform".into());
+        }
+
+        if let Some(path) = AUTH_LOG_PATHS.iter().map(Path::new).find(|path| path.exists()) {
+            info!("Watching {} for failed authentications", path.display());
+            return Ok(Self::File(FileTail::open(path)?));
+        }
+
+        // auth (4) and authpriv (10); matches on the same field are ORed
+        let mut child = tokio::process::Command::new("journalctl")
+            .args(["--follow", "--lines=0", "--output=export", "SYSLOG_FACILITY=4", "SYSLOG_FACILITY=10"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("No auth log found and journalctl is unavailable: {}", e))?;
+
+        let mut stdout = child.stdout.take().ok_or("journalctl has no stdout")?;
+        let (tx, failures) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut parser = JournalExportParser::new();
+            let mut chunk = vec![0u8; 16 * 1024];
+
+            loop {
+                match stdout.read(&mut chunk).await {
+                    Ok(0) => break,
+                    Ok(read) => {
+                        for entry in parser.push(&chunk[..read]) {
+                            if let Some(failure) = parse_journal_entry(&entry) {
+                                if tx.send(failure).is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Reading journalctl output failed: {}", e);
+                        break;
+                    }
+                }
+            }
+
+            warn!("journalctl exited, failed authentications are no longer monitored");
+        });
+
+        info!("Watching the systemd journal for failed authentications");
+        Ok(Self::Journal { failures, _child: child })
+    }
+
+    pub async fn read_failures(&mut self) -> Result<Vec<AuthFailure>, Box<dyn std::error::Error>> {
+        match self {
+            Self::File(tail) => {
+                let now = Utc::now();
+                Ok(tail.read_lines()?
+                    .iter()
+                    .filter_map(|line| parse_syslog_line(line, now))
+                    .collect())
+            }
+            Self::Journal { failures, .. } => {
+                let mut batch = Vec::new();
+                while let Ok(failure) = failures.try_recv() {
+                    batch.push(failure);
+                }
+                Ok(batch)
+            }
+        }
+    }
+}
+
+// Reads lines appended to a file since the last call, following logrotate
+pub struct FileTail {
+    path: PathBuf,
+    position: u64,
+    inode: u64,
+    partial: String,
+}
+
+impl FileTail {
+    // Starts at the end; failures from before the agent started are not reported
+    pub fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let metadata = std::fs::metadata(path)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            position: metadata.len(),
+            inode: inode(&metadata),
+            partial: String::new(),
+        })
+    }
+
+    pub fn read_lines(&mut self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let metadata = std::fs::metadata(&self.path)?;
+
+        if inode(&metadata) != self.inode || metadata.len() < self.position {
+            debug!("{} was rotated, reading from the start", self.path.display());
+            self.inode = inode(&metadata);
+            self.position = 0;
+            self.partial.clear();
+        }
+
+        if metadata.len() == self.position {
+            return Ok(Vec::new());
+        }
+
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.position))?;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        self.position += data.len() as u64;
+
+        self.partial.push_str(&String::from_utf8_lossy(&data));
+        let complete = match self.partial.rfind('\n') {
+            Some(end) => {
+                let rest = self.partial.split_off(end + 1);
+                std::mem::replace(&mut self.partial, rest)
+            }
+            None => return Ok(Vec::new()),
+        };
+
+        Ok(complete.lines().map(str::to_string).collect())
+    }
+}
+
+#[cfg(unix)]
+fn inode(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &std::fs::Metadata) -> u64 {
+    0
+}
@@ -0,0 +1,61 @@
+use tracing::info;
+
+#[allow(dead_code)]
+#[path = "../relay.rs"]
+mod relay;
+
+#[path = "../tls.rs"]
+mod tls;
+
+use relay::RelayServer;
+
+const USAGE: &str = "\
+Usage: device-notifier-relay [command]
+
+Commands:
+  serve                      Run the relay (the default)
+  device-token <device-id>   Print the relay.token for an agent's config
+
+Environment:
+  RELAY_TOKEN                Secret the Discord bot authenticates with (required)
+  RELAY_BIND                 Address to listen on, 127.0.0.1:7421 by default
+  RELAY_TLS_CERT             PEM certificate chain; serve HTTPS with RELAY_TLS_KEY
+  RELAY_TLS_KEY              PEM private key";
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let token = std::env::var("RELAY_TOKEN").map_err(|_| "RELAY_TOKEN is required")?;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [] | ["serve"] => {}
+        ["device-token", device_id] => {
+            println!("{}", relay::device_token(&token, device_id)?);
+            return Ok(());
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return Err("Unknown command".into());
+        }
+    }
+
+    tracing_subscriber::fmt::init();
+
+    // Loopback unless told otherwise, so the relay sits behind a TLS proxy or serves TLS itself
+    let bind_address = std::env::var("RELAY_BIND").unwrap_or_else(|_| "127.0.0.1:7421".to_string());
+    let cert_path = std::env::var("RELAY_TLS_CERT").ok();
+    let key_path = std::env::var("RELAY_TLS_KEY").ok();
+    let tls = relay::load_tls(cert_path.as_deref(), key_path.as_deref())?;
+
+    let server = RelayServer::bind(&bind_address, token, tls).await?;
+    info!("Device Notifier relay started");
+
+    tokio::select! {
+        _ = server.serve() => {}
+        _ = tokio::signal::ctrl_c() => {
+            info!("Shutdown signal received, stopping relay...");
+        }
+    }
+
+    Ok(())
+}
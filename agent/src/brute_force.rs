@@ -0,0 +1,148 @@
+use crate::auth_log::AuthFailure;
+use crate::config::BruteForceConfig;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BruteForceTarget {
+    User,
+    Source,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BruteForceAlert {
+    pub target: BruteForceTarget,
+    pub key: String,
+    pub failures: u32,
+    pub window_seconds: u64,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub users: Vec<String>,
+    pub sources: Vec<IpAddr>,
+    pub services: Vec<String>,
+}
+
+impl BruteForceAlert {
+    pub fn describe(&self) -> String {
+        let subject = match self.target {
+            BruteForceTarget::User => format!("user {}", self.key),
+            BruteForceTarget::Source => format!("source {}", self.key),
+        };
+
+        let mut description = format!(
+            "{} failed authentications for {} within {}s via {}",
+            self.failures, subject, self.window_seconds, self.services.join(", ")
+        );
+
+        match self.target {
+            BruteForceTarget::User if !self.sources.is_empty() => {
+                let sources: Vec<String> = self.sources.iter().map(IpAddr::to_string).collect();
+                description.push_str(&format!(" from {}", sources.join(", ")));
+            }
+            BruteForceTarget::Source if !self.users.is_empty() => {
+                description.push_str(&format!(" targeting {}", self.users.join(", ")));
+            }
+            _ => {}
+        }
+
+        description
+    }
+}
+
+// Correlates failed authentications per user and per source IP over a sliding window.
+// Each key alerts once and is then silent for the cooldown, however long the attack runs.
+#[derive(Debug, Default)]
+pub struct BruteForceDetector {
+    windows: HashMap<(BruteForceTarget, String), VecDeque<AuthFailure>>,
+    last_alerts: HashMap<(BruteForceTarget, String), DateTime<Utc>>,
+}
+
+impl BruteForceDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, failure: &AuthFailure, settings: &BruteForceConfig) -> Vec<BruteForceAlert> {
+        if !settings.enabled {
+            return Vec::new();
+        }
+
+        let mut keys = Vec::new();
+        if let Some(username) = &failure.username {
+            keys.push((BruteForceTarget::User, username.clone()));
+        }
+        if let Some(source_ip) = failure.source_ip {
+            keys.push((BruteForceTarget::Source, source_ip.to_string()));
+        }
+
+        let window = Duration::seconds(settings.window_seconds as i64);
+        let cooldown = Duration::seconds(settings.cooldown_seconds as i64);
+        let now = failure.timestamp;
+
+        let mut alerts = Vec::new();
+        for key in keys {
+            let failures = self.windows.entry(key.clone()).or_default();
+            failures.push_back(failure.clone());
+            while failures.front().is_some_and(|oldest| now - oldest.timestamp > window) {
+                failures.pop_front();
+            }
+
+            let count: u32 = failures.iter().map(|failure| failure.count).sum();
+            if count < settings.max_failures {
+                continue;
+            }
+
+            if let Some(last_alert) = self.last_alerts.get(&key) {
+                if now - *last_alert < cooldown {
+                    continue;
+                }
+            }
+
+            self.last_alerts.insert(key.clone(), now);
+            alerts.push(Self::alert(key, failures, count, settings.window_seconds));
+        }
+
+        alerts
+    }
+
+    // Drops windows and cooldowns that can no longer affect an alert
+    pub fn prune(&mut self, now: DateTime<Utc>, settings: &BruteForceConfig) {
+        let window = Duration::seconds(settings.window_seconds as i64);
+        let cooldown = Duration::seconds(settings.cooldown_seconds as i64);
+
+        self.windows.retain(|_, failures| {
+            failures.back().is_some_and(|newest| now - newest.timestamp <= window)
+        });
+        self.last_alerts.retain(|_, last_alert| now - *last_alert < cooldown);
+    }
+
+    fn alert(key: (BruteForceTarget, String), failures: &VecDeque<AuthFailure>, count: u32, window_seconds: u64) -> BruteForceAlert {
+        let mut users: Vec<String> = failures.iter().filter_map(|failure| failure.username.clone()).collect();
+        users.sort();
+        users.dedup();
+
+        let mut sources: Vec<IpAddr> = failures.iter().filter_map(|failure| failure.source_ip).collect();
+        sources.sort();
+        sources.dedup();
+
+        let mut services: Vec<String> = failures.iter().map(|failure| failure.service.clone()).collect();
+        services.sort();
+        services.dedup();
+
+        let (target, key) = key;
+        BruteForceAlert {
+            target,
+            key,
+            failures: count,
+            window_seconds,
+            first_seen: failures.front().map(|failure| failure.timestamp).unwrap_or_else(Utc::now),
+            last_seen: failures.back().map(|failure| failure.timestamp).unwrap_or_else(Utc::now),
+            users,
+            sources,
+            services,
+        }
+    }
+}
@@ -0,0 +1,401 @@
+use crate::config::{self, Config};
+use crate::control::{self, Request, Secret};
+use crate::discord::{DiscordEvent, EventType};
+use crate::identity::DeviceIdentity;
+use crate::jwt;
+use crate::routing;
+use crate::security::SecurityManager;
+use crate::storage::{SecureStorage, LOCAL_PASSWORD};
+use base64::{Engine as _, engine::general_purpose};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+const DEFAULT_TOKEN_TTL_SECONDS: i64 = 3600;
+
+pub const USAGE: &str = "\
+Usage: device-notifier [--config-dir <path>] [--set <key>=<value>]... [command]
+
+Commands:
+  run                               Run the agent (the default)
+  status                            Show the running agent's status
+  config show                       Print the effective configuration, secrets redacted
+  config set <key>=<value>          Save a setting to config.toml and reload the agent
+  config validate [--file <path>]   Check a configuration file
+  audit export [--format json|csv] [--output <path>]
+                                    Export the audit log
+  audit verify                      Check the audit log's hash chain for tampering
+  history [--limit <n>]             List remote commands the agent executed
+  emergency-disable                 Switch off remote features and stop the agent
+  emergency-enable                  Allow the agent to start again
+  rotate-keys                       Re-encrypt stored data under a new data key
+  password set [--name <name>]      Set a local password; the default, local, approves apps
+  password change [--name <name>]   Change a local password
+  password verify [--name <name>]   Check a local password
+  test-notify [<EventType>] [--message <text>]
+                                    Send a test event through the running agent
+  route <EventType> [--user <name>] Show where such an event would be delivered
+  pair                              Print what the Discord bot needs to reach this device
+  token [--ttl <seconds>]           Issue a command API token signed with the device key
+  help                              Show this message";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run,
+    Status,
+    ConfigShow,
+    ConfigSet { key: String, value: String },
+    ConfigValidate { file: Option<PathBuf> },
+    AuditExport { format: String, output: Option<PathBuf> },
+    AuditVerify,
+    History { limit: Option<usize> },
+    EmergencyDisable,
+    EmergencyEnable,
+    RotateKeys,
+    PasswordSet { name: String },
+    PasswordChange { name: String },
+    PasswordVerify { name: String },
+    TestNotify { event_type: EventType, message: Option<String> },
+    Route { event_type: EventType, user: Option<String> },
+    Pair,
+    Token { ttl_seconds: i64 },
+    Help,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cli {
+    pub config_dir: Option<PathBuf>,
+    pub overrides: Vec<(String, String)>,
+    pub command: Command,
+}
+
+fn parse_setting(setting: &str) -> Result<(String, String), String> {
+    let (key, value) = setting.split_once('=')
+        .ok_or_else(|| format!("Invalid setting {}, expected key=value", setting))?;
+    Ok((key.trim().to_string(), value.trim().to_string()))
+}
+
+fn parse_event_type(name: &str) -> Result<EventType, String> {
+    serde_json::from_value(serde_json::Value::String(name.to_string()))
+        .map_err(|_| format!("Unknown event type: {}", name))
+}
+
+// --config-dir and --set apply to every command and may appear anywhere; the other
+// options belong to a single command
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Cli, String> {
+    let mut config_dir = None;
+    let mut overrides = Vec::new();
+    let mut words = Vec::new();
+    let mut options: HashMap<String, String> = HashMap::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config-dir" => config_dir = Some(PathBuf::from(args.next().ok_or("--config-dir requires a path")?)),
+            "--set" => overrides.push(parse_setting(&args.next().ok_or("--set requires key=value")?)?),
+            "-h" | "--help" => words = vec!["help".to_string()],
+            option if option.starts_with("--") => {
+                let value = args.next().ok_or_else(|| format!("{} requires a value", option))?;
+                options.insert(option.to_string(), value);
+            }
+            _ => words.push(arg),
+        }
+    }
+
+    let mut option = |name: &str| options.remove(name);
+
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    let command = match words.as_slice() {
+        [] | ["run"] => Command::Run,
+        ["status"] => Command::Status,
+        ["config", "show"] => Command::ConfigShow,
+        ["config", "set", setting] => {
+            let (key, value) = parse_setting(setting)?;
+            Command::ConfigSet { key, value }
+        }
+        ["config", "validate"] => Command::ConfigValidate { file: option("--file").map(PathBuf::from) },
+        ["audit", "export"] => Command::AuditExport {
+            format: option("--format").unwrap_or_else(|| "json".to_string()),
+            output: option("--output").map(PathBuf::from),
+        },
+        ["audit", "verify"] => Command::AuditVerify,
+        ["history"] => Command::History {
+            limit: option("--limit")
+                .map(|limit| limit.parse().map_err(|_| format!("Invalid --limit {}", limit)))
+                .transpose()?,
+        },
+        ["emergency-disable"] => Command::EmergencyDisable,
+        ["emergency-enable"] => Command::EmergencyEnable,
+        ["rotate-keys"] => Command::RotateKeys,
+        ["password", "set"] => Command::PasswordSet { name: option("--name").unwrap_or_else(|| LOCAL_PASSWORD.to_string()) },
+        ["password", "change"] => Command::PasswordChange { name: option("--name").unwrap_or_else(|| LOCAL_PASSWORD.to_string()) },
+        ["password", "verify"] => Command::PasswordVerify { name: option("--name").unwrap_or_else(|| LOCAL_PASSWORD.to_string()) },
+        ["test-notify"] => Command::TestNotify { event_type: EventType::Heartbeat, message: option("--message") },
+        ["test-notify", event_type] => Command::TestNotify { event_type: parse_event_type(event_type)?, message: option("--message") },
+        ["route", event_type] => Command::Route { event_type: parse_event_type(event_type)?, user: option("--user") },
+        ["pair"] => Command::Pair,
+        ["token"] => Command::Token {
+            ttl_seconds: match option("--ttl") {
+                Some(ttl) => ttl.parse().ok().filter(|ttl| *ttl > 0).ok_or_else(|| format!("Invalid --ttl {}", ttl))?,
+                None => DEFAULT_TOKEN_TTL_SECONDS,
+            },
+        },
+        ["help"] => Command::Help,
+        _ => return Err(format!("Unknown command: {}\n\n{}", words.join(" "), USAGE)),
+    };
+
+    if let Some(unused) = options.keys().next() {
+        return Err(format!("{} is not an option of {}", unused, words.join(" ")));
+    }
+
+    Ok(Cli { config_dir, overrides, command })
+}
+
+// Runs every command except `run`, which starts the agent itself
+pub async fn execute(command: Command, overrides: &[(String, String)]) -> Result<(), Box<dyn std::error::Error>> {
+    let socket = control::socket_path()?;
+
+    match command {
+        Command::Run => return Err("run is handled by the agent itself".into()),
+        Command::Help => println!("{}", USAGE),
+        Command::Status => match control::send(&socket, &Request::Status).await? {
+            Some(status) => print_fields(&status, ""),
+            None => {
+                let config = Config::load_with_overrides(overrides)?;
+                if config.is_emergency_disabled() {
+                    println!("Emergency disable is active");
+                }
+                return Err("The agent is not running".into());
+            }
+        },
+        Command::ConfigShow => {
+            let mut config = match control::send(&socket, &Request::ConfigShow).await? {
+                Some(config) => config,
+                None => serde_json::to_value(Config::load_with_overrides(overrides)?)?,
+            };
+            control::redact(&mut config);
+            print!("{}", config::to_toml(&config));
+        }
+        Command::ConfigSet { key, value } => {
+            // Loading with the new value validates it before anything is written
+            let config = Config::load_with_overrides(&[(key.clone(), value)])?;
+            config.persist(&[&key])?;
+
+            match control::send(&socket, &Request::Reload).await? {
+                Some(reloaded) => println!("Saved {}; agent reloaded (changed: {})", key, reloaded["changed"]),
+                None => println!("Saved {}", key),
+            }
+        }
+        Command::ConfigValidate { file } => {
+            let file = match file {
+                Some(file) => file,
+                None => Config::get_config_dir()?.join("config.toml"),
+            };
+            Config::load_from(&file, &Config::process_environment(), overrides)?;
+            println!("{} is valid", file.display());
+        }
+        Command::AuditExport { format, output } => {
+            let exported = match control::send(&socket, &Request::AuditExport { format: format.clone() }).await? {
+                Some(exported) => exported.as_str().unwrap_or_default().as_bytes().to_vec(),
+                None => {
+                    let config = Config::load_with_overrides(overrides)?;
+                    open_storage(config).await?.export_audit_logs(&format).await?
+                }
+            };
+
+            match output {
+                Some(output) => {
+                    std::fs::write(&output, exported)?;
+                    println!("Audit log written to {}", output.display());
+                }
+                None => print!("{}", String::from_utf8_lossy(&exported)),
+            }
+        }
+        Command::History { limit } => {
+            let history = control::send(&socket, &Request::History { limit }).await?
+                .ok_or("The agent is not running; command history is only kept in memory")?;
+
+            for entry in history.as_array().into_iter().flatten() {
+                println!(
+                    "{}  {:<10} {:<20} {}  {}",
+                    entry["timestamp"].as_str().unwrap_or_default(),
+                    entry["command_type"].as_str().unwrap_or_default(),
+                    entry["authorized_user"].as_str().unwrap_or_default(),
+                    if entry["success"].as_bool().unwrap_or(false) { "ok    " } else { "failed" },
+                    entry["details"].as_str().unwrap_or_default()
+                );
+            }
+        }
+        Command::AuditVerify => {
+            let entries = match control::send(&socket, &Request::AuditVerify).await? {
+                Some(verified) => verified["entries"].as_u64().unwrap_or_default() as usize,
+                None => {
+                    let config = Config::load_with_overrides(overrides)?;
+                    open_storage(config).await?.verify_audit_chain().await?
+                }
+            };
+            println!("Audit log intact: {} entries verified", entries);
+        }
+        Command::EmergencyDisable => {
+            if control::send(&socket, &Request::EmergencyDisable).await?.is_none() {
+                Config::load_with_overrides(overrides)?.emergency_disable()?;
+            }
+            println!("Emergency disable activated. Remote features are off and the agent will not start until `device-notifier emergency-enable`.");
+        }
+        Command::EmergencyEnable => {
+            Config::load_with_overrides(overrides)?.emergency_enable()?;
+            println!("Emergency disable lifted. Features it switched off stay off until re-enabled with `device-notifier config set`.");
+        }
+        Command::RotateKeys => {
+            // A running agent holds the keyring in memory, so it has to do the rotation
+            let (version, files) = match control::send(&socket, &Request::RotateKeys).await? {
+                Some(rotated) => (rotated["key_version"].as_u64().unwrap_or_default() as u32, rotated["files"].as_u64().unwrap_or_default() as usize),
+                None => {
+                    let config = Config::load_with_overrides(overrides)?;
+                    open_storage(config).await?.rotate_keys().await?
+                }
+            };
+            println!("Now using data key v{}; re-encrypted {} files", version, files);
+        }
+        // A running agent owns the audit log, so password changes go through it when it is up
+        Command::PasswordSet { name } => {
+            let password = prompt_new_password()?;
+            let request = Request::SetPassword { name: name.clone(), password: Secret(password.clone()) };
+            if control::send(&socket, &request).await?.is_none() {
+                let config = Config::load_with_overrides(overrides)?;
+                open_storage(config).await?.set_password(&name, &password).await?;
+            }
+            println!("Password {} set", name);
+        }
+        Command::PasswordChange { name } => {
+            let current = rpassword::prompt_password("Current password: ")?;
+            let new = prompt_new_password()?;
+            let request = Request::ChangePassword { name: name.clone(), current: Secret(current.clone()), new: Secret(new.clone()) };
+            if control::send(&socket, &request).await?.is_none() {
+                let config = Config::load_with_overrides(overrides)?;
+                open_storage(config).await?.change_password(&name, &current, &new).await?;
+            }
+            println!("Password {} changed", name);
+        }
+        Command::PasswordVerify { name } => {
+            let password = rpassword::prompt_password("Password: ")?;
+            let request = Request::VerifyPassword { name: name.clone(), password: Secret(password.clone()) };
+            let valid = match control::send(&socket, &request).await? {
+                Some(result) => result["valid"].as_bool().unwrap_or(false),
+                None => {
+                    let config = Config::load_with_overrides(overrides)?;
+                    open_storage(config).await?.verify_password(&name, &password).await?
+                }
+            };
+            if !valid {
+                return Err(format!("Incorrect {} password", name).into());
+            }
+            println!("Password {} is correct", name);
+        }
+        Command::TestNotify { event_type, message } => {
+            let route = control::send(&socket, &Request::TestNotify { event_type, message }).await?
+                .ok_or("The agent is not running; start it to send a test notification")?;
+            println!("{}", route.as_str().unwrap_or_default());
+        }
+        Command::Route { event_type, user } => {
+            let config = Config::load_with_overrides(overrides)?;
+            let event = DiscordEvent {
+                device_alias: config.device.alias.clone(),
+                device_id_hash: String::new(),
+                event_type,
+                timestamp: chrono::Utc::now(),
+                user_local: user,
+                notes: None,
+                severity: None,
+            };
+            println!("{}", routing::dry_run(&config, &event));
+        }
+        Command::Pair => pair(overrides).await?,
+        Command::Token { ttl_seconds } => {
+            let storage = open_storage(Config::load_with_overrides(overrides)?).await?;
+            let identity = DeviceIdentity::load_or_create(&storage).await?;
+            println!("{}", issue_token(&identity, ttl_seconds, chrono::Utc::now())?);
+        }
+    }
+
+    Ok(())
+}
+
+fn prompt_new_password() -> Result<String, Box<dyn std::error::Error>> {
+    let password = rpassword::prompt_password("New password: ")?;
+    if password != rpassword::prompt_password("Repeat new password: ")? {
+        return Err("The passwords do not match".into());
+    }
+    Ok(password)
+}
+
+async fn open_storage(config: Config) -> Result<SecureStorage, Box<dyn std::error::Error>> {
+    let config = config.into_shared();
+    let mut storage = SecureStorage::new()?;
+    storage.initialize(config.clone(), SecurityManager::new(config)?).await?;
+    Ok(storage)
+}
+
+// Local callers of the command API send this as "Authorization: Bearer <token>"
+pub fn issue_token(identity: &DeviceIdentity, ttl_seconds: i64, now: chrono::DateTime<chrono::Utc>) -> Result<String, Box<dyn std::error::Error>> {
+    let claims = serde_json::json!({
+        "iss": "device-notifier-cli",
+        "aud": identity.device_id(),
+        "iat": now.timestamp(),
+        "exp": now.timestamp() + ttl_seconds,
+    });
+
+    jwt::encode(&claims, &jwt::SigningKey::EdDsa(identity))
+}
+
+// Lists the device in the bot's .env format; a command secret is generated and saved
+// if none is configured yet
+async fn pair(overrides: &[(String, String)]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load_with_overrides(overrides)?;
+
+    if config.security.hmac_secret.is_none() {
+        let mut secret = [0u8; 32];
+        SystemRandom::new().fill(&mut secret).map_err(|_| "Failed to generate a command secret")?;
+        config.security.hmac_secret = Some(general_purpose::STANDARD.encode(secret));
+        config.persist(&["security.hmac_secret"])?;
+    }
+
+    let storage = open_storage(config.clone()).await?;
+    let identity = DeviceIdentity::load_or_create(&storage).await?;
+
+    // Bot commands take the alias as a single word
+    let alias: String = config.device.alias.to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+
+    println!("Device: {}", config.device.alias);
+    println!("Device id: {}", identity.device_id());
+    println!("Fingerprint: {}", identity.fingerprint());
+    println!("Public key: {}", general_purpose::STANDARD.encode(identity.public_key()));
+    println!();
+    println!("Add to the Discord bot's .env:");
+    println!("DEVICE_IDS={}={}", alias, identity.device_id());
+    if config.api.enabled {
+        let scheme = if config.api.tls_cert_path.is_some() { "https" } else { "http" };
+        let address = config.api.bind_address.replace("0.0.0.0", "<this device's address>");
+        println!("DEVICE_ENDPOINTS={}={}://{}", alias, scheme, address);
+    }
+    println!("HMAC_SECRET={}", config.security.hmac_secret.unwrap_or_default());
+
+    Ok(())
+}
+
+// Prints a JSON object as indented "key: value" lines
+fn print_fields(value: &serde_json::Value, indent: &str) {
+    for (key, value) in value.as_object().into_iter().flatten() {
+        match value {
+            serde_json::Value::Object(_) => {
+                println!("{}{}:", indent, key);
+                print_fields(value, &format!("{}  ", indent));
+            }
+            serde_json::Value::String(text) => println!("{}{}: {}", indent, key, text),
+            other => println!("{}{}: {}", indent, key, other),
+        }
+    }
+}
@@ -1,31 +1,46 @@
-use crate::config::Config;
-use crate::discord::{DiscordClient, DiscordCommand, CommandResponse, CommandType};
+use crate::config::{Config, SharedConfig};
+use crate::discord::{DiscordClient, DiscordCommand, DiscordEvent, EventType, CommandResponse, CommandType, MAX_COMMAND_AGE_SECONDS, MAX_CLOCK_SKEW_SECONDS};
+use crate::identity::DeviceIdentity;
+use crate::jwt;
+use crate::notifier::Notifier;
 use crate::system::SystemManager;
 use crate::security::SecurityManager;
 use crate::storage::SecureStorage;
+use crate::server::CommandServer;
+use crate::relay::RelayClient;
+use crate::replay::ReplayCache;
+use crate::signing;
+use crate::process_guard::{Approval, ProcessGuard};
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tracing::{info, warn, error, debug};
+use tracing::{info, warn, error};
 use chrono::{DateTime, Utc};
 use std::collections::HashMap;
 use std::time::{Duration, Instant};
 
 pub struct CommandExecutor {
+    config: SharedConfig,
     system: Arc<SystemManager>,
+    #[allow(dead_code)]
     security: Arc<SecurityManager>,
     storage: Arc<SecureStorage>,
     command_history: Arc<RwLock<HashMap<String, CommandHistoryEntry>>>,
     rate_limiter: Arc<RwLock<RateLimiter>>,
+    replay_cache: ReplayCache,
+    process_guard: Arc<ProcessGuard>,
+    notifier: Arc<dyn Notifier>,
+    identity: Arc<DeviceIdentity>,
 }
 
-#[derive(Debug, Clone)]
-struct CommandHistoryEntry {
-    command_id: String,
-    command_type: CommandType,
-    authorized_user: String,
-    timestamp: DateTime<Utc>,
-    success: bool,
-    details: String,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandHistoryEntry {
+    pub command_id: String,
+    pub command_type: CommandType,
+    pub authorized_user: String,
+    pub timestamp: DateTime<Utc>,
+    pub success: bool,
+    pub details: String,
 }
 
 struct RateLimiter {
@@ -47,7 +62,7 @@ impl RateLimiter {
         let now = Instant::now();
         let window_start = now - self.window_duration;
         
-        let user_commands = self.commands.entry(user_id.to_string()).or_insert_with(Vec::new);
+        let user_commands = self.commands.entry(user_id.to_string()).or_default();
         
         // Remove old commands outside the window
         user_commands.retain(|&time| time > window_start);
@@ -66,21 +81,77 @@ impl CommandExecutor {
         system: Arc<SystemManager>,
         security: Arc<SecurityManager>,
         storage: Arc<SecureStorage>,
+        process_guard: Arc<ProcessGuard>,
+        notifier: Arc<dyn Notifier>,
+        identity: Arc<DeviceIdentity>,
+        config: SharedConfig,
     ) -> Self {
+        let replay_cache = ReplayCache::new(
+            storage.clone(),
+            chrono::Duration::seconds(MAX_COMMAND_AGE_SECONDS + MAX_CLOCK_SKEW_SECONDS),
+        );
+
         Self {
+            config,
             system,
             security,
             storage,
             command_history: Arc::new(RwLock::new(HashMap::new())),
             rate_limiter: Arc::new(RwLock::new(RateLimiter::new(10, Duration::from_secs(60)))),
+            replay_cache,
+            process_guard,
+            notifier,
+            identity,
+        }
+    }
+
+    pub fn process_guard(&self) -> &Arc<ProcessGuard> {
+        &self.process_guard
+    }
+
+    // Bearer tokens for the command API are JWTs addressed to this device, signed with
+    // the command secret by the bot or with the device key by `device-notifier token`
+    pub async fn verify_api_token(&self, token: &str) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        let secret = self.config.read().await.security.hmac_secret.clone();
+        let api_key = secret.as_deref().map(signing::api_token_key).transpose()?;
+
+        let mut keys = vec![jwt::VerifyingKey::EdDsa(self.identity.public_key())];
+        if let Some(api_key) = &api_key {
+            keys.push(jwt::VerifyingKey::Hs256(api_key));
         }
+        let validation = jwt::Validation {
+            audience: Some(self.identity.device_id().to_string()),
+            ..jwt::Validation::default()
+        };
+
+        jwt::decode(token, &keys, &validation)
     }
 
-    pub async fn start_listening(&self, discord: Arc<DiscordClient>) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn start_listening(self: Arc<Self>, discord: Arc<DiscordClient>) -> Result<(), Box<dyn std::error::Error>> {
         info!("Starting command listener...");
-        
-        // In a real implementation, this would listen for commands from Discord
-        // For now, we'll simulate command processing
+
+        let api_config = self.config.read().await.api.clone();
+        let server_handle = if api_config.enabled {
+            if self.config.read().await.security.hmac_secret.is_none() {
+                return Err("Command API requires security.hmac_secret to be configured".into());
+            }
+
+            let server = CommandServer::bind(&api_config, self.clone(), discord.clone()).await?;
+            Some(tokio::spawn(server.serve()))
+        } else {
+            info!("Command API disabled");
+            None
+        };
+
+        let relay_handle = if self.config.read().await.relay.enabled {
+            let client = self.create_relay_client(discord.device_id()).await?;
+            let executor = self.clone();
+            let discord = discord.clone();
+            Some(tokio::spawn(async move { executor.poll_relay(client, discord).await }))
+        } else {
+            None
+        };
+
         loop {
             tokio::time::sleep(Duration::from_secs(1)).await;
             
@@ -90,10 +161,141 @@ impl CommandExecutor {
                 break;
             }
         }
+
+        if let Some(handle) = server_handle {
+            handle.abort();
+        }
+
+        if let Some(handle) = relay_handle {
+            handle.abort();
+        }
         
         Ok(())
     }
 
+    async fn create_relay_client(&self, device_id: &str) -> Result<RelayClient, Box<dyn std::error::Error>> {
+        let config = self.config.read().await;
+
+        if config.security.hmac_secret.is_none() {
+            return Err("Relay mode requires security.hmac_secret to be configured".into());
+        }
+
+        let url = config.relay.url.as_ref().ok_or("relay.url must be set when relay mode is enabled")?;
+        let token = config.relay.token.as_ref().ok_or("relay.token must be set when relay mode is enabled")?;
+
+        info!("Relay mode enabled, polling {} as device {}", url, device_id);
+        RelayClient::new(url, token, device_id)
+    }
+
+    async fn poll_relay(&self, client: RelayClient, discord: Arc<DiscordClient>) {
+        let wait = Duration::from_secs(self.config.read().await.relay.poll_timeout_seconds);
+        let mut backoff = Duration::from_secs(1);
+
+        loop {
+            let commands = match client.fetch_commands(wait).await.map_err(|e| e.to_string()) {
+                Ok(commands) => {
+                    backoff = Duration::from_secs(1);
+                    commands
+                }
+                Err(e) => {
+                    warn!("Failed to poll relay: {}, retrying in {:?}", e, backoff);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(Duration::from_secs(60));
+                    continue;
+                }
+            };
+
+            for value in commands {
+                let response = match serde_json::from_value::<DiscordCommand>(value.clone()) {
+                    Ok(command) => match self.handle_command(&discord, command).await {
+                        Ok(response) => response,
+                        Err(e) => {
+                            error!("Failed to handle relayed command: {}", e);
+                            continue;
+                        }
+                    },
+                    Err(e) => {
+                        warn!("Malformed command from relay: {}", e);
+                        match value.get("command_id").and_then(|id| id.as_str()) {
+                            Some(command_id) => CommandResponse {
+                                command_id: command_id.to_string(),
+                                success: false,
+                                message: format!("Malformed command: {}", e),
+                                timestamp: Utc::now(),
+                            },
+                            None => continue,
+                        }
+                    }
+                };
+
+                if let Err(e) = client.post_response(&response).await {
+                    warn!("Failed to post command response to relay: {}", e);
+                }
+            }
+        }
+    }
+
+    pub async fn handle_command(&self, discord: &DiscordClient, command: DiscordCommand) -> Result<CommandResponse, Box<dyn std::error::Error>> {
+        if !discord.validate_command(&command).await? {
+            warn!("Rejected command {} from user: {}", command.command_id, command.authorized_user);
+            self.log_rejection("command_rejected", &command, "Command failed validation").await?;
+            return Ok(Self::rejection_response(&command));
+        }
+
+        match self.replay_cache.check_and_record(&command.command_id, command.timestamp).await.map_err(|e| e.to_string()) {
+            Ok(true) => {}
+            Ok(false) => {
+                self.log_rejection("replay_rejected", &command, "Command id already used").await?;
+                return Ok(Self::rejection_response(&command));
+            }
+            Err(e) => {
+                error!("Refusing command {}, replay protection is unavailable: {}", command.command_id, e);
+                self.log_rejection("replay_cache_unavailable", &command, &e).await?;
+
+                let mut response = Self::rejection_response(&command);
+                response.message = "Command rejected: replay protection is unavailable".to_string();
+                return Ok(response);
+            }
+        }
+
+        let config = self.config.read().await.clone();
+        if let Err(reason) = self.validate_command_permissions(&command, &config).await {
+            warn!("Denied command {} from user {}: {}", command.command_id, command.authorized_user, reason);
+            self.log_rejection("command_denied", &command, &reason.to_string()).await?;
+
+            let mut response = Self::rejection_response(&command);
+            response.message = format!("Command denied: {}", reason);
+            return Ok(response);
+        }
+
+        self.execute_command(command).await
+    }
+
+    fn rejection_response(command: &DiscordCommand) -> CommandResponse {
+        CommandResponse {
+            command_id: command.command_id.clone(),
+            success: false,
+            message: "Command rejected".to_string(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    async fn log_rejection(&self, event_type: &str, command: &DiscordCommand, reason: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let log_entry = serde_json::json!({
+            "command_id": command.command_id,
+            "command_type": command.command.as_str(),
+            "authorized_user": command.authorized_user,
+            "roles": command.roles,
+            "device_id": command.device_id,
+            "command_timestamp": command.timestamp.to_rfc3339(),
+            "reason": reason,
+            "timestamp": Utc::now().to_rfc3339()
+        });
+
+        self.storage.log_audit_event(event_type, &log_entry).await?;
+        Ok(())
+    }
+
     pub async fn execute_command(&self, command: DiscordCommand) -> Result<CommandResponse, Box<dyn std::error::Error>> {
         let command_id = command.command_id.clone();
         let authorized_user = command.authorized_user.clone();
@@ -133,10 +335,30 @@ impl CommandExecutor {
             }
             CommandType::Status => {
                 match self.system.get_system_info().await {
-                    Ok(info) => (true, format!("Status: {:?}", info)),
+                    Ok(info) => (true, info.to_string()),
                     Err(e) => (false, format!("Failed to get status: {}", e)),
                 }
             }
+            CommandType::ApproveApp => {
+                let pid = command.arguments.get("pid")
+                    .and_then(|pid| pid.as_u64())
+                    .and_then(|pid| u32::try_from(pid).ok());
+
+                match pid {
+                    Some(pid) => {
+                        let approval = Approval::Remote {
+                            issuer: authorized_user.clone(),
+                            command_id: command_id.clone(),
+                        };
+
+                        match self.process_guard.approve(pid, approval).await {
+                            Ok(()) => (true, format!("Process {} approved and resumed", pid)),
+                            Err(e) => (false, format!("Failed to approve process {}: {}", pid, e)),
+                        }
+                    }
+                    None => (false, "ApproveApp requires a numeric pid argument".to_string()),
+                }
+            }
         };
         
         let response = CommandResponse {
@@ -153,10 +375,30 @@ impl CommandExecutor {
         self.store_command_history(&command_id, &command_type, &authorized_user, success, &details).await?;
         
         info!("Command executed: {:?} - {}", command_type, if success { "SUCCESS" } else { "FAILED" });
+
+        self.notify_executed(&command_type, &authorized_user, success, &details).await;
         
         Ok(response)
     }
 
+    async fn notify_executed(&self, command_type: &CommandType, user: &str, success: bool, details: &str) {
+        let event = {
+            let config = self.config.read().await;
+            if !config.features.audit_logging {
+                return;
+            }
+
+            // Status replies carry the whole system report; the audit log keeps it in full
+            let details: String = details.chars().take(200).collect();
+            let notes = format!("Command '{}' executed: {} - {}", command_type.as_str(), if success { "SUCCESS" } else { "FAILED" }, details);
+            DiscordEvent::new(&config, &self.identity, EventType::CommandExecuted, Some(user.to_string()), Some(notes))
+        };
+
+        if let Err(e) = self.notifier.notify(&event).await.map_err(|e| e.to_string()) {
+            warn!("Failed to send command executed event: {}", e);
+        }
+    }
+
     async fn check_rate_limit(&self, user_id: &str) -> Result<bool, Box<dyn std::error::Error>> {
         let mut rate_limiter = self.rate_limiter.write().await;
         Ok(rate_limiter.can_execute(user_id))
@@ -193,12 +435,14 @@ impl CommandExecutor {
         
         // Keep only last 1000 commands
         if history.len() > 1000 {
-            let mut entries: Vec<_> = history.iter().collect();
-            entries.sort_by_key(|(_, entry)| entry.timestamp);
+            let mut entries: Vec<_> = history.iter()
+                .map(|(key, entry)| (key.clone(), entry.timestamp))
+                .collect();
+            entries.sort_by_key(|(_, timestamp)| *timestamp);
             let to_remove = entries.len() - 1000;
             
             for (key, _) in entries.iter().take(to_remove) {
-                history.remove(*key);
+                history.remove(key);
             }
         }
         
@@ -220,6 +464,7 @@ impl CommandExecutor {
         Ok(entries)
     }
 
+    #[allow(dead_code)]
     pub async fn clear_command_history(&self) -> Result<(), Box<dyn std::error::Error>> {
         let mut history = self.command_history.write().await;
         history.clear();
@@ -275,31 +520,64 @@ impl CommandExecutor {
         Ok(emergency_file.exists())
     }
 
-    pub async fn validate_command_permissions(&self, command: &DiscordCommand, config: &Config) -> Result<bool, Box<dyn std::error::Error>> {
+    pub async fn validate_command_permissions(&self, command: &DiscordCommand, config: &Config) -> Result<(), DenyReason> {
         // Check if remote commands are enabled
         if !config.user_consent.remote_commands_enabled {
-            return Ok(false);
+            return Err(DenyReason::RemoteCommandsDisabled);
         }
         
-        // Check if user is authorized
-        if !config.discord.allowed_users.contains(&command.authorized_user) {
-            return Ok(false);
+        // Explicitly allowed users may run every command
+        if config.discord.allowed_users.contains(&command.authorized_user) {
+            return Ok(());
         }
         
-        // Check if specific command type is allowed
-        match command.command {
-            CommandType::Lock => {
-                // Lock is always allowed if remote commands are enabled
-                Ok(true)
-            }
-            CommandType::Logout => {
-                // Logout might require additional confirmation
-                Ok(true) // In real implementation, check additional permissions
-            }
-            CommandType::Ping | CommandType::Status => {
-                // These are always allowed
-                Ok(true)
+        // Otherwise the issuer needs an allowed role whose allowlist covers the command
+        let roles: Vec<String> = command.roles.iter()
+            .filter(|role| config.discord.allowed_roles.contains(role))
+            .cloned()
+            .collect();
+        
+        if roles.is_empty() {
+            return Err(DenyReason::UnauthorizedIssuer {
+                user: command.authorized_user.clone(),
+            });
+        }
+        
+        let permitted = roles.iter().any(|role| {
+            config.discord.role_permissions
+                .get(role)
+                .map(|commands| commands.contains(&command.command))
+                .unwrap_or(false)
+        });
+        
+        if permitted {
+            Ok(())
+        } else {
+            Err(DenyReason::CommandNotPermitted {
+                command: command.command.clone(),
+                roles,
+            })
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DenyReason {
+    RemoteCommandsDisabled,
+    UnauthorizedIssuer { user: String },
+    CommandNotPermitted { command: CommandType, roles: Vec<String> },
+}
+
+impl std::fmt::Display for DenyReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DenyReason::RemoteCommandsDisabled => write!(f, "remote commands are disabled on this device"),
+            DenyReason::UnauthorizedIssuer { user } => write!(f, "user {} is not authorized", user),
+            DenyReason::CommandNotPermitted { command, roles } => {
+                write!(f, "{} is not permitted for roles [{}]", command.as_str(), roles.join(", "))
             }
         }
     }
 }
+
+impl std::error::Error for DenyReason {}
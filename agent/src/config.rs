@@ -1,10 +1,26 @@
+use crate::discord::{CommandType, EventType, Severity};
+use crate::health;
+use crate::keystore;
+use crate::network;
+use crate::process_guard::AppMatcher;
+use crate::routing;
+use crate::sinks;
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::collections::HashMap;
-use config::{Config as ConfigFile, File};
-use dirs;
-use tracing::{info, warn, error};
+use std::sync::RwLock;
+use config::{builder::DefaultState, Config as ConfigFile, ConfigBuilder, ConfigError, Environment, File, FileFormat};
+use toml_edit::{DocumentMut, Item, Table};
+use tracing::{info, warn};
+
+pub const ENV_PREFIX: &str = "DEVICE_NOTIFIER";
+pub const CONFIG_DIR_ENV: &str = "DEVICE_NOTIFIER_CONFIG_DIR";
+
+static CONFIG_DIR_OVERRIDE: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+// The one live configuration shared by every subsystem; swapped in place on reload
+pub type SharedConfig = std::sync::Arc<tokio::sync::RwLock<Config>>;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -13,7 +29,15 @@ pub struct Config {
     pub features: FeatureConfig,
     pub security: SecurityConfig,
     pub app_rules: HashMap<String, AppRule>,
+    pub notifiers: HashMap<String, NotifierConfig>,
+    pub routes: Vec<RoutingRule>,
+    pub dedup: DedupConfig,
+    pub digest: DigestConfig,
+    pub health: HealthConfig,
+    pub network: NetworkConfig,
     pub device: DeviceConfig,
+    pub api: ApiConfig,
+    pub relay: RelayConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,6 +56,9 @@ pub struct DiscordConfig {
     pub webhook_url: Option<String>,
     pub allowed_users: Vec<String>,
     pub allowed_roles: Vec<String>,
+    pub role_permissions: HashMap<String, Vec<CommandType>>,
+    // Event types posted to the Discord webhook; empty means all of them
+    pub events: Vec<EventType>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -49,33 +76,437 @@ pub struct SecurityConfig {
     pub command_timeout_seconds: u64,
     pub max_commands_per_minute: u32,
     pub require_local_auth_for_critical: bool,
+    pub app_approval_timeout_seconds: u64,
+    pub brute_force: BruteForceConfig,
+    pub encryption: EncryptionConfig,
 }
 
+// Where the master key that unwraps the storage encryption keys comes from
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionConfig {
+    pub master_key: MasterKeySource,
+    // Defaults to keys/master.key in the config directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyfile: Option<String>,
+    // Environment variable the passphrase is read from
+    pub passphrase_env: String,
+    // Passphrase supplied directly, taking the place of passphrase_env. Never read from
+    // or written to the config file.
+    #[serde(skip)]
+    pub passphrase: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MasterKeySource {
+    Keyfile,
+    Passphrase,
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        Self {
+            master_key: MasterKeySource::Keyfile,
+            keyfile: None,
+            passphrase_env: "DEVICE_NOTIFIER_PASSPHRASE".to_string(),
+            passphrase: None,
+        }
+    }
+}
+
+// Escalates when failed authentications for one user or from one source reach
+// max_failures within window_seconds, then stays quiet for cooldown_seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BruteForceConfig {
+    pub enabled: bool,
+    pub max_failures: u32,
+    pub window_seconds: u64,
+    pub cooldown_seconds: u64,
+    pub response: BruteForceResponse,
+    // Run with BRUTE_FORCE_* environment variables describing the alert
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hook_command: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BruteForceResponse {
+    None,
+    LockScreen,
+    Hook,
+}
+
+impl Default for BruteForceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_failures: 5,
+            window_seconds: 300,
+            cooldown_seconds: 900,
+            response: BruteForceResponse::None,
+            hook_command: None,
+        }
+    }
+}
+
+// Rules match on the rule name unless executable, path or pattern is given, in which
+// case every matcher that is set must match
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct AppRule {
     pub requires_remote_password: bool,
     pub requires_local_password: bool,
     pub blocked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub custom_message: Option<String>,
+    // Glob matched against the process or executable file name, e.g. "steam*"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub executable: Option<String>,
+    // Glob matched against the full executable path, e.g. "/opt/games/**"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    // Regex matched against the executable name or full path
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+}
+
+// An additional notification sink. Which fields apply depends on the type; see sinks.rs
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifierConfig {
+    #[serde(rename = "type")]
+    pub kind: Option<NotifierKind>,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    // Event types this sink receives; empty means all of them
+    pub events: Vec<EventType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<String>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+    // Overrides the priority derived from the event type (ntfy 1-5, Gotify 0-10)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub smtp_host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub smtp_port: Option<u16>,
+    pub smtp_tls: SmtpTls,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    pub to: Vec<String>,
+    // Syslog destination: udp://host:port, tcp://host:port or unix:///dev/log
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifierKind {
+    Webhook,
+    Slack,
+    Matrix,
+    Ntfy,
+    Gotify,
+    Email,
+    Syslog,
+}
+
+impl NotifierKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotifierKind::Webhook => "webhook",
+            NotifierKind::Slack => "slack",
+            NotifierKind::Matrix => "matrix",
+            NotifierKind::Ntfy => "ntfy",
+            NotifierKind::Gotify => "gotify",
+            NotifierKind::Email => "email",
+            NotifierKind::Syslog => "syslog",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpTls {
+    None,
+    #[default]
+    Starttls,
+    Tls,
+}
+
+// Evaluated in order; the first rule whose conditions all match decides where an event
+// goes. An empty condition matches anything, and empty destinations drop the event.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingRule {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub events: Vec<EventType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_severity: Option<Severity>,
+    pub users: Vec<String>,
+    pub device_tags: Vec<String>,
+    // "discord" or names from [notifiers]
+    pub destinations: Vec<String>,
+}
+
+// Identical events (same type, user and details) within window_seconds are sent once
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DedupConfig {
+    pub enabled: bool,
+    pub window_seconds: u64,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window_seconds: 900,
+        }
+    }
+}
+
+// Events at or below max_severity are held back and sent as one summary per interval
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DigestConfig {
+    pub enabled: bool,
+    pub interval: DigestInterval,
+    pub max_severity: Severity,
+}
+
+impl Default for DigestConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: DigestInterval::Hourly,
+            max_severity: Severity::Info,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DigestInterval {
+    Hourly,
+    Daily,
+}
+
+// Resource checks run every interval_seconds. Each check alerts once its value has
+// stayed above a threshold for the check's duration; unset fields use the defaults
+// in health.rs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    pub enabled: bool,
+    pub interval_seconds: u64,
+    pub cpu: HealthThreshold,
+    pub memory: HealthThreshold,
+    pub swap: HealthThreshold,
+    pub disk: HealthThreshold,
+    // Load average per CPU core
+    pub load: HealthThreshold,
+    // Degrees Celsius, from any sensor
+    pub temperature: HealthThreshold,
+    pub processes: HealthThreshold,
+    // Mount points the disk check covers; empty means every mounted disk
+    pub mounts: Vec<String>,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_seconds: 60,
+            cpu: HealthThreshold::default(),
+            memory: HealthThreshold::default(),
+            swap: HealthThreshold::default(),
+            disk: HealthThreshold::default(),
+            load: HealthThreshold::default(),
+            temperature: HealthThreshold::default(),
+            processes: HealthThreshold::default(),
+            mounts: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthThreshold {
+    pub enabled: bool,
+    pub warning: Option<f64>,
+    pub critical: Option<f64>,
+    pub duration_seconds: Option<u64>,
+}
+
+// Connectivity probes and interface change detection. Without probes only the default
+// gateway is checked, so nothing leaves the local network unless configured to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub enabled: bool,
+    pub interval_seconds: u64,
+    pub timeout_seconds: u64,
+    pub probes: Vec<ProbeConfig>,
+    // Report address, Wi-Fi and VPN changes
+    pub detect_changes: bool,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_seconds: 30,
+            timeout_seconds: 5,
+            probes: Vec::new(),
+            detect_changes: true,
+        }
+    }
+}
+
+// target is host:port for tcp, a host name for dns and a URL for http
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProbeConfig {
+    #[serde(rename = "type")]
+    pub kind: ProbeKind,
+    #[serde(default)]
+    pub target: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeKind {
+    Tcp,
+    Dns,
+    Http,
+    Gateway,
+}
+
+impl ProbeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProbeKind::Tcp => "tcp",
+            ProbeKind::Dns => "dns",
+            ProbeKind::Http => "http",
+            ProbeKind::Gateway => "gateway",
+        }
+    }
+}
+
+impl Default for HealthThreshold {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            warning: None,
+            critical: None,
+            duration_seconds: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceConfig {
     pub alias: String,
-    pub device_id: String,
+    pub tags: Vec<String>,
     pub platform: String,
     pub version: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiConfig {
+    pub enabled: bool,
+    pub bind_address: String,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    // Require a JWT bearer token, signed with a key derived from security.hmac_secret or
+    // with the device key, on every request except the health check and local approvals
+    pub require_token: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayConfig {
+    pub enabled: bool,
+    pub url: Option<String>,
+    // This device's token, printed by `device-notifier-relay device-token <device id>`
+    pub token: Option<String>,
+    pub poll_timeout_seconds: u64,
+}
+
 impl Config {
+    pub fn into_shared(self) -> SharedConfig {
+        std::sync::Arc::new(tokio::sync::RwLock::new(self))
+    }
+
+    #[allow(dead_code)]
     pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
-        let config_dir = Self::get_config_dir()?;
-        let config_file = config_dir.join("config.toml");
-        
-        let mut config_builder = ConfigFile::builder();
-        
-        // Add default configuration
-        config_builder = config_builder
+        Self::load_with_overrides(&[])
+    }
+
+    pub fn load_with_overrides(overrides: &[(String, String)]) -> Result<Self, Box<dyn std::error::Error>> {
+        let config_file = Self::get_config_dir()?.join("config.toml");
+        Self::load_from(&config_file, &Self::process_environment(), overrides)
+    }
+
+    pub fn process_environment() -> Vec<(String, String)> {
+        std::env::vars().collect()
+    }
+
+    // Layers, lowest priority first: built-in defaults, config.toml, DEVICE_NOTIFIER_*
+    // variables from environment (nested keys use "__", e.g. DEVICE_NOTIFIER_API__ENABLED=true)
+    // and command line overrides. Loading never writes the file back; see persist.
+    pub fn load_from(config_file: &Path, environment: &[(String, String)], overrides: &[(String, String)]) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config_builder = Self::defaults()?
+            .add_source(File::from(config_file.to_path_buf()).format(FileFormat::Toml).required(false))
+            .add_source(
+                Environment::with_prefix(ENV_PREFIX)
+                    .prefix_separator("_")
+                    .separator("__")
+                    .source(Some(environment.iter().cloned().collect())),
+            );
+
+        for (key, value) in overrides {
+            config_builder = config_builder.set_override(key.as_str(), value.as_str())?;
+        }
+
+        let config = Self::from_config_file(&config_builder.build()?)?;
+        config.validate()?;
+
+        info!("Configuration loaded from {}", config_file.display());
+        Ok(config)
+    }
+
+    #[allow(dead_code)]
+    pub fn from_toml_str(contents: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let config_file = Self::defaults()?
+            .add_source(File::from_str(contents, FileFormat::Toml))
+            .build()?;
+
+        let config = Self::from_config_file(&config_file)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn defaults() -> Result<ConfigBuilder<DefaultState>, ConfigError> {
+        ConfigFile::builder()
             .set_default("user_consent.telemetry_enabled", false)?
             .set_default("user_consent.remote_commands_enabled", false)?
             .set_default("user_consent.discord_integration_enabled", false)?
@@ -88,98 +519,301 @@ impl Config {
             .set_default("security.command_timeout_seconds", 30)?
             .set_default("security.max_commands_per_minute", 10)?
             .set_default("security.require_local_auth_for_critical", true)?
+            .set_default("security.app_approval_timeout_seconds", 120)?
             .set_default("device.platform", std::env::consts::OS)?
-            .set_default("device.version", env!("CARGO_PKG_VERSION"))?;
+            .set_default("device.version", env!("CARGO_PKG_VERSION"))?
+            .set_default("api.enabled", false)?
+            .set_default("api.bind_address", "127.0.0.1:7420")?
+            .set_default("relay.enabled", false)?
+            .set_default("relay.poll_timeout_seconds", 30)
+    }
 
-        // Load existing config file if it exists
-        if config_file.exists() {
-            config_builder = config_builder.add_source(File::from(config_file));
-        }
+    fn from_config_file(config: &ConfigFile) -> Result<Self, Box<dyn std::error::Error>> {
+        let app_rules: HashMap<String, AppRule> = match config.get("app_rules") {
+            Ok(rules) => rules,
+            Err(ConfigError::NotFound(_)) => HashMap::new(),
+            Err(e) => return Err(format!("Invalid app_rules: {}", e).into()),
+        };
 
-        let config = config_builder.build()?;
-        
-        let mut app_rules = HashMap::new();
-        if let Ok(rules) = config.get_table("app_rules") {
-            for (app_name, rule_value) in rules {
-                if let Ok(rule) = serde_json::from_value(serde_json::Value::String(rule_value.to_string())) {
-                    app_rules.insert(app_name, rule);
-                }
-            }
-        }
+        let notifiers: HashMap<String, NotifierConfig> = match config.get("notifiers") {
+            Ok(notifiers) => notifiers,
+            Err(ConfigError::NotFound(_)) => HashMap::new(),
+            Err(e) => return Err(format!("Invalid notifiers: {}", e).into()),
+        };
+
+        let routes: Vec<RoutingRule> = match config.get("routes") {
+            Ok(routes) => routes,
+            Err(ConfigError::NotFound(_)) => Vec::new(),
+            Err(e) => return Err(format!("Invalid routes: {}", e).into()),
+        };
+
+        let dedup: DedupConfig = match config.get("dedup") {
+            Ok(dedup) => dedup,
+            Err(ConfigError::NotFound(_)) => DedupConfig::default(),
+            Err(e) => return Err(format!("Invalid dedup: {}", e).into()),
+        };
+
+        let digest: DigestConfig = match config.get("digest") {
+            Ok(digest) => digest,
+            Err(ConfigError::NotFound(_)) => DigestConfig::default(),
+            Err(e) => return Err(format!("Invalid digest: {}", e).into()),
+        };
+
+        let health: HealthConfig = match config.get("health") {
+            Ok(health) => health,
+            Err(ConfigError::NotFound(_)) => HealthConfig::default(),
+            Err(e) => return Err(format!("Invalid health: {}", e).into()),
+        };
+
+        let network: NetworkConfig = match config.get("network") {
+            Ok(network) => network,
+            Err(ConfigError::NotFound(_)) => NetworkConfig::default(),
+            Err(e) => return Err(format!("Invalid network: {}", e).into()),
+        };
+
+        let brute_force: BruteForceConfig = match config.get("security.brute_force") {
+            Ok(brute_force) => brute_force,
+            Err(ConfigError::NotFound(_)) => BruteForceConfig::default(),
+            Err(e) => return Err(format!("Invalid security.brute_force: {}", e).into()),
+        };
+
+        let encryption: EncryptionConfig = match config.get("security.encryption") {
+            Ok(encryption) => encryption,
+            Err(ConfigError::NotFound(_)) => EncryptionConfig::default(),
+            Err(e) => return Err(format!("Invalid security.encryption: {}", e).into()),
+        };
+
+        let role_permissions: HashMap<String, Vec<CommandType>> = match config.get("discord.role_permissions") {
+            Ok(role_permissions) => role_permissions,
+            Err(ConfigError::NotFound(_)) => HashMap::new(),
+            Err(e) => return Err(format!("Invalid discord.role_permissions: {}", e).into()),
+        };
+
+        let events: Vec<EventType> = match config.get("discord.events") {
+            Ok(events) => events,
+            Err(ConfigError::NotFound(_)) => Vec::new(),
+            Err(e) => return Err(format!("Invalid discord.events: {}", e).into()),
+        };
 
         let device_config = DeviceConfig {
-            alias: config.get_string("device.alias").unwrap_or_else(|_| "Unknown Device".to_string()),
-            device_id: Self::generate_device_id(),
-            platform: config.get_string("device.platform").unwrap_or_else(|_| std::env::consts::OS.to_string()),
-            version: config.get_string("device.version").unwrap_or_else(|_| env!("CARGO_PKG_VERSION").to_string()),
+            alias: setting(config, "device.alias")?.unwrap_or_else(|| "Unknown Device".to_string()),
+            tags: get_string_list(config, "device.tags"),
+            platform: setting(config, "device.platform")?.unwrap_or_else(|| std::env::consts::OS.to_string()),
+            version: setting(config, "device.version")?.unwrap_or_else(|| env!("CARGO_PKG_VERSION").to_string()),
         };
 
         let user_consent = UserConsent {
-            telemetry_enabled: config.get_bool("user_consent.telemetry_enabled").unwrap_or(false),
-            remote_commands_enabled: config.get_bool("user_consent.remote_commands_enabled").unwrap_or(false),
-            discord_integration_enabled: config.get_bool("user_consent.discord_integration_enabled").unwrap_or(false),
+            telemetry_enabled: setting(config, "user_consent.telemetry_enabled")?.unwrap_or(false),
+            remote_commands_enabled: setting(config, "user_consent.remote_commands_enabled")?.unwrap_or(false),
+            discord_integration_enabled: setting(config, "user_consent.discord_integration_enabled")?.unwrap_or(false),
             consent_timestamp: None, // Will be set when consent is given
-            consent_version: config.get_string("user_consent.consent_version").unwrap_or_else(|_| "1.0".to_string()),
+            consent_version: setting(config, "user_consent.consent_version")?.unwrap_or_else(|| "1.0".to_string()),
         };
 
         let discord_config = DiscordConfig {
-            bot_token: config.get_string("discord.bot_token").ok(),
-            channel_id: config.get_string("discord.channel_id").ok(),
-            webhook_url: config.get_string("discord.webhook_url").ok(),
-            allowed_users: config.get_array("discord.allowed_users").unwrap_or_default().iter()
-                .filter_map(|v| v.to_string().ok()).collect(),
-            allowed_roles: config.get_array("discord.allowed_roles").unwrap_or_default().iter()
-                .filter_map(|v| v.to_string().ok()).collect(),
+            bot_token: setting(config, "discord.bot_token")?,
+            channel_id: setting(config, "discord.channel_id")?,
+            webhook_url: setting(config, "discord.webhook_url")?,
+            allowed_users: get_string_list(config, "discord.allowed_users"),
+            allowed_roles: get_string_list(config, "discord.allowed_roles"),
+            role_permissions,
+            events,
         };
 
         let features = FeatureConfig {
-            login_notifications: config.get_bool("features.login_notifications").unwrap_or(false),
-            logout_notifications: config.get_bool("features.logout_notifications").unwrap_or(false),
-            failed_auth_notifications: config.get_bool("features.failed_auth_notifications").unwrap_or(false),
-            heartbeat_enabled: config.get_bool("features.heartbeat_enabled").unwrap_or(true),
-            audit_logging: config.get_bool("features.audit_logging").unwrap_or(true),
+            login_notifications: setting(config, "features.login_notifications")?.unwrap_or(false),
+            logout_notifications: setting(config, "features.logout_notifications")?.unwrap_or(false),
+            failed_auth_notifications: setting(config, "features.failed_auth_notifications")?.unwrap_or(false),
+            heartbeat_enabled: setting(config, "features.heartbeat_enabled")?.unwrap_or(true),
+            audit_logging: setting(config, "features.audit_logging")?.unwrap_or(true),
         };
 
         let security = SecurityConfig {
-            hmac_secret: config.get_string("security.hmac_secret").ok(),
-            command_timeout_seconds: config.get_int("security.command_timeout_seconds").unwrap_or(30) as u64,
-            max_commands_per_minute: config.get_int("security.max_commands_per_minute").unwrap_or(10) as u32,
-            require_local_auth_for_critical: config.get_bool("security.require_local_auth_for_critical").unwrap_or(true),
+            hmac_secret: setting(config, "security.hmac_secret")?,
+            command_timeout_seconds: unsigned_setting(config, "security.command_timeout_seconds")?.unwrap_or(30),
+            max_commands_per_minute: unsigned_setting(config, "security.max_commands_per_minute")?.unwrap_or(10),
+            require_local_auth_for_critical: setting(config, "security.require_local_auth_for_critical")?.unwrap_or(true),
+            app_approval_timeout_seconds: unsigned_setting(config, "security.app_approval_timeout_seconds")?.unwrap_or(120),
+            brute_force,
+            encryption,
+        };
+
+        let api = ApiConfig {
+            enabled: setting(config, "api.enabled")?.unwrap_or(false),
+            bind_address: setting(config, "api.bind_address")?.unwrap_or_else(|| "127.0.0.1:7420".to_string()),
+            tls_cert_path: setting(config, "api.tls_cert_path")?,
+            tls_key_path: setting(config, "api.tls_key_path")?,
+            require_token: setting(config, "api.require_token")?.unwrap_or(false),
         };
 
-        let config = Config {
+        let relay = RelayConfig {
+            enabled: setting(config, "relay.enabled")?.unwrap_or(false),
+            url: setting(config, "relay.url")?,
+            token: setting(config, "relay.token")?,
+            poll_timeout_seconds: unsigned_setting(config, "relay.poll_timeout_seconds")?.unwrap_or(30),
+        };
+
+        Ok(Config {
             user_consent,
-            discord: DiscordConfig {
-                bot_token: config.get_string("discord.bot_token").ok(),
-                channel_id: config.get_string("discord.channel_id").ok(),
-                webhook_url: config.get_string("discord.webhook_url").ok(),
-                allowed_users: config.get_array("discord.allowed_users").unwrap_or_default().iter()
-                    .filter_map(|v| v.to_string().ok()).collect(),
-                allowed_roles: config.get_array("discord.allowed_roles").unwrap_or_default().iter()
-                    .filter_map(|v| v.to_string().ok()).collect(),
-            },
+            discord: discord_config,
             features,
             security,
             app_rules,
+            notifiers,
+            routes,
+            dedup,
+            digest,
+            health,
+            network,
             device: device_config,
-        };
+            api,
+            relay,
+        })
+    }
 
-        // Save the configuration
-        config.save()?;
-        
-        info!("Configuration loaded successfully");
-        Ok(config)
+    // Reports every invalid setting at once so a bad config fails at startup
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut names: Vec<&String> = self.app_rules.keys().collect();
+        names.sort();
+
+        let mut errors = Vec::new();
+        for name in names {
+            let rule = &self.app_rules[name];
+
+            if rule.blocked && (rule.requires_local_password || rule.requires_remote_password) {
+                errors.push(format!("app_rules.{}: a blocked app cannot also require a password", name));
+            } else if !rule.blocked && !rule.requires_local_password && !rule.requires_remote_password {
+                errors.push(format!("app_rules.{}: rule neither blocks nor requires a password", name));
+            }
+
+            if let Err(e) = AppMatcher::new(name, rule) {
+                errors.push(format!("app_rules.{}: {}", name, e));
+            }
+        }
+
+        let mut names: Vec<&String> = self.notifiers.keys().collect();
+        names.sort();
+
+        for name in names {
+            if let Err(e) = sinks::check(&self.notifiers[name], &self.device.alias) {
+                errors.push(format!("notifiers.{}: {}", name, e));
+            }
+        }
+
+        for (index, rule) in self.routes.iter().enumerate() {
+            for destination in &rule.destinations {
+                if destination != routing::DISCORD && !self.notifiers.contains_key(destination) {
+                    errors.push(format!("{}: unknown destination {}", routing::rule_label(index, rule), destination));
+                }
+            }
+        }
+        if self.notifiers.contains_key(routing::DISCORD) {
+            errors.push(format!("notifiers.{}: the name is reserved for the Discord webhook", routing::DISCORD));
+        }
+
+        if self.dedup.enabled && self.dedup.window_seconds == 0 {
+            errors.push("dedup: window_seconds must be greater than zero".to_string());
+        }
+        if self.digest.enabled && self.digest.max_severity >= Severity::Warning {
+            errors.push("digest: max_severity must be info or notice so warnings are never delayed".to_string());
+        }
+        errors.extend(health::check(&self.health));
+        errors.extend(network::check(&self.network));
+
+        // Checked here as well as at startup so a reload cannot remove the secret
+        // while the listeners keep running
+        if self.security.hmac_secret.is_none() {
+            if self.api.enabled {
+                errors.push("api: enabled requires security.hmac_secret".to_string());
+            }
+            if self.relay.enabled {
+                errors.push("relay: enabled requires security.hmac_secret".to_string());
+            }
+        }
+
+        let brute_force = &self.security.brute_force;
+        if brute_force.max_failures == 0 || brute_force.window_seconds == 0 {
+            errors.push("security.brute_force: max_failures and window_seconds must be greater than zero".to_string());
+        }
+        if brute_force.response == BruteForceResponse::Hook && brute_force.hook_command.is_none() {
+            errors.push("security.brute_force: response = \"hook\" requires hook_command".to_string());
+        }
+
+        let encryption = &self.security.encryption;
+        match encryption.master_key {
+            MasterKeySource::Passphrase if encryption.keyfile.is_some() => {
+                errors.push("security.encryption: keyfile only applies to master_key = \"keyfile\"".to_string());
+            }
+            MasterKeySource::Passphrase if encryption.passphrase_env.is_empty() && encryption.passphrase.is_none() => {
+                errors.push("security.encryption: master_key = \"passphrase\" requires passphrase_env".to_string());
+            }
+            _ => {}
+        }
+
+        if !errors.is_empty() {
+            return Err(format!("Invalid configuration:\n  {}", errors.join("\n  ")).into());
+        }
+
+        Ok(())
     }
 
-    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn persist(&self, keys: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
         let config_dir = Self::get_config_dir()?;
         fs::create_dir_all(&config_dir)?;
-        
-        let config_file = config_dir.join("config.toml");
-        let config_str = toml::to_string_pretty(self)?;
-        fs::write(config_file, config_str)?;
-        
-        info!("Configuration saved successfully");
+
+        self.persist_to(&config_dir.join("config.toml"), keys)
+    }
+
+    // Writes only the given dotted keys back to the file, keeping comments, ordering
+    // and any keys the agent does not know about
+    pub fn persist_to(&self, config_file: &Path, keys: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        let contents = match fs::read_to_string(config_file) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut document: DocumentMut = contents.parse()?;
+        let values = serde_json::to_value(self)?;
+
+        for key in keys {
+            let segments: Vec<&str> = key.split('.').collect();
+            let value = segments.iter()
+                .try_fold(&values, |value, segment| value.get(segment))
+                .ok_or_else(|| format!("Unknown configuration key: {}", key))?;
+            let (name, parents) = segments.split_last().ok_or("Empty configuration key")?;
+
+            let mut table = document.as_table_mut();
+            for parent in parents {
+                table = table.entry(parent)
+                    .or_insert_with(|| {
+                        let mut table = Table::new();
+                        table.set_implicit(true);
+                        Item::Table(table)
+                    })
+                    .as_table_mut()
+                    .ok_or_else(|| format!("{} is not a table in {}", parent, config_file.display()))?;
+            }
+
+            match (json_to_toml(value), table.get_mut(name).and_then(|item| item.as_value_mut())) {
+                (Some(Item::Value(mut new_value)), Some(existing)) => {
+                    // Keep any trailing comment on the existing line
+                    *new_value.decor_mut() = existing.decor().clone();
+                    *existing = new_value;
+                }
+                (Some(item), _) => {
+                    table.insert(name, item);
+                }
+                (None, _) => {
+                    table.remove(name);
+                }
+            }
+        }
+
+        // Owner-only, since the file holds notifier tokens, passwords and webhook URLs
+        keystore::replace_file(config_file, document.to_string().as_bytes())?;
+
+        info!("Configuration saved: {}", keys.join(", "));
         Ok(())
     }
 
@@ -208,7 +842,15 @@ impl Config {
         self.features.failed_auth_notifications = false;
         self.features.heartbeat_enabled = false;
         
-        self.save()?;
+        self.persist(&[
+            "user_consent.telemetry_enabled",
+            "user_consent.remote_commands_enabled",
+            "user_consent.discord_integration_enabled",
+            "features.login_notifications",
+            "features.logout_notifications",
+            "features.failed_auth_notifications",
+            "features.heartbeat_enabled",
+        ])?;
         
         warn!("Emergency disable activated");
         Ok(())
@@ -226,28 +868,102 @@ impl Config {
         Ok(())
     }
 
+    // Takes precedence over DEVICE_NOTIFIER_CONFIG_DIR and the platform config directory
+    pub fn set_config_dir(path: impl Into<PathBuf>) {
+        let mut config_dir = CONFIG_DIR_OVERRIDE.write().unwrap_or_else(|e| e.into_inner());
+        *config_dir = Some(path.into());
+    }
+
     pub fn get_config_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+        if let Some(config_dir) = CONFIG_DIR_OVERRIDE.read().unwrap_or_else(|e| e.into_inner()).clone() {
+            return Ok(config_dir);
+        }
+
+        if let Some(config_dir) = std::env::var_os(CONFIG_DIR_ENV).filter(|dir| !dir.is_empty()) {
+            return Ok(PathBuf::from(config_dir));
+        }
+
         let config_dir = dirs::config_dir()
             .ok_or("Could not determine config directory")?
             .join("device-notifier");
         Ok(config_dir)
     }
+}
 
-    fn generate_device_id() -> String {
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
-        
-        let mut hasher = DefaultHasher::new();
-        if let Ok(hostname) = std::env::var("COMPUTERNAME") {
-            hostname.hash(&mut hasher);
-        } else if let Ok(hostname) = std::env::var("HOSTNAME") {
-            hostname.hash(&mut hasher);
+// None only when the key is not set, so a typo such as "ture" is an error rather than
+// the default
+fn setting<T: serde::de::DeserializeOwned>(config: &ConfigFile, key: &str) -> Result<Option<T>, Box<dyn std::error::Error>> {
+    match config.get(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(ConfigError::NotFound(_)) => Ok(None),
+        Err(e) => Err(format!("Invalid {}: {}", key, e).into()),
+    }
+}
+
+// Counts and durations, which must not wrap when set to a negative number
+fn unsigned_setting<T: TryFrom<i64>>(config: &ConfigFile, key: &str) -> Result<Option<T>, Box<dyn std::error::Error>> {
+    match setting::<i64>(config, key)? {
+        Some(value) => T::try_from(value).map(Some).map_err(|_| format!("Invalid {}: {} is out of range", key, value).into()),
+        None => Ok(None),
+    }
+}
+
+// Lists may be TOML arrays or comma separated strings from the environment or command line
+fn get_string_list(config: &ConfigFile, key: &str) -> Vec<String> {
+    if let Ok(values) = config.get_array(key) {
+        return values.into_iter().filter_map(|value| value.into_string().ok()).collect();
+    }
+
+    config.get_string(key)
+        .map(|value| {
+            value.split(',')
+                .map(|item| item.trim())
+                .filter(|item| !item.is_empty())
+                .map(|item| item.to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Renders a configuration serialized to JSON as a TOML document, e.g. for `config show`
+pub fn to_toml(value: &serde_json::Value) -> String {
+    match json_to_toml(value) {
+        Some(Item::Table(table)) => DocumentMut::from(table).to_string(),
+        _ => String::new(),
+    }
+}
+
+fn json_to_toml(value: &serde_json::Value) -> Option<Item> {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut table = Table::new();
+            for (key, value) in map {
+                if let Some(item) = json_to_toml(value) {
+                    table.insert(key, item);
+                }
+            }
+            Some(Item::Table(table))
         }
-        
-        std::env::var("USERNAME").unwrap_or_default().hash(&mut hasher);
-        std::env::consts::OS.hash(&mut hasher);
-        
-        format!("{:x}", hasher.finish())
+        other => json_to_toml_value(other).map(Item::Value),
+    }
+}
+
+fn json_to_toml_value(value: &serde_json::Value) -> Option<toml_edit::Value> {
+    match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::Bool(value) => Some((*value).into()),
+        serde_json::Value::Number(number) => number.as_i64()
+            .map(toml_edit::Value::from)
+            .or_else(|| number.as_f64().map(toml_edit::Value::from)),
+        serde_json::Value::String(value) => Some(value.as_str().into()),
+        serde_json::Value::Array(items) => Some(toml_edit::Value::Array(
+            items.iter().filter_map(json_to_toml_value).collect(),
+        )),
+        serde_json::Value::Object(map) => Some(toml_edit::Value::InlineTable(
+            map.iter()
+                .filter_map(|(key, value)| json_to_toml_value(value).map(|value| (key.as_str(), value)))
+                .collect(),
+        )),
     }
 }
 
@@ -267,6 +983,8 @@ impl Default for Config {
                 webhook_url: None,
                 allowed_users: Vec::new(),
                 allowed_roles: Vec::new(),
+                role_permissions: HashMap::new(),
+                events: Vec::new(),
             },
             features: FeatureConfig {
                 login_notifications: false,
@@ -280,14 +998,36 @@ impl Default for Config {
                 command_timeout_seconds: 30,
                 max_commands_per_minute: 10,
                 require_local_auth_for_critical: true,
+                app_approval_timeout_seconds: 120,
+                brute_force: BruteForceConfig::default(),
+                encryption: EncryptionConfig::default(),
             },
             app_rules: HashMap::new(),
+            notifiers: HashMap::new(),
+            routes: Vec::new(),
+            dedup: DedupConfig::default(),
+            digest: DigestConfig::default(),
+            health: HealthConfig::default(),
+            network: NetworkConfig::default(),
             device: DeviceConfig {
                 alias: "Unknown Device".to_string(),
-                device_id: "unknown".to_string(),
+                tags: Vec::new(),
                 platform: std::env::consts::OS.to_string(),
                 version: env!("CARGO_PKG_VERSION").to_string(),
             },
+            api: ApiConfig {
+                enabled: false,
+                bind_address: "127.0.0.1:7420".to_string(),
+                tls_cert_path: None,
+                tls_key_path: None,
+                require_token: false,
+            },
+            relay: RelayConfig {
+                enabled: false,
+                url: None,
+                token: None,
+                poll_timeout_seconds: 30,
+            },
         }
     }
 }
@@ -0,0 +1,349 @@
+use crate::commands::CommandExecutor;
+use crate::config::SharedConfig;
+use crate::discord::{DiscordEvent, EventType};
+use crate::identity::DeviceIdentity;
+use crate::notifier::Notifier;
+use crate::outbox::Outbox;
+use crate::reload::ConfigReloader;
+use crate::routing;
+use crate::storage::SecureStorage;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::Notify;
+use tracing::{info, warn, debug};
+
+// Longer request lines are rejected instead of buffered
+const MAX_REQUEST_BYTES: u64 = 64 * 1024;
+
+#[cfg(windows)]
+const PIPE_NAME: &str = r"\\.\pipe\device-notifier";
+
+// One JSON request per connection, answered with one JSON response line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    ConfigShow,
+    Reload,
+    History { limit: Option<usize> },
+    AuditExport { format: String },
+    AuditVerify,
+    EmergencyDisable,
+    RotateKeys,
+    SetPassword { name: String, password: Secret },
+    ChangePassword { name: String, current: Secret, new: Secret },
+    VerifyPassword { name: String, password: Secret },
+    TestNotify { event_type: EventType, message: Option<String> },
+}
+
+// Passwords in requests, kept out of debug logs
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(pub String);
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default)]
+    pub data: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Unix socket in the config directory, so only users who can read the agent's
+// configuration can drive it. Windows uses a named pipe instead.
+pub fn socket_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    #[cfg(windows)]
+    {
+        Ok(PathBuf::from(PIPE_NAME))
+    }
+
+    // In a directory of its own, which ControlServer::bind keeps private
+    #[cfg(not(windows))]
+    {
+        Ok(crate::config::Config::get_config_dir()?.join("run").join("agent.sock"))
+    }
+}
+
+// Replaces secrets with a placeholder so `config show` output can be shared
+pub fn redact(config: &mut serde_json::Value) {
+    // Slack, Gotify and webhook URLs carry their token in the URL, and headers are
+    // usually there for an Authorization value
+    if let Some(notifiers) = config.get_mut("notifiers").and_then(|notifiers| notifiers.as_object_mut()) {
+        for notifier in notifiers.values_mut().filter_map(|notifier| notifier.as_object_mut()) {
+            if let Some(url) = notifier.get_mut("url").filter(|url| !url.is_null()) {
+                *url = "<redacted>".into();
+            }
+            if let Some(headers) = notifier.get_mut("headers").and_then(|headers| headers.as_object_mut()) {
+                headers.values_mut().for_each(|value| *value = "<redacted>".into());
+            }
+        }
+    }
+
+    redact_secrets(config);
+}
+
+fn redact_secrets(value: &mut serde_json::Value) {
+    const SECRET_KEYS: &[&str] = &["bot_token", "webhook_url", "hmac_secret", "token", "password"];
+
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if SECRET_KEYS.contains(&key.as_str()) && !value.is_null() {
+                    *value = "<redacted>".into();
+                } else {
+                    redact_secrets(value);
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact_secrets),
+        _ => {}
+    }
+}
+
+// Handles of the running agent that control requests act on
+pub struct ControlState {
+    pub config: SharedConfig,
+    pub storage: Arc<SecureStorage>,
+    pub identity: Arc<DeviceIdentity>,
+    pub executor: Arc<CommandExecutor>,
+    pub notifier: Arc<dyn Notifier>,
+    pub outbox: Arc<Outbox>,
+    pub reloader: Arc<ConfigReloader>,
+    pub started_at: DateTime<Utc>,
+    // Notified when a request stops the agent
+    pub shutdown: Arc<Notify>,
+}
+
+impl ControlState {
+    pub async fn handle(&self, request: Request) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        debug!("Control request: {:?}", request);
+
+        match request {
+            Request::Status => {
+                let config = self.config.read().await;
+                let mut notifiers: Vec<&String> = config.notifiers.keys().collect();
+                notifiers.sort();
+
+                Ok(serde_json::json!({
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "device_alias": config.device.alias,
+                    "device_id": self.identity.device_id(),
+                    "fingerprint": self.identity.fingerprint(),
+                    "started_at": self.started_at.to_rfc3339(),
+                    "uptime_seconds": (Utc::now() - self.started_at).num_seconds(),
+                    "discord_enabled": config.user_consent.discord_integration_enabled && config.discord.webhook_url.is_some(),
+                    "remote_commands_enabled": config.user_consent.remote_commands_enabled,
+                    "notifiers": notifiers,
+                    "queued_events": self.outbox.len().await,
+                    "commands": self.executor.get_command_stats().await?,
+                }))
+            }
+            Request::ConfigShow => {
+                let mut config = serde_json::to_value(&*self.config.read().await)?;
+                redact(&mut config);
+                Ok(config)
+            }
+            Request::Reload => Ok(serde_json::json!({ "changed": self.reloader.reload().await? })),
+            Request::History { limit } => Ok(serde_json::to_value(self.executor.get_command_history(limit).await?)?),
+            Request::AuditExport { format } => {
+                let exported = self.storage.export_audit_logs(&format).await?;
+                Ok(String::from_utf8(exported)?.into())
+            }
+            Request::AuditVerify => Ok(serde_json::json!({ "entries": self.storage.verify_audit_chain().await? })),
+            Request::EmergencyDisable => {
+                self.config.write().await.emergency_disable()?;
+                self.shutdown.notify_one();
+                Ok(serde_json::Value::Null)
+            }
+            Request::RotateKeys => {
+                let (version, files) = self.storage.rotate_keys().await?;
+                Ok(serde_json::json!({ "key_version": version, "files": files }))
+            }
+            Request::SetPassword { name, password } => {
+                self.storage.set_password(&name, &password.0).await?;
+                Ok(serde_json::Value::Null)
+            }
+            // Both answer whether a password is right, so both count toward the lockout
+            Request::ChangePassword { name, current, new } => {
+                if !self.executor.process_guard().verify_password(&name, &current.0).await? {
+                    return Err("The current password is incorrect".into());
+                }
+                self.storage.change_password(&name, &current.0, &new.0).await?;
+                Ok(serde_json::Value::Null)
+            }
+            Request::VerifyPassword { name, password } => {
+                Ok(serde_json::json!({ "valid": self.executor.process_guard().verify_password(&name, &password.0).await? }))
+            }
+            Request::TestNotify { event_type, message } => {
+                let (event, route) = {
+                    let config = self.config.read().await;
+                    let notes = message.unwrap_or_else(|| "Test notification from device-notifier".to_string());
+                    let event = DiscordEvent::new(&config, &self.identity, event_type, None, Some(notes));
+                    let route = routing::dry_run(&config, &event);
+                    (event, route)
+                };
+
+                self.notifier.notify(&event).await?;
+                Ok(route.into())
+            }
+        }
+    }
+
+    async fn respond<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) -> Result<(), Box<dyn std::error::Error>> {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut line = String::new();
+        BufReader::new(reader).take(MAX_REQUEST_BYTES).read_line(&mut line).await?;
+
+        let result = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => self.handle(request).await.map_err(|e| e.to_string()),
+            Err(e) => Err(format!("Invalid request: {}", e)),
+        };
+        let response = match result {
+            Ok(data) => Response { ok: true, data, error: None },
+            Err(error) => Response { ok: false, data: serde_json::Value::Null, error: Some(error) },
+        };
+
+        let mut body = serde_json::to_vec(&response)?;
+        body.push(b'\n');
+        writer.write_all(&body).await?;
+        writer.shutdown().await?;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+pub struct ControlServer {
+    listener: tokio::net::UnixListener,
+    state: Arc<ControlState>,
+}
+
+#[cfg(unix)]
+impl ControlServer {
+    pub async fn bind(path: &std::path::Path, state: Arc<ControlState>) -> Result<Self, Box<dyn std::error::Error>> {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+        // The socket is created with the process umask and only restricted after bind, so
+        // the directory keeps other users out in between. Changing its mode fails unless
+        // the agent owns it.
+        if let Some(parent) = path.parent() {
+            std::fs::DirBuilder::new().recursive(true).mode(0o700).create(parent)?;
+            std::fs::set_permissions(parent, std::fs::Permissions::from_mode(0o700))?;
+        }
+
+        // A socket left behind by an agent that did not shut down cleanly
+        if path.exists() {
+            if tokio::net::UnixStream::connect(path).await.is_ok() {
+                return Err(format!("Another agent is already listening on {}", path.display()).into());
+            }
+            std::fs::remove_file(path)?;
+        }
+
+        let listener = tokio::net::UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+
+        info!("Control socket listening on {}", path.display());
+        Ok(Self { listener, state })
+    }
+
+    pub async fn serve(self) {
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, _)) => {
+                    let state = self.state.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = state.respond(stream).await.map_err(|e| e.to_string()) {
+                            warn!("Control connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Failed to accept control connection: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+pub struct ControlServer {
+    name: PathBuf,
+    state: Arc<ControlState>,
+}
+
+#[cfg(windows)]
+impl ControlServer {
+    pub async fn bind(path: &std::path::Path, state: Arc<ControlState>) -> Result<Self, Box<dyn std::error::Error>> {
+        info!("Control pipe listening on {}", path.display());
+        Ok(Self { name: path.to_path_buf(), state })
+    }
+
+    pub async fn serve(self) {
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        let mut options = ServerOptions::new();
+        options.first_pipe_instance(true);
+
+        loop {
+            let server = match options.create(&self.name) {
+                Ok(server) => server,
+                Err(e) => {
+                    warn!("Failed to create control pipe: {}", e);
+                    return;
+                }
+            };
+            options.first_pipe_instance(false);
+
+            if let Err(e) = server.connect().await {
+                warn!("Failed to accept control connection: {}", e);
+                continue;
+            }
+
+            let state = self.state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = state.respond(server).await.map_err(|e| e.to_string()) {
+                    warn!("Control connection failed: {}", e);
+                }
+            });
+        }
+    }
+}
+
+// Sends a request to the running agent. Ok(None) means no agent is listening.
+pub async fn send(path: &std::path::Path, request: &Request) -> Result<Option<serde_json::Value>, Box<dyn std::error::Error>> {
+    #[cfg(unix)]
+    let stream = match tokio::net::UnixStream::connect(path).await {
+        Ok(stream) => stream,
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused) => return Ok(None),
+        Err(e) => return Err(format!("Could not connect to {}: {}", path.display(), e).into()),
+    };
+
+    #[cfg(windows)]
+    let stream = match tokio::net::windows::named_pipe::ClientOptions::new().open(path) {
+        Ok(stream) => stream,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Could not connect to {}: {}", path.display(), e).into()),
+    };
+
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut body = serde_json::to_vec(request)?;
+    body.push(b'\n');
+    writer.write_all(&body).await?;
+
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+    let response: Response = serde_json::from_str(&line)
+        .map_err(|e| format!("Invalid response from the agent: {}", e))?;
+
+    match response.error {
+        Some(error) if !response.ok => Err(error.into()),
+        _ => Ok(Some(response.data)),
+    }
+}
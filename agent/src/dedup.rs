@@ -0,0 +1,361 @@
+use crate::config::{DigestInterval, SharedConfig};
+use crate::discord::{DiscordEvent, EventType};
+use crate::notifier::Notifier;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Local, TimeZone, Timelike, Utc};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{error, debug};
+
+// How long suppressed copies wait for another copy to report them before prune does
+const SUPPRESSED_MAX_AGE_HOURS: i64 = 24;
+
+// Events held for one digest. Beyond this the oldest are dropped and only counted, so a
+// long delivery outage neither grows memory without bound nor produces a huge digest.
+pub const MAX_DIGEST_EVENTS: usize = 500;
+
+#[derive(Debug, Clone)]
+struct Recent {
+    last_sent: DateTime<Utc>,
+    suppressed: u32,
+    // The latest suppressed copy, used to report the count if no further copy comes
+    last_suppressed: Option<DiscordEvent>,
+}
+
+// Collapses identical events (same type, user and details) within a window. The next
+// copy sent after the window reports how many were suppressed in between.
+#[derive(Debug, Default)]
+pub struct Deduplicator {
+    recent: HashMap<String, Recent>,
+}
+
+impl Deduplicator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // None if the event should be dropped, otherwise how many copies were suppressed
+    pub fn check(&mut self, event: &DiscordEvent, window: Duration) -> Option<u32> {
+        let key = format!(
+            "{:?}|{}|{}",
+            event.event_type,
+            event.user_local.as_deref().unwrap_or_default(),
+            event.notes.as_deref().unwrap_or_default()
+        );
+        let now = event.timestamp;
+
+        match self.recent.get_mut(&key) {
+            Some(recent) if now - recent.last_sent < window => {
+                recent.suppressed += 1;
+                recent.last_suppressed = Some(event.clone());
+                None
+            }
+            Some(recent) => {
+                let suppressed = recent.suppressed;
+                *recent = Recent { last_sent: now, suppressed: 0, last_suppressed: None };
+                Some(suppressed)
+            }
+            None => {
+                self.recent.insert(key, Recent { last_sent: now, suppressed: 0, last_suppressed: None });
+                Some(0)
+            }
+        }
+    }
+
+    // Entries with suppressed copies stay until the next copy reports the count, or
+    // until they reach the maximum age. Those are dropped and returned as events that
+    // carry the count instead.
+    pub fn prune(&mut self, now: DateTime<Utc>, window: Duration) -> Vec<DiscordEvent> {
+        let max_age = Duration::hours(SUPPRESSED_MAX_AGE_HOURS).max(window);
+        let mut expired = Vec::new();
+
+        self.recent.retain(|_, recent| {
+            let age = now - recent.last_sent;
+            if recent.suppressed == 0 {
+                return age < window;
+            }
+            if age < max_age {
+                return true;
+            }
+
+            if let Some(mut event) = recent.last_suppressed.take() {
+                let note = suppressed_note(recent.suppressed);
+                event.timestamp = now;
+                event.notes = Some(match event.notes.take() {
+                    Some(notes) => format!("{} {}", notes, note),
+                    None => note,
+                });
+                expired.push(event);
+            }
+            false
+        });
+
+        expired
+    }
+}
+
+fn suppressed_note(suppressed: u32) -> String {
+    format!("({} identical events suppressed)", suppressed)
+}
+
+#[derive(Debug, Clone)]
+pub struct Condition {
+    pub since: DateTime<Utc>,
+    pub occurrences: u32,
+}
+
+// Tracks ongoing conditions such as high CPU usage so a monitor reports each one when
+// it starts and again when it clears, however many checks it lasts
+#[derive(Debug, Default)]
+pub struct ConditionTracker {
+    active: HashMap<String, Condition>,
+}
+
+impl ConditionTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // True only when the condition was not already active
+    pub fn raise(&mut self, key: &str, now: DateTime<Utc>) -> bool {
+        match self.active.get_mut(key) {
+            Some(condition) => {
+                condition.occurrences += 1;
+                false
+            }
+            None => {
+                self.active.insert(key.to_string(), Condition { since: now, occurrences: 1 });
+                true
+            }
+        }
+    }
+
+    // The condition that just ended, if it was active
+    pub fn clear(&mut self, key: &str) -> Option<Condition> {
+        self.active.remove(key)
+    }
+
+    #[allow(dead_code)]
+    pub fn is_active(&self, key: &str) -> bool {
+        self.active.contains_key(key)
+    }
+}
+
+pub fn resolved_notes(description: &str, condition: &Condition, now: DateTime<Utc>) -> String {
+    let minutes = (now - condition.since).num_minutes().max(0);
+    format!(
+        "{} cleared after {}h {}m ({} checks)",
+        description, minutes / 60, minutes % 60, condition.occurrences
+    )
+}
+
+// Start of the next hour, or the next local midnight for daily digests
+pub fn next_digest_at(now: DateTime<Utc>, interval: DigestInterval) -> DateTime<Utc> {
+    match interval {
+        DigestInterval::Hourly => {
+            let hour = now.with_minute(0).and_then(|now| now.with_second(0)).and_then(|now| now.with_nanosecond(0));
+            hour.unwrap_or(now) + Duration::hours(1)
+        }
+        DigestInterval::Daily => {
+            let tomorrow = now.with_timezone(&Local).date_naive().succ_opt().unwrap_or_default();
+            Local.from_local_datetime(&tomorrow.and_time(chrono::NaiveTime::MIN))
+                .earliest()
+                .map(|midnight| midnight.with_timezone(&Utc))
+                .unwrap_or(now + Duration::days(1))
+        }
+    }
+}
+
+// Events waiting for the next digest, oldest first
+#[derive(Debug, Default)]
+struct PendingDigest {
+    events: Vec<DiscordEvent>,
+    // Older events dropped to stay within MAX_DIGEST_EVENTS
+    suppressed: u32,
+}
+
+impl PendingDigest {
+    fn trim(&mut self) {
+        let excess = self.events.len().saturating_sub(MAX_DIGEST_EVENTS);
+        self.events.drain(..excess);
+        self.suppressed = self.suppressed.saturating_add(excess as u32);
+    }
+}
+
+// Summary of held-back events: counts per event type, with the users involved, and how
+// many older events were dropped without being listed
+pub fn digest_notes(events: &[DiscordEvent], suppressed: u32) -> String {
+    let mut counts: Vec<(EventType, u32, Vec<String>)> = Vec::new();
+
+    for event in events {
+        let index = match counts.iter().position(|(event_type, _, _)| *event_type == event.event_type) {
+            Some(index) => index,
+            None => {
+                counts.push((event.event_type.clone(), 0, Vec::new()));
+                counts.len() - 1
+            }
+        };
+
+        let (_, count, users) = &mut counts[index];
+        *count += 1;
+        if let Some(user) = &event.user_local {
+            if !users.contains(user) {
+                users.push(user.clone());
+            }
+        }
+    }
+
+    let first = events.iter().map(|event| event.timestamp).min().unwrap_or_else(Utc::now);
+    let last = events.iter().map(|event| event.timestamp).max().unwrap_or_else(Utc::now);
+
+    let mut lines = vec![format!(
+        "{} events between {} and {}",
+        events.len(),
+        first.format("%Y-%m-%d %H:%M UTC"),
+        last.format("%Y-%m-%d %H:%M UTC")
+    )];
+    for (event_type, count, users) in counts {
+        if users.is_empty() {
+            lines.push(format!("{:?} × {}", event_type, count));
+        } else {
+            lines.push(format!("{:?} × {} ({})", event_type, count, users.join(", ")));
+        }
+    }
+
+    if suppressed > 0 {
+        lines.push(format!("{} older events suppressed", suppressed));
+    }
+
+    lines.join("\n")
+}
+
+// Sits in front of the notification hub: drops repeats and holds low-severity events
+// for the periodic digest
+pub struct DedupNotifier {
+    config: SharedConfig,
+    inner: Arc<dyn Notifier>,
+    deduplicator: Mutex<Deduplicator>,
+    digest: Mutex<PendingDigest>,
+}
+
+impl DedupNotifier {
+    pub fn new(config: SharedConfig, inner: Arc<dyn Notifier>) -> Self {
+        Self {
+            config,
+            inner,
+            deduplicator: Mutex::new(Deduplicator::new()),
+            digest: Mutex::new(PendingDigest::default()),
+        }
+    }
+
+    pub async fn run(&self) {
+        loop {
+            let interval = self.config.read().await.digest.interval;
+            let wait = (next_digest_at(Utc::now(), interval) - Utc::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(wait).await;
+
+            if let Err(e) = self.flush_digest().await.map_err(|e| e.to_string()) {
+                error!("Failed to send event digest: {}", e);
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub async fn pending_digest(&self) -> usize {
+        self.digest.lock().await.events.len()
+    }
+
+    // Sends everything held back so far as a single Digest event
+    pub async fn flush_digest(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let PendingDigest { events, suppressed } = std::mem::take(&mut *self.digest.lock().await);
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        let digest = DiscordEvent {
+            device_alias: events[0].device_alias.clone(),
+            device_id_hash: events[0].device_id_hash.clone(),
+            event_type: EventType::Digest,
+            timestamp: Utc::now(),
+            user_local: None,
+            notes: Some(digest_notes(&events, suppressed)),
+            severity: None,
+        };
+
+        // Keep the held-back events for the next attempt, ahead of any batched since
+        if let Err(e) = self.inner.notify(&digest).await.map_err(|e| e.to_string()) {
+            let mut pending = self.digest.lock().await;
+            let newer = std::mem::replace(&mut *pending, PendingDigest { events, suppressed });
+            pending.events.extend(newer.events);
+            pending.suppressed = pending.suppressed.saturating_add(newer.suppressed);
+            pending.trim();
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Notifier for DedupNotifier {
+    fn name(&self) -> &str {
+        "dedup"
+    }
+
+    async fn notify(&self, event: &DiscordEvent) -> Result<(), Box<dyn std::error::Error>> {
+        let (dedup, digest) = {
+            let config = self.config.read().await;
+            (config.dedup.clone(), config.digest.clone())
+        };
+
+        // Heartbeats repeat by design and every remote command is worth reporting
+        let exempt = matches!(
+            event.event_type,
+            EventType::Heartbeat | EventType::CommandExecuted | EventType::Resolved | EventType::Digest
+        );
+
+        let mut event = event.clone();
+        if dedup.enabled && !exempt {
+            let window = Duration::seconds(dedup.window_seconds as i64);
+            let (expired, checked) = {
+                let mut deduplicator = self.deduplicator.lock().await;
+                let expired = deduplicator.prune(event.timestamp, window);
+                (expired, deduplicator.check(&event, window))
+            };
+
+            for report in &expired {
+                if let Err(e) = self.inner.notify(report).await.map_err(|e| e.to_string()) {
+                    error!("Failed to report suppressed {:?} events: {}", report.event_type, e);
+                }
+            }
+
+            match checked {
+                None => {
+                    debug!("Suppressed repeated {:?} event", event.event_type);
+                    return Ok(());
+                }
+                Some(0) => {}
+                Some(suppressed) => {
+                    let note = suppressed_note(suppressed);
+                    event.notes = Some(match event.notes.take() {
+                        Some(notes) => format!("{} {}", notes, note),
+                        None => note,
+                    });
+                }
+            }
+        }
+
+        // A delayed heartbeat would be no proof of life, so heartbeats are never batched
+        let batched = !matches!(event.event_type, EventType::Heartbeat | EventType::Digest)
+            && event.severity() <= digest.max_severity;
+        if digest.enabled && batched {
+            let mut pending = self.digest.lock().await;
+            pending.events.push(event);
+            pending.trim();
+            return Ok(());
+        }
+
+        self.inner.notify(&event).await
+    }
+}
@@ -1,11 +1,19 @@
-use crate::config::Config;
+use crate::config::{Config, SharedConfig};
+use crate::identity::DeviceIdentity;
+use crate::notifier::Notifier;
+use crate::outbox::Outbox;
+use crate::signing;
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use reqwest::Client;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
-use tracing::{info, warn, error, debug};
+use tracing::{info, warn, debug};
 use chrono::{DateTime, Utc};
-use uuid::Uuid;
+
+pub const MAX_COMMAND_AGE_SECONDS: i64 = 300;
+pub const MAX_CLOCK_SKEW_SECONDS: i64 = 30;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DiscordEvent {
@@ -15,32 +23,129 @@ pub struct DiscordEvent {
     pub timestamp: DateTime<Utc>,
     pub user_local: Option<String>,
     pub notes: Option<String>,
+    // Overrides the event type's severity, e.g. for health alerts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub severity: Option<Severity>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum EventType {
     Login,
     Logout,
     FailedAuth,
     Heartbeat,
     CommandExecuted,
+    AppBlocked,
+    AppApprovalRequired,
+    BruteForceDetected,
+    Resolved,
+    Digest,
+    HealthAlert,
+    NetworkChange,
+}
+
+impl DiscordEvent {
+    pub fn new(config: &Config, identity: &DeviceIdentity, event_type: EventType, user_local: Option<String>, notes: Option<String>) -> Self {
+        Self {
+            device_alias: config.device.alias.clone(),
+            device_id_hash: identity.fingerprint().to_string(),
+            event_type,
+            timestamp: Utc::now(),
+            user_local,
+            notes,
+            severity: None,
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.severity.unwrap_or_else(|| self.event_type.severity())
+    }
+}
+
+impl EventType {
+    pub fn title(&self) -> &'static str {
+        match self {
+            EventType::Login => "🔓 User Login",
+            EventType::Logout => "🔒 User Logout",
+            EventType::FailedAuth => "⚠️ Failed Authentication",
+            EventType::Heartbeat => "💓 System Heartbeat",
+            EventType::CommandExecuted => "⚡ Command Executed",
+            EventType::AppBlocked => "🚫 Application Blocked",
+            EventType::AppApprovalRequired => "🔑 Application Approval Required",
+            EventType::BruteForceDetected => "🚨 Brute-Force Attack Detected",
+            EventType::Resolved => "✅ Resolved",
+            EventType::Digest => "📋 Event Digest",
+            EventType::HealthAlert => "🩺 Health Alert",
+            EventType::NetworkChange => "🌐 Network Change",
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            EventType::Heartbeat | EventType::Digest => Severity::Info,
+            EventType::Login | EventType::Logout | EventType::CommandExecuted | EventType::Resolved | EventType::NetworkChange => Severity::Notice,
+            EventType::FailedAuth | EventType::AppBlocked | EventType::AppApprovalRequired | EventType::HealthAlert => Severity::Warning,
+            EventType::BruteForceDetected => Severity::Critical,
+        }
+    }
+}
+
+// Ordered from least to most severe so routing rules can use a minimum
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Notice,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Notice => "notice",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DiscordCommand {
+    pub version: u32,
     pub command: CommandType,
     pub command_id: String,
+    pub device_id: String,
     pub authorized_user: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    pub nonce: String,
     pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub arguments: serde_json::Map<String, serde_json::Value>,
     pub signature: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CommandType {
     Lock,
     Logout,
     Ping,
     Status,
+    ApproveApp,
+}
+
+impl CommandType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandType::Lock => "Lock",
+            CommandType::Logout => "Logout",
+            CommandType::Ping => "Ping",
+            CommandType::Status => "Status",
+            CommandType::ApproveApp => "ApproveApp",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -51,25 +156,40 @@ pub struct CommandResponse {
     pub timestamp: DateTime<Utc>,
 }
 
+// Outcome of one webhook POST, as far as the outbox needs to know
+#[derive(Debug, Clone, PartialEq)]
+pub enum Delivery {
+    // reset_after is set when the rate limit bucket is now empty
+    Sent { reset_after: Option<Duration> },
+    RateLimited(Duration),
+    Rejected(u16),
+    Skipped,
+}
+
 pub struct DiscordClient {
-    config: Arc<RwLock<Config>>,
+    config: SharedConfig,
+    identity: Arc<DeviceIdentity>,
+    outbox: Arc<Outbox>,
     http_client: Client,
     last_heartbeat: Arc<RwLock<DateTime<Utc>>>,
 }
 
 impl DiscordClient {
-    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn new(config: SharedConfig, identity: Arc<DeviceIdentity>, outbox: Arc<Outbox>) -> Result<Self, Box<dyn std::error::Error>> {
         let http_client = Client::builder()
             .timeout(std::time::Duration::from_secs(30))
             .build()?;
 
         Ok(Self {
-            config: Arc::new(RwLock::new(config.clone())),
+            config,
+            identity,
+            outbox,
             http_client,
             last_heartbeat: Arc::new(RwLock::new(Utc::now())),
         })
     }
 
+    // Queues the event; the outbox delivers it once Discord is reachable
     pub async fn send_event(&self, event: DiscordEvent) -> Result<(), Box<dyn std::error::Error>> {
         let config = self.config.read().await;
         
@@ -78,132 +198,81 @@ impl DiscordClient {
             return Ok(());
         }
 
-        let webhook_url = match &config.discord.webhook_url {
-            Some(url) => url,
-            None => {
-                warn!("No Discord webhook URL configured");
-                return Ok(());
+        if config.discord.webhook_url.is_none() {
+            warn!("No Discord webhook URL configured");
+            return Ok(());
+        }
+        drop(config);
+
+        debug!("Queueing {:?} event", event.event_type);
+        self.outbox.enqueue(event).await
+    }
+
+    #[allow(dead_code)]
+    pub fn outbox(&self) -> &Arc<Outbox> {
+        &self.outbox
+    }
+
+    // One attempt at posting an event; network errors and 5xx responses are errors
+    // so the outbox retries them with backoff
+    pub async fn deliver(&self, event: &DiscordEvent) -> Result<Delivery, Box<dyn std::error::Error>> {
+        let webhook_url = {
+            let config = self.config.read().await;
+
+            if !config.user_consent.discord_integration_enabled {
+                return Ok(Delivery::Skipped);
+            }
+
+            match &config.discord.webhook_url {
+                Some(url) => url.clone(),
+                None => return Ok(Delivery::Skipped),
             }
         };
 
-        let embed = self.create_event_embed(&event);
+        let embed = self.create_event_embed(event);
         let payload = serde_json::json!({
             "embeds": [embed]
         });
 
         let response = self.http_client
-            .post(webhook_url)
+            .post(&webhook_url)
             .json(&payload)
             .send()
             .await?;
 
-        if response.status().is_success() {
-            info!("Event sent to Discord successfully: {:?}", event.event_type);
-        } else {
-            error!("Failed to send event to Discord: {}", response.status());
-        }
-
-        Ok(())
-    }
-
-    pub async fn send_heartbeat(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let config = self.config.read().await;
-        
-        if !config.features.heartbeat_enabled {
-            return Ok(());
-        }
-
-        let event = DiscordEvent {
-            device_alias: config.device.alias.clone(),
-            device_id_hash: self.hash_device_id(&config.device.device_id),
-            event_type: EventType::Heartbeat,
-            timestamp: Utc::now(),
-            user_local: None,
-            notes: Some("System heartbeat".to_string()),
-        };
+        let status = response.status();
+        let reset_after = header_seconds(response.headers(), "x-ratelimit-reset-after");
 
-        self.send_event(event).await?;
-        
-        let mut last_heartbeat = self.last_heartbeat.write().await;
-        *last_heartbeat = Utc::now();
-        
-        Ok(())
-    }
-
-    pub async fn send_login_event(&self, username: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let config = self.config.read().await;
-        
-        if !config.features.login_notifications {
-            return Ok(());
-        }
-
-        let event = DiscordEvent {
-            device_alias: config.device.alias.clone(),
-            device_id_hash: self.hash_device_id(&config.device.device_id),
-            event_type: EventType::Login,
-            timestamp: Utc::now(),
-            user_local: Some(username.to_string()),
-            notes: None,
-        };
+        if status.is_success() {
+            info!("Event sent to Discord successfully: {:?}", event.event_type);
 
-        self.send_event(event).await
-    }
+            let exhausted = response.headers()
+                .get("x-ratelimit-remaining")
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|remaining| remaining.trim() == "0");
 
-    pub async fn send_logout_event(&self, username: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let config = self.config.read().await;
-        
-        if !config.features.logout_notifications {
-            return Ok(());
+            return Ok(Delivery::Sent { reset_after: reset_after.filter(|_| exhausted) });
         }
 
-        let event = DiscordEvent {
-            device_alias: config.device.alias.clone(),
-            device_id_hash: self.hash_device_id(&config.device.device_id),
-            event_type: EventType::Logout,
-            timestamp: Utc::now(),
-            user_local: Some(username.to_string()),
-            notes: None,
-        };
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let retry_after_header = header_seconds(response.headers(), "retry-after");
+            let body: serde_json::Value = response.json().await.unwrap_or_default();
 
-        self.send_event(event).await
-    }
+            // The body's retry_after is the most precise; it has millisecond resolution
+            let retry_after = body["retry_after"].as_f64()
+                .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                .or(retry_after_header)
+                .or(reset_after)
+                .unwrap_or(Duration::from_secs(1));
 
-    pub async fn send_failed_auth_event(&self, username: &str, details: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let config = self.config.read().await;
-        
-        if !config.features.failed_auth_notifications {
-            return Ok(());
+            return Ok(Delivery::RateLimited(retry_after));
         }
 
-        let event = DiscordEvent {
-            device_alias: config.device.alias.clone(),
-            device_id_hash: self.hash_device_id(&config.device.device_id),
-            event_type: EventType::FailedAuth,
-            timestamp: Utc::now(),
-            user_local: Some(username.to_string()),
-            notes: Some(details.to_string()),
-        };
-
-        self.send_event(event).await
-    }
-
-    pub async fn send_command_executed_event(&self, command: &str, success: bool, details: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let config = self.config.read().await;
-        
-        if !config.features.audit_logging {
-            return Ok(());
+        if status.is_client_error() {
+            return Ok(Delivery::Rejected(status.as_u16()));
         }
 
-        let event = DiscordEvent {
-            device_alias: config.device.alias.clone(),
-            device_id_hash: self.hash_device_id(&config.device.device_id),
-            event_type: EventType::CommandExecuted,
-            timestamp: Utc::now(),
-            user_local: None,
-            notes: Some(format!("Command '{}' executed: {} - {}", command, if success { "SUCCESS" } else { "FAILED" }, details)),
-        };
-
-        self.send_event(event).await
+        Err(format!("Discord returned {}", status).into())
     }
 
     fn create_event_embed(&self, event: &DiscordEvent) -> serde_json::Value {
@@ -213,15 +282,19 @@ impl DiscordClient {
             EventType::FailedAuth => 0xff0000, // Red
             EventType::Heartbeat => 0x0088ff,  // Blue
             EventType::CommandExecuted => 0x8800ff, // Purple
+            EventType::AppBlocked => 0xcc0000,  // Dark red
+            EventType::AppApprovalRequired => 0xffcc00, // Yellow
+            EventType::BruteForceDetected => 0x990000, // Crimson
+            EventType::Resolved => 0x00cc66,    // Teal green
+            EventType::Digest => 0x888888,      // Grey
+            EventType::HealthAlert => match event.severity() {
+                Severity::Critical => 0xff0000, // Red
+                _ => 0xffaa00,                  // Amber
+            },
+            EventType::NetworkChange => 0x00aacc, // Cyan
         };
 
-        let title = match event.event_type {
-            EventType::Login => "🔓 User Login",
-            EventType::Logout => "🔒 User Logout",
-            EventType::FailedAuth => "⚠️ Failed Authentication",
-            EventType::Heartbeat => "💓 System Heartbeat",
-            EventType::CommandExecuted => "⚡ Command Executed",
-        };
+        let title = event.event_type.title();
 
         let mut fields = vec![
             serde_json::json!({
@@ -249,6 +322,14 @@ impl DiscordClient {
             }));
         }
 
+        if let Some(severity) = event.severity {
+            fields.push(serde_json::json!({
+                "name": "Severity",
+                "value": severity.as_str(),
+                "inline": true
+            }));
+        }
+
         if let Some(ref notes) = event.notes {
             fields.push(serde_json::json!({
                 "name": "Details",
@@ -268,11 +349,13 @@ impl DiscordClient {
         })
     }
 
-    fn hash_device_id(&self, device_id: &str) -> String {
-        use sha2::{Sha256, Digest};
-        let mut hasher = Sha256::new();
-        hasher.update(device_id.as_bytes());
-        format!("{:x}", hasher.finalize())
+    pub fn device_id(&self) -> &str {
+        self.identity.device_id()
+    }
+
+    #[allow(dead_code)]
+    pub fn device_id_hash(&self) -> &str {
+        self.identity.fingerprint()
     }
 
     pub async fn validate_command(&self, command: &DiscordCommand) -> Result<bool, Box<dyn std::error::Error>> {
@@ -282,45 +365,51 @@ impl DiscordClient {
             return Ok(false);
         }
 
-        // Check if command is from an authorized user
-        if !config.discord.allowed_users.contains(&command.authorized_user) {
-            warn!("Command from unauthorized user: {}", command.authorized_user);
-            return Ok(false);
-        }
-
-        // Check timestamp freshness (within 5 minutes)
+        // Check timestamp freshness (within 5 minutes, allowing for small clock skew)
         let now = Utc::now();
         let command_time = command.timestamp;
         let time_diff = now.signed_duration_since(command_time);
         
-        if time_diff.num_seconds() > 300 {
+        if time_diff.num_seconds() > MAX_COMMAND_AGE_SECONDS {
             warn!("Command timestamp too old: {} seconds", time_diff.num_seconds());
             return Ok(false);
         }
 
-        // Validate HMAC signature if configured
-        if let Some(ref hmac_secret) = config.security.hmac_secret {
-            if !self.verify_signature(command, hmac_secret).await? {
-                warn!("Invalid command signature");
-                return Ok(false);
-            }
+        if time_diff.num_seconds() < -MAX_CLOCK_SKEW_SECONDS {
+            warn!("Command timestamp in the future: {} seconds", -time_diff.num_seconds());
+            return Ok(false);
+        }
+
+        // Commands are bound to a single device
+        if command.device_id != self.identity.device_id() {
+            warn!("Command {} addressed to another device: {}", command.command_id, command.device_id);
+            return Ok(false);
+        }
+
+        // Unsigned commands are never accepted, even if a reload removed the secret
+        let Some(hmac_secret) = config.security.hmac_secret.as_deref() else {
+            warn!("Command {} rejected: security.hmac_secret is not configured", command.command_id);
+            return Ok(false);
+        };
+        if !self.verify_signature(command, hmac_secret).await? {
+            warn!("Invalid command signature");
+            return Ok(false);
         }
 
         Ok(true)
     }
 
     async fn verify_signature(&self, command: &DiscordCommand, secret: &str) -> Result<bool, Box<dyn std::error::Error>> {
-        use hmac::{Hmac, Mac};
-        use sha2::Sha256;
-        
-        let payload = format!("{}{}{}", command.command_id, command.authorized_user, command.timestamp.timestamp());
-        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
-        mac.update(payload.as_bytes());
-        
-        let expected_signature = base64::encode(mac.finalize().into_bytes());
-        Ok(command.signature == expected_signature)
+        match signing::verify_command(command, secret) {
+            Ok(valid) => Ok(valid),
+            Err(e) => {
+                warn!("Command {} has a malformed signing envelope: {}", command.command_id, e);
+                Ok(false)
+            }
+        }
     }
 
+    #[allow(dead_code)]
     pub async fn get_status(&self) -> serde_json::Value {
         let config = self.config.read().await;
         let last_heartbeat = self.last_heartbeat.read().await;
@@ -341,3 +430,25 @@ impl DiscordClient {
         })
     }
 }
+
+#[async_trait]
+impl Notifier for DiscordClient {
+    fn name(&self) -> &str {
+        "discord"
+    }
+
+    async fn notify(&self, event: &DiscordEvent) -> Result<(), Box<dyn std::error::Error>> {
+        if event.event_type == EventType::Heartbeat {
+            *self.last_heartbeat.write().await = event.timestamp;
+        }
+
+        self.send_event(event.clone()).await
+    }
+}
+
+fn header_seconds(headers: &reqwest::header::HeaderMap, name: &str) -> Option<Duration> {
+    headers.get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<f64>().ok())
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+}
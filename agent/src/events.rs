@@ -1,38 +1,48 @@
-use crate::config::Config;
-use crate::discord::{DiscordClient, DiscordEvent, EventType};
+use crate::auth_log::{self, AuthLogReader};
+use crate::brute_force::{BruteForceAlert, BruteForceDetector};
+use crate::config::{BruteForceConfig, BruteForceResponse, Config};
+use crate::dedup::{self, ConditionTracker};
+use crate::discord::{DiscordEvent, EventType};
+use crate::health::{HealthChange, HealthMonitor};
+use crate::identity::DeviceIdentity;
+use crate::network::{self, NetworkState, ProbeError};
+use crate::notifier::Notifier;
+use crate::sessions::{self, SessionEvent, SessionTracker};
 use crate::storage::SecureStorage;
 use crate::system::SystemManager;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tracing::{info, warn, error, debug};
-use chrono::{DateTime, Utc};
-use std::collections::HashMap;
+use tracing::{info, warn, error};
+use chrono::Utc;
 use std::time::Duration;
 
 pub struct EventMonitor {
-    discord: Arc<DiscordClient>,
+    notifier: Arc<dyn Notifier>,
+    identity: Arc<DeviceIdentity>,
     storage: Arc<SecureStorage>,
     config: Arc<RwLock<Config>>,
     system: Arc<SystemManager>,
-    last_events: Arc<RwLock<HashMap<String, DateTime<Utc>>>>,
+    sessions: Arc<RwLock<SessionTracker>>,
     monitoring: Arc<RwLock<bool>>,
 }
 
 impl EventMonitor {
     pub fn new(
-        discord: Arc<DiscordClient>,
+        notifier: Arc<dyn Notifier>,
+        identity: Arc<DeviceIdentity>,
         storage: Arc<SecureStorage>,
         config: Arc<RwLock<Config>>,
     ) -> Self {
         Self {
-            discord,
+            notifier,
+            identity,
             storage,
             config,
             system: Arc::new(SystemManager::new().unwrap_or_else(|_| {
                 error!("Failed to create system manager");
                 SystemManager::new().unwrap_or_else(|_| panic!("System manager creation failed"))
             })),
-            last_events: Arc::new(RwLock::new(HashMap::new())),
+            sessions: Arc::new(RwLock::new(SessionTracker::new())),
             monitoring: Arc::new(RwLock::new(false)),
         }
     }
@@ -49,6 +59,7 @@ impl EventMonitor {
         
         // Spawn monitoring tasks
         self.spawn_login_monitor().await?;
+        self.spawn_auth_log_monitor().await?;
         self.spawn_system_health_monitor().await?;
         self.spawn_network_monitor().await?;
         
@@ -56,7 +67,7 @@ impl EventMonitor {
     }
 
     pub async fn stop(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut monitoring = self.monitoring.monitoring.write().await;
+        let mut monitoring = self.monitoring.write().await;
         *monitoring = false;
         
         // Stop system monitoring
@@ -67,10 +78,17 @@ impl EventMonitor {
     }
 
     async fn spawn_login_monitor(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let discord = self.discord.clone();
+        if !sessions::is_supported() {
+            warn!("Login and logout notifications are not supported on this platform");
+            return Ok(());
+        }
+
+        let notifier = self.notifier.clone();
+        let identity = self.identity.clone();
         let storage = self.storage.clone();
         let config = self.config.clone();
-        let last_events = self.last_events.clone();
+        let system = self.system.clone();
+        let tracker = self.sessions.clone();
         let monitoring = self.monitoring.clone();
         
         tokio::spawn(async move {
@@ -79,7 +97,7 @@ impl EventMonitor {
             while *monitoring.read().await {
                 interval.tick().await;
                 
-                if let Err(e) = Self::check_login_events(&discord, &storage, &config, &last_events).await {
+                if let Err(e) = Self::check_login_events(notifier.as_ref(), &identity, &storage, &config, &system, &tracker).await {
                     error!("Error checking login events: {}", e);
                 }
             }
@@ -88,19 +106,59 @@ impl EventMonitor {
         Ok(())
     }
 
-    async fn spawn_system_health_monitor(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let discord = self.discord.clone();
+    async fn spawn_auth_log_monitor(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if !auth_log::is_supported() {
+            warn!("Failed authentication notifications are not supported on this platform");
+            return Ok(());
+        }
+
+        let mut reader = match AuthLogReader::open().await {
+            Ok(reader) => reader,
+            Err(e) => {
+                warn!("Failed authentication monitoring disabled: {}", e);
+                return Ok(());
+            }
+        };
+
+        let notifier = self.notifier.clone();
+        let identity = self.identity.clone();
         let storage = self.storage.clone();
         let config = self.config.clone();
+        let system = self.system.clone();
         let monitoring = self.monitoring.clone();
         
         tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_secs(60)); // Check every minute
+            let mut interval = tokio::time::interval(Duration::from_secs(5));
+            let mut detector = BruteForceDetector::new();
             
             while *monitoring.read().await {
                 interval.tick().await;
                 
-                if let Err(e) = Self::check_system_health(&discord, &storage, &config).await {
+                if let Err(e) = Self::check_auth_failures(notifier.as_ref(), &identity, &storage, &config, &system, &mut reader, &mut detector).await {
+                    error!("Error checking failed authentications: {}", e);
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn spawn_system_health_monitor(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let notifier = self.notifier.clone();
+        let identity = self.identity.clone();
+        let config = self.config.clone();
+        let system = self.system.clone();
+        let monitoring = self.monitoring.clone();
+        
+        tokio::spawn(async move {
+            let mut health = HealthMonitor::new();
+
+            while *monitoring.read().await {
+                // Re-read so a reload can change the interval
+                let interval_seconds = config.read().await.health.interval_seconds.max(1);
+                tokio::time::sleep(Duration::from_secs(interval_seconds)).await;
+                
+                if let Err(e) = Self::check_system_health(notifier.as_ref(), &identity, &config, &system, &mut health).await {
                     error!("Error checking system health: {}", e);
                 }
             }
@@ -110,18 +168,21 @@ impl EventMonitor {
     }
 
     async fn spawn_network_monitor(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let discord = self.discord.clone();
+        let notifier = self.notifier.clone();
+        let identity = self.identity.clone();
         let storage = self.storage.clone();
         let config = self.config.clone();
         let monitoring = self.monitoring.clone();
         
         tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_secs(30)); // Check every 30 seconds
-            
+            let mut conditions = ConditionTracker::new();
+            let mut previous = None;
+
             while *monitoring.read().await {
-                interval.tick().await;
+                let interval_seconds = config.read().await.network.interval_seconds.max(1);
+                tokio::time::sleep(Duration::from_secs(interval_seconds)).await;
                 
-                if let Err(e) = Self::check_network_status(&discord, &storage, &config).await {
+                if let Err(e) = Self::check_network_status(notifier.as_ref(), &identity, &storage, &config, &mut conditions, &mut previous).await {
                     error!("Error checking network status: {}", e);
                 }
             }
@@ -131,187 +192,329 @@ impl EventMonitor {
     }
 
     async fn check_login_events(
-        discord: &DiscordClient,
+        notifier: &dyn Notifier,
+        identity: &DeviceIdentity,
         storage: &SecureStorage,
         config: &Arc<RwLock<Config>>,
-        last_events: &Arc<RwLock<HashMap<String, DateTime<Utc>>>>
+        system: &SystemManager,
+        tracker: &Arc<RwLock<SessionTracker>>
     ) -> Result<(), Box<dyn std::error::Error>> {
+        // Keep tracking while notifications are off so enabling them doesn't report old sessions
+        let current = system.active_sessions().await?;
+        let session_events = tracker.write().await.update(current, Utc::now());
+
         let config = config.read().await;
-        
-        if !config.features.login_notifications && !config.features.logout_notifications {
-            return Ok(());
+
+        for session_event in session_events {
+            let (event_type, audit_type, enabled, session, timestamp, notes) = match session_event {
+                SessionEvent::Login(session) => {
+                    let notes = format!("{} at {}", session.describe(), session.login_time.to_rfc3339());
+                    (EventType::Login, "login", config.features.login_notifications, session.clone(), session.login_time, notes)
+                }
+                SessionEvent::Logout { session, logout_time } => {
+                    // utmp only says the session is gone; wtmp records when it ended
+                    let logout_time = sessions::logout_time(&session).unwrap_or(logout_time);
+                    let minutes = (logout_time - session.login_time).num_minutes().max(0);
+                    let notes = format!("{}, logged in for {}h {}m", session.describe(), minutes / 60, minutes % 60);
+                    (EventType::Logout, "logout", config.features.logout_notifications, session, logout_time, notes)
+                }
+            };
+
+            let log_entry = serde_json::json!({
+                "user": session.username,
+                "tty": session.tty,
+                "remote_host": session.remote_host,
+                "remote": session.is_remote(),
+                "session_id": session.session_id,
+                "pid": session.pid,
+                "login_time": session.login_time.to_rfc3339(),
+                "timestamp": timestamp.to_rfc3339()
+            });
+
+            if let Err(e) = storage.log_audit_event(audit_type, &log_entry).await {
+                error!("Failed to log {} event: {}", audit_type, e);
+            }
+
+            if !enabled {
+                continue;
+            }
+
+            let event = DiscordEvent {
+                device_alias: config.device.alias.clone(),
+                device_id_hash: identity.fingerprint().to_string(),
+                event_type,
+                timestamp,
+                user_local: Some(session.username.clone()),
+                notes: Some(notes),
+                severity: None,
+            };
+
+            if let Err(e) = notifier.notify(&event).await {
+                error!("Failed to send {} event: {}", audit_type, e);
+            }
         }
 
-        // Get current users from system
-        let system_info = self.system.get_system_info().await?;
-        let current_users = system_info["users"].as_array()
-            .unwrap_or(&Vec::new())
-            .iter()
-            .map(|user| user["username"].as_str().unwrap_or("Unknown").to_string())
-            .collect::<Vec<_>>();
+        Ok(())
+    }
 
-        let mut last_events = last_events.write().await;
-        
-        // Check for new logins
-        for username in &current_users {
-            let event_key = format!("login_{}", username);
-            if !last_events.contains_key(&event_key) {
-                if config.features.login_notifications {
-                    let event = DiscordEvent {
-                        device_alias: config.device.alias.clone(),
-                        device_id_hash: "hash_placeholder".to_string(), // In real implementation, get actual hash
-                        event_type: EventType::Login,
-                        timestamp: Utc::now(),
-                        user_local: Some(username.clone()),
-                        notes: None,
-                    };
-                    
-                    if let Err(e) = discord.send_event(event).await {
-                        error!("Failed to send login event: {}", e);
-                    }
+    async fn check_auth_failures(
+        notifier: &dyn Notifier,
+        identity: &DeviceIdentity,
+        storage: &SecureStorage,
+        config: &Arc<RwLock<Config>>,
+        system: &SystemManager,
+        reader: &mut AuthLogReader,
+        detector: &mut BruteForceDetector
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let failures = reader.read_failures().await.map_err(|e| e.to_string())?;
+        let config = config.read().await.clone();
+        let settings = &config.security.brute_force;
+
+        // One event per user and source per poll, however many lines a burst wrote
+        for failure in auth_log::aggregate(failures) {
+            let details = failure.describe();
+            warn!("Failed authentication for {}: {}", failure.username.as_deref().unwrap_or("unknown user"), details);
+
+            if let Err(e) = storage.log_audit_event("failed_auth", &serde_json::to_value(&failure)?).await {
+                error!("Failed to log failed authentication: {}", e);
+            }
+
+            if config.features.failed_auth_notifications {
+                let username = failure.username.clone().unwrap_or_else(|| "unknown".to_string());
+                let event = DiscordEvent::new(&config, identity, EventType::FailedAuth, Some(username), Some(details));
+
+                if let Err(e) = notifier.notify(&event).await {
+                    error!("Failed to send failed authentication event: {}", e);
                 }
-                
-                last_events.insert(event_key, Utc::now());
+            }
+
+            for alert in detector.record(&failure, settings) {
+                Self::escalate_brute_force(notifier, &config, identity, storage, system, &alert).await;
             }
         }
-        
-        // Check for logouts (users that were logged in but are no longer present)
-        let mut users_to_remove = Vec::new();
-        for (event_key, _) in last_events.iter() {
-            if event_key.starts_with("login_") {
-                let username = event_key.strip_prefix("login_").unwrap_or("");
-                if !current_users.contains(&username.to_string()) {
-                    if config.features.logout_notifications {
-                        let event = DiscordEvent {
-                            device_alias: config.device.alias.clone(),
-                            device_id_hash: "hash_placeholder".to_string(),
-                            event_type: EventType::Logout,
-                            timestamp: Utc::now(),
-                            user_local: Some(username.to_string()),
-                            notes: None,
-                        };
-                        
-                        if let Err(e) = discord.send_event(event).await {
-                            error!("Failed to send logout event: {}", e);
-                        }
-                    }
-                    
-                    users_to_remove.push(event_key.clone());
+
+        detector.prune(Utc::now(), settings);
+        Ok(())
+    }
+
+    async fn escalate_brute_force(
+        notifier: &dyn Notifier,
+        config: &Config,
+        identity: &DeviceIdentity,
+        storage: &SecureStorage,
+        system: &SystemManager,
+        alert: &BruteForceAlert
+    ) {
+        let settings = &config.security.brute_force;
+        let details = alert.describe();
+        warn!("Brute-force attack detected: {}", details);
+
+        match serde_json::to_value(alert) {
+            Ok(log_entry) => {
+                if let Err(e) = storage.log_audit_event("brute_force_detected", &log_entry).await {
+                    error!("Failed to log brute-force alert: {}", e);
                 }
             }
+            Err(e) => error!("Failed to serialize brute-force alert: {}", e),
         }
-        
-        // Remove logged out users
-        for event_key in users_to_remove {
-            last_events.remove(&event_key);
+
+        let response = match Self::run_brute_force_response(system, settings, alert).await.map_err(|e| e.to_string()) {
+            Ok(Some(action)) => {
+                info!("Brute-force response: {}", action);
+                format!("\nResponse: {}", action)
+            }
+            Ok(None) => String::new(),
+            Err(e) => {
+                error!("Brute-force response failed: {}", e);
+                format!("\nResponse failed: {}", e)
+            }
+        };
+
+        if settings.response != BruteForceResponse::None {
+            let log_entry = serde_json::json!({
+                "response": settings.response,
+                "target": alert.key,
+                "result": response.trim()
+            });
+
+            if let Err(e) = storage.log_audit_event("brute_force_response", &log_entry).await {
+                error!("Failed to log brute-force response: {}", e);
+            }
+        }
+
+        let user = alert.users.first().filter(|_| alert.users.len() == 1).cloned();
+        let event = DiscordEvent::new(config, identity, EventType::BruteForceDetected, user, Some(format!("{}{}", details, response)));
+
+        if let Err(e) = notifier.notify(&event).await {
+            error!("Failed to send brute-force alert: {}", e);
+        }
+    }
+
+    async fn run_brute_force_response(
+        system: &SystemManager,
+        settings: &BruteForceConfig,
+        alert: &BruteForceAlert
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        match settings.response {
+            BruteForceResponse::None => Ok(None),
+            BruteForceResponse::LockScreen => {
+                system.lock_screen().await?;
+                Ok(Some("screen locked".to_string()))
+            }
+            BruteForceResponse::Hook => {
+                let hook = settings.hook_command.as_deref().ok_or("No hook_command configured")?;
+                let target = serde_json::to_value(alert.target)?;
+
+                let mut command = tokio::process::Command::new(hook);
+                command
+                    .env("BRUTE_FORCE_TARGET", target.as_str().unwrap_or_default())
+                    .env("BRUTE_FORCE_KEY", &alert.key)
+                    .env("BRUTE_FORCE_FAILURES", alert.failures.to_string())
+                    .env("BRUTE_FORCE_USERS", alert.users.join(","))
+                    .env("BRUTE_FORCE_SOURCES", alert.sources.iter().map(|ip| ip.to_string()).collect::<Vec<_>>().join(","))
+                    .kill_on_drop(true);
+
+                let status = tokio::time::timeout(Duration::from_secs(30), command.status()).await
+                    .map_err(|_| format!("{} timed out", hook))??;
+
+                if !status.success() {
+                    return Err(format!("{} exited with {}", hook, status).into());
+                }
+
+                Ok(Some(format!("ran {}", hook)))
+            }
         }
-        
-        Ok(())
     }
 
     async fn check_system_health(
-        discord: &DiscordClient,
-        storage: &SecureStorage,
-        config: &Arc<RwLock<Config>>
+        notifier: &dyn Notifier,
+        identity: &DeviceIdentity,
+        config: &Arc<RwLock<Config>>,
+        system: &SystemManager,
+        health: &mut HealthMonitor
     ) -> Result<(), Box<dyn std::error::Error>> {
         let config = config.read().await;
         
-        if !config.features.audit_logging {
+        if !config.health.enabled {
             return Ok(());
         }
 
-        // Check memory usage
-        let memory_info = self.system.get_memory_usage().await?;
-        let memory_usage_percent = memory_info["memory_usage_percent"].as_f64().unwrap_or(0.0);
-        
-        if memory_usage_percent > 90.0 {
-            let event = DiscordEvent {
-                device_alias: config.device.alias.clone(),
-                device_id_hash: "hash_placeholder".to_string(),
-                event_type: EventType::Heartbeat,
-                timestamp: Utc::now(),
-                user_local: None,
-                notes: Some(format!("High memory usage: {:.1}%", memory_usage_percent)),
+        let snapshot = system.health_snapshot().await?;
+
+        for change in health.evaluate(&config.health, &snapshot, Utc::now()) {
+            let event = match change {
+                HealthChange::Alert { severity, notes } => {
+                    let mut event = DiscordEvent::new(&config, identity, EventType::HealthAlert, None, Some(notes));
+                    event.severity = Some(severity);
+                    event
+                }
+                HealthChange::Resolved { notes } => DiscordEvent::new(&config, identity, EventType::Resolved, None, Some(notes)),
             };
-            
-            if let Err(e) = discord.send_event(event).await {
-                error!("Failed to send high memory warning: {}", e);
+
+            if let Err(e) = notifier.notify(&event).await {
+                error!("Failed to send health notification: {}", e);
             }
         }
         
-        // Check CPU usage
-        let cpu_info = self.system.get_cpu_usage().await?;
-        let global_cpu_usage = cpu_info["global_cpu_usage"].as_f64().unwrap_or(0.0);
-        
-        if global_cpu_usage > 95.0 {
-            let event = DiscordEvent {
-                device_alias: config.device.alias.clone(),
-                device_id_hash: "hash_placeholder".to_string(),
-                event_type: EventType::Heartbeat,
-                timestamp: Utc::now(),
-                user_local: None,
-                notes: Some(format!("High CPU usage: {:.1}%", global_cpu_usage)),
-            };
-            
-            if let Err(e) = discord.send_event(event).await {
-                error!("Failed to send high CPU warning: {}", e);
+        Ok(())
+    }
+
+    // Notifies once when a condition starts and sends a Resolved event when it clears,
+    // instead of repeating the warning on every check
+    async fn report_condition(
+        notifier: &dyn Notifier,
+        identity: &DeviceIdentity,
+        config: &Config,
+        conditions: &mut ConditionTracker,
+        key: &str,
+        active: bool,
+        notes: String
+    ) {
+        let now = Utc::now();
+
+        let event = if active {
+            if !conditions.raise(key, now) {
+                return;
+            }
+            DiscordEvent::new(config, identity, EventType::NetworkChange, None, Some(notes))
+        } else {
+            match conditions.clear(key) {
+                Some(condition) => {
+                    let notes = dedup::resolved_notes(&format!("{} failure", key), &condition, now);
+                    DiscordEvent::new(config, identity, EventType::Resolved, None, Some(notes))
+                }
+                None => return,
             }
+        };
+
+        if let Err(e) = notifier.notify(&event).await {
+            error!("Failed to send {} notification: {}", key, e);
         }
-        
-        Ok(())
     }
 
     async fn check_network_status(
-        discord: &DiscordClient,
+        notifier: &dyn Notifier,
+        identity: &DeviceIdentity,
         storage: &SecureStorage,
-        config: &Arc<RwLock<Config>>
+        config: &Arc<RwLock<Config>>,
+        conditions: &mut ConditionTracker,
+        previous: &mut Option<NetworkState>
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let config = config.read().await;
+        // Probes can take a while, so don't hold up reloads meanwhile
+        let config = config.read().await.clone();
         
-        if !config.features.audit_logging {
+        if !config.network.enabled {
             return Ok(());
         }
 
-        // Simple network connectivity check
-        let test_url = "https://httpbin.org/get";
-        let client = reqwest::Client::new();
-        
-        match client.get(test_url).timeout(Duration::from_secs(10)).send().await {
-            Ok(response) => {
-                if !response.status().is_success() {
-                    let event = DiscordEvent {
-                        device_alias: config.device.alias.clone(),
-                        device_id_hash: "hash_placeholder".to_string(),
-                        event_type: EventType::Heartbeat,
-                        timestamp: Utc::now(),
-                        user_local: None,
-                        notes: Some(format!("Network connectivity issue: HTTP {}", response.status())),
-                    };
-                    
-                    if let Err(e) = discord.send_event(event).await {
-                        error!("Failed to send network warning: {}", e);
-                    }
-                }
-            }
-            Err(e) => {
-                let event = DiscordEvent {
-                    device_alias: config.device.alias.clone(),
-                    device_id_hash: "hash_placeholder".to_string(),
-                    event_type: EventType::Heartbeat,
-                    timestamp: Utc::now(),
-                    user_local: None,
-                    notes: Some(format!("Network connectivity lost: {}", e)),
-                };
-                
-                if let Err(e) = discord.send_event(event).await {
-                    error!("Failed to send network warning: {}", e);
+        let timeout = Duration::from_secs(config.network.timeout_seconds);
+        for probe in network::probes(&config.network) {
+            let key = format!("Connectivity probe {}", network::probe_label(&probe));
+            match network::run_probe(&probe, timeout).await {
+                Ok(()) => Self::report_condition(notifier, identity, &config, conditions, &key, false, String::new()).await,
+                Err(ProbeError::Failed(e)) => {
+                    let notes = format!("{} failed: {}", key, e);
+                    Self::report_condition(notifier, identity, &config, conditions, &key, true, notes).await;
                 }
+                // Says nothing about the network, so any open alert stays as it is
+                Err(ProbeError::Unavailable(e)) => warn!("{} could not run: {}", key, e),
             }
         }
+
+        if !config.network.detect_changes {
+            return Ok(());
+        }
+
+        let current = network::current_state(previous.as_ref()).await?;
+        let before = match previous.replace(current.clone()) {
+            Some(before) => before,
+            None => return Ok(()),
+        };
+
+        let mut known_ssids: Vec<String> = match storage.retrieve_encrypted_data(network::KNOWN_SSIDS_KEY).await? {
+            Some(data) => serde_json::from_slice(&data)?,
+            None => Vec::new(),
+        };
+
+        let changes = network::describe_changes(&before, &current, &known_ssids);
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(ssid) = current.ssid.as_ref().filter(|ssid| !known_ssids.contains(ssid)) {
+            known_ssids.push(ssid.clone());
+            storage.store_encrypted_data(network::KNOWN_SSIDS_KEY, &serde_json::to_vec(&known_ssids)?).await?;
+        }
+
+        let event = DiscordEvent::new(&config, identity, EventType::NetworkChange, None, Some(changes.join("\n")));
+        if let Err(e) = notifier.notify(&event).await {
+            error!("Failed to send network change notification: {}", e);
+        }
         
         Ok(())
     }
 
+    #[allow(dead_code)]
     pub async fn trigger_custom_event(
         &self,
         event_type: EventType,
@@ -322,23 +525,24 @@ impl EventMonitor {
         
         let event = DiscordEvent {
             device_alias: config.device.alias.clone(),
-            device_id_hash: "hash_placeholder".to_string(),
+            device_id_hash: self.identity.fingerprint().to_string(),
             event_type,
             timestamp: Utc::now(),
             user_local: user,
             notes,
+            severity: None,
         };
         
-        if let Err(e) = self.discord.send_event(event).await {
+        if let Err(e) = self.notifier.notify(&event).await {
             error!("Failed to send custom event: {}", e);
             return Err(e);
         }
         
         // Log the event
         let log_entry = serde_json::json!({
-            "event_type": format!("{:?}", event_type),
-            "user": user,
-            "notes": notes,
+            "event_type": format!("{:?}", event.event_type),
+            "user": event.user_local,
+            "notes": event.notes,
             "timestamp": Utc::now().to_rfc3339()
         });
         
@@ -347,19 +551,14 @@ impl EventMonitor {
         Ok(())
     }
 
+    #[allow(dead_code)]
     pub async fn get_monitoring_status(&self) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
         let monitoring = self.monitoring.read().await;
-        let last_events = self.last_events.read().await;
+        let sessions = self.sessions.read().await.sessions();
         
         Ok(serde_json::json!({
             "monitoring_active": *monitoring,
-            "active_event_monitors": last_events.len(),
-            "last_events": last_events.iter().map(|(k, v)| {
-                serde_json::json!({
-                    "event": k,
-                    "timestamp": v.to_rfc3339()
-                })
-            }).collect::<Vec<_>>(),
+            "active_sessions": sessions,
             "timestamp": Utc::now().to_rfc3339()
         }))
     }
@@ -0,0 +1,235 @@
+use crate::config::{HealthConfig, HealthThreshold};
+use crate::dedup::{self, Condition};
+use crate::discord::Severity;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+
+// One sample of everything the health checks look at
+#[derive(Debug, Clone, Default)]
+pub struct HealthSnapshot {
+    pub cpu_percent: f64,
+    pub memory_percent: f64,
+    // None on machines without swap
+    pub swap_percent: Option<f64>,
+    pub load_per_core: f64,
+    // (mount point, percent used)
+    pub disks: Vec<(String, f64)>,
+    // (sensor label, degrees Celsius)
+    pub temperatures: Vec<(String, f64)>,
+    pub process_count: usize,
+}
+
+// Thresholds in effect for a check once the built-in defaults are applied
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    pub warning: f64,
+    pub critical: Option<f64>,
+    pub duration: Duration,
+}
+
+// Built-in warning and critical levels and how long (seconds) a value must stay above them
+fn defaults(check: &str) -> (f64, Option<f64>, u64) {
+    match check {
+        "cpu" => (90.0, Some(98.0), 300),
+        "memory" => (90.0, Some(97.0), 300),
+        "swap" => (50.0, Some(80.0), 300),
+        "disk" => (90.0, Some(95.0), 0),
+        "load" => (2.0, Some(4.0), 300),
+        "temperature" => (85.0, Some(95.0), 60),
+        _ => (1500.0, None, 300),
+    }
+}
+
+pub fn limits(check: &str, threshold: &HealthThreshold) -> Limits {
+    let (warning, critical, duration_seconds) = defaults(check);
+    let warning = threshold.warning.unwrap_or(warning);
+
+    Limits {
+        warning,
+        // A default critical level below a raised warning level would never apply
+        critical: threshold.critical.or(critical.filter(|critical| *critical > warning)),
+        duration: Duration::seconds(threshold.duration_seconds.unwrap_or(duration_seconds) as i64),
+    }
+}
+
+fn thresholds(config: &HealthConfig) -> [(&'static str, &HealthThreshold); 7] {
+    [
+        ("cpu", &config.cpu),
+        ("memory", &config.memory),
+        ("swap", &config.swap),
+        ("disk", &config.disk),
+        ("load", &config.load),
+        ("temperature", &config.temperature),
+        ("processes", &config.processes),
+    ]
+}
+
+pub fn check(config: &HealthConfig) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    if config.enabled && config.interval_seconds == 0 {
+        errors.push("health: interval_seconds must be greater than zero".to_string());
+    }
+
+    for (name, threshold) in thresholds(config) {
+        let limits = limits(name, threshold);
+        if limits.warning <= 0.0 {
+            errors.push(format!("health.{}: warning must be greater than zero", name));
+        }
+        if limits.critical.is_some_and(|critical| critical < limits.warning) {
+            errors.push(format!("health.{}: critical must not be below warning", name));
+        }
+    }
+
+    errors
+}
+
+#[derive(Debug, Clone)]
+pub struct Reading {
+    // Identifies the condition across samples, e.g. "disk:/home"
+    pub key: String,
+    pub check: &'static str,
+    pub label: String,
+    pub value: f64,
+    pub unit: &'static str,
+}
+
+pub fn readings(config: &HealthConfig, snapshot: &HealthSnapshot) -> Vec<Reading> {
+    let reading = |key: String, check, label: String, value, unit| Reading { key, check, label, value, unit };
+
+    let mut readings = vec![
+        reading("cpu".to_string(), "cpu", "CPU usage".to_string(), snapshot.cpu_percent, "%"),
+        reading("memory".to_string(), "memory", "Memory usage".to_string(), snapshot.memory_percent, "%"),
+    ];
+    if let Some(swap) = snapshot.swap_percent {
+        readings.push(reading("swap".to_string(), "swap", "Swap usage".to_string(), swap, "%"));
+    }
+    for (mount, used) in &snapshot.disks {
+        if config.mounts.is_empty() || config.mounts.contains(mount) {
+            readings.push(reading(format!("disk:{}", mount), "disk", format!("Disk usage on {}", mount), *used, "%"));
+        }
+    }
+    readings.push(reading("load".to_string(), "load", "Load average per core".to_string(), snapshot.load_per_core, ""));
+    for (sensor, celsius) in &snapshot.temperatures {
+        readings.push(reading(format!("temperature:{}", sensor), "temperature", format!("Temperature of {}", sensor), *celsius, "°C"));
+    }
+    readings.push(reading("processes".to_string(), "processes", "Process count".to_string(), snapshot.process_count as f64, ""));
+
+    let enabled: Vec<&str> = thresholds(config).into_iter()
+        .filter(|(_, threshold)| threshold.enabled)
+        .map(|(name, _)| name)
+        .collect();
+    readings.retain(|reading| enabled.contains(&reading.check));
+
+    readings
+}
+
+fn format_value(value: f64, unit: &str) -> String {
+    format!("{}{}", (value * 10.0).round() / 10.0, unit)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HealthChange {
+    Alert { severity: Severity, notes: String },
+    Resolved { notes: String },
+}
+
+#[derive(Debug, Clone)]
+struct Breach {
+    label: String,
+    since: DateTime<Utc>,
+    checks: u32,
+    // Highest severity reported so far, None until the duration has passed
+    alerted: Option<Severity>,
+}
+
+// Turns successive snapshots into alerts: one when a value has stayed above a threshold
+// for the check's duration, another if it climbs to critical, and a resolution once it
+// drops back below the warning level
+#[derive(Debug, Default)]
+pub struct HealthMonitor {
+    breaches: HashMap<String, Breach>,
+}
+
+impl HealthMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn evaluate(&mut self, config: &HealthConfig, snapshot: &HealthSnapshot, now: DateTime<Utc>) -> Vec<HealthChange> {
+        let mut changes = Vec::new();
+        let readings = readings(config, snapshot);
+
+        for reading in &readings {
+            let threshold = thresholds(config).into_iter()
+                .find(|(name, _)| *name == reading.check)
+                .map(|(_, threshold)| threshold);
+            let limits = match threshold {
+                Some(threshold) => limits(reading.check, threshold),
+                None => continue,
+            };
+
+            let level = match limits.critical {
+                Some(critical) if reading.value >= critical => Some((Severity::Critical, critical)),
+                _ if reading.value >= limits.warning => Some((Severity::Warning, limits.warning)),
+                _ => None,
+            };
+
+            let (severity, limit) = match level {
+                Some(level) => level,
+                None => {
+                    if let Some(breach) = self.breaches.remove(&reading.key) {
+                        changes.extend(Self::resolved(&breach, now));
+                    }
+                    continue;
+                }
+            };
+
+            let breach = self.breaches.entry(reading.key.clone()).or_insert_with(|| Breach {
+                label: reading.label.clone(),
+                since: now,
+                checks: 0,
+                alerted: None,
+            });
+            breach.checks += 1;
+
+            if now - breach.since >= limits.duration && breach.alerted.is_none_or(|alerted| severity > alerted) {
+                breach.alerted = Some(severity);
+
+                let mut notes = format!(
+                    "{} is {}, above the {} threshold of {}",
+                    reading.label,
+                    format_value(reading.value, reading.unit),
+                    severity.as_str(),
+                    format_value(limit, reading.unit)
+                );
+                if limits.duration > Duration::zero() {
+                    notes.push_str(&format!(" for {}m", (now - breach.since).num_minutes()));
+                }
+                changes.push(HealthChange::Alert { severity, notes });
+            }
+        }
+
+        // Conditions whose reading went away (unmounted disk, disabled check) end too
+        let gone: Vec<String> = self.breaches.keys()
+            .filter(|key| !readings.iter().any(|reading| &reading.key == *key))
+            .cloned()
+            .collect();
+        for key in gone {
+            if let Some(breach) = self.breaches.remove(&key) {
+                changes.extend(Self::resolved(&breach, now));
+            }
+        }
+
+        changes
+    }
+
+    fn resolved(breach: &Breach, now: DateTime<Utc>) -> Option<HealthChange> {
+        breach.alerted?;
+
+        let condition = Condition { since: breach.since, occurrences: breach.checks };
+        Some(HealthChange::Resolved {
+            notes: dedup::resolved_notes(&format!("{} alert", breach.label), &condition, now),
+        })
+    }
+}
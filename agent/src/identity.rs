@@ -0,0 +1,113 @@
+use crate::storage::SecureStorage;
+use base64::{Engine as _, engine::general_purpose};
+use chrono::{DateTime, Utc};
+use ring::digest::{digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+const STORAGE_KEY: &str = "device_identity";
+const IDENTITY_VERSION: u32 = 1;
+
+// Generated once per install and kept in SecureStorage. The device id addresses
+// commands to this device; the fingerprint (SHA-256 of the Ed25519 public key)
+// identifies it in events without revealing the id.
+pub struct DeviceIdentity {
+    device_id: String,
+    pkcs8: Vec<u8>,
+    keypair: Ed25519KeyPair,
+    fingerprint: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredIdentity {
+    version: u32,
+    device_id: String,
+    pkcs8: String,
+    created_at: DateTime<Utc>,
+}
+
+impl DeviceIdentity {
+    pub async fn load_or_create(storage: &SecureStorage) -> Result<Self, Box<dyn std::error::Error>> {
+        // A stored identity that cannot be read is an error rather than a reason to replace
+        // it, since replacing it unpairs the device
+        let data = storage.retrieve_encrypted_data(STORAGE_KEY).await
+            .map_err(|e| format!("Stored device identity could not be read: {}", e))?;
+        if let Some(data) = data {
+            let stored: StoredIdentity = serde_json::from_slice(&data)
+                .map_err(|e| format!("Stored device identity is corrupt: {}", e))?;
+            return Self::from_stored(&stored);
+        }
+
+        let identity = Self::generate()?;
+        let stored = StoredIdentity {
+            version: IDENTITY_VERSION,
+            device_id: identity.device_id.clone(),
+            pkcs8: general_purpose::STANDARD.encode(&identity.pkcs8),
+            created_at: identity.created_at,
+        };
+        storage.store_encrypted_data(STORAGE_KEY, &serde_json::to_vec(&stored)?).await?;
+
+        info!("Generated device identity {} ({})", identity.device_id, identity.fingerprint);
+        Ok(identity)
+    }
+
+    // A new identity that is not persisted
+    pub fn generate() -> Result<Self, Box<dyn std::error::Error>> {
+        let rng = SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
+            .map_err(|_| "Failed to generate device key")?;
+
+        Self::from_parts(uuid::Uuid::new_v4().to_string(), pkcs8.as_ref().to_vec(), Utc::now())
+    }
+
+    fn from_stored(stored: &StoredIdentity) -> Result<Self, Box<dyn std::error::Error>> {
+        if stored.version != IDENTITY_VERSION {
+            return Err(format!("Unsupported device identity version: {}", stored.version).into());
+        }
+
+        let pkcs8 = general_purpose::STANDARD.decode(&stored.pkcs8)?;
+        Self::from_parts(stored.device_id.clone(), pkcs8, stored.created_at)
+    }
+
+    fn from_parts(device_id: String, pkcs8: Vec<u8>, created_at: DateTime<Utc>) -> Result<Self, Box<dyn std::error::Error>> {
+        let keypair = Ed25519KeyPair::from_pkcs8(&pkcs8)
+            .map_err(|e| format!("Device key is invalid: {}", e))?;
+        let fingerprint = hex_encode(digest(&SHA256, keypair.public_key().as_ref()).as_ref());
+
+        Ok(Self {
+            device_id,
+            pkcs8,
+            keypair,
+            fingerprint,
+            created_at,
+        })
+    }
+
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        self.keypair.public_key().as_ref()
+    }
+
+    #[allow(dead_code)]
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.keypair.sign(message).as_ref().to_vec()
+    }
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,206 @@
+use crate::identity::DeviceIdentity;
+use crate::security;
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use sha2::Sha256;
+
+// RFC 7518 section 3.2: HS256 keys must be at least as long as the hash output
+const MIN_HMAC_KEY_BYTES: usize = 32;
+
+// JSON Web Tokens (RFC 7519) in compact JWS form, signed with HS256 (RFC 7518) or
+// Ed25519 (EdDSA, RFC 8037). The algorithm is fixed by the key a token is checked
+// against, never by the token's own header, so "none" and HS256-signed-with-a-public-key
+// tokens are rejected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    Hs256,
+    EdDsa,
+}
+
+impl Algorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Algorithm::Hs256 => "HS256",
+            Algorithm::EdDsa => "EdDSA",
+        }
+    }
+}
+
+pub enum SigningKey<'a> {
+    #[allow(dead_code)]
+    Hs256(&'a [u8]),
+    EdDsa(&'a DeviceIdentity),
+}
+
+#[derive(Clone, Copy)]
+pub enum VerifyingKey<'a> {
+    Hs256(&'a [u8]),
+    // Raw 32-byte Ed25519 public key
+    EdDsa(&'a [u8]),
+}
+
+impl VerifyingKey<'_> {
+    fn algorithm(&self) -> Algorithm {
+        match self {
+            VerifyingKey::Hs256(_) => Algorithm::Hs256,
+            VerifyingKey::EdDsa(_) => Algorithm::EdDsa,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Validation {
+    // Clock skew tolerated when checking exp and nbf
+    pub leeway_seconds: i64,
+    // Tokens carrying an aud claim are only accepted if it names this audience
+    pub audience: Option<String>,
+    pub issuer: Option<String>,
+    pub require_exp: bool,
+}
+
+impl Default for Validation {
+    fn default() -> Self {
+        Self {
+            leeway_seconds: 60,
+            audience: None,
+            issuer: None,
+            require_exp: true,
+        }
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if key.len() < MIN_HMAC_KEY_BYTES {
+        return Err(format!("HS256 keys must be at least {} bytes", MIN_HMAC_KEY_BYTES).into());
+    }
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)?;
+    mac.update(data);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+pub fn encode(claims: &serde_json::Value, key: &SigningKey) -> Result<String, Box<dyn std::error::Error>> {
+    if !claims.is_object() {
+        return Err("JWT claims must be a JSON object".into());
+    }
+
+    let algorithm = match key {
+        SigningKey::Hs256(_) => Algorithm::Hs256,
+        SigningKey::EdDsa(_) => Algorithm::EdDsa,
+    };
+    let header = serde_json::json!({ "alg": algorithm.as_str(), "typ": "JWT" });
+
+    let signing_input = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims)?)
+    );
+    let signature = match key {
+        SigningKey::Hs256(secret) => hmac_sha256(secret, signing_input.as_bytes())?,
+        SigningKey::EdDsa(identity) => identity.sign(signing_input.as_bytes()),
+    };
+
+    Ok(format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature)))
+}
+
+// Checks the header and signature and returns the decoded payload, which for JWTs
+// holds the claims
+pub fn verify_signature(token: &str, keys: &[VerifyingKey]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let parts: Vec<&str> = token.split('.').collect();
+    let (header_b64, payload_b64, signature_b64) = match parts.as_slice() {
+        [header, payload, signature] => (*header, *payload, *signature),
+        _ => return Err("Malformed JWT: expected three dot-separated parts".into()),
+    };
+
+    let header: serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header_b64).map_err(|_| "Malformed JWT header")?)
+        .map_err(|_| "Malformed JWT header")?;
+    let algorithm = header["alg"].as_str().ok_or("JWT header has no alg")?;
+    let key = keys.iter()
+        .find(|key| key.algorithm().as_str() == algorithm)
+        .ok_or_else(|| format!("Unexpected JWT algorithm {}", algorithm))?;
+
+    if let Some(typ) = header.get("typ") {
+        if !typ.as_str().is_some_and(|typ| typ.eq_ignore_ascii_case("JWT")) {
+            return Err(format!("Unexpected JWT type {}", typ).into());
+        }
+    }
+    // RFC 7515 section 4.1.11: extensions marked critical must be understood, and none are
+    if header.get("crit").is_some() {
+        return Err("Unsupported critical JWT header parameters".into());
+    }
+
+    let signing_input = &token[..header_b64.len() + 1 + payload_b64.len()];
+    let signature = URL_SAFE_NO_PAD.decode(signature_b64).map_err(|_| "Malformed JWT signature")?;
+
+    let valid = match key {
+        VerifyingKey::Hs256(secret) => security::constant_time_eq(&hmac_sha256(secret, signing_input.as_bytes())?, &signature),
+        VerifyingKey::EdDsa(public_key) => UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(signing_input.as_bytes(), &signature)
+            .is_ok(),
+    };
+    if !valid {
+        return Err("Invalid JWT signature".into());
+    }
+
+    Ok(URL_SAFE_NO_PAD.decode(payload_b64).map_err(|_| "Malformed JWT payload")?)
+}
+
+fn numeric_date(claims: &serde_json::Value, name: &str) -> Result<Option<f64>, Box<dyn std::error::Error>> {
+    match claims.get(name) {
+        None => Ok(None),
+        Some(value) => value.as_f64().map(Some).ok_or_else(|| format!("JWT {} claim is not a number", name).into()),
+    }
+}
+
+pub fn validate_claims(claims: &serde_json::Value, validation: &Validation, now: DateTime<Utc>) -> Result<(), Box<dyn std::error::Error>> {
+    let now = now.timestamp() as f64;
+    let leeway = validation.leeway_seconds as f64;
+
+    match numeric_date(claims, "exp")? {
+        Some(exp) if now - leeway >= exp => return Err("JWT has expired".into()),
+        None if validation.require_exp => return Err("JWT has no exp claim".into()),
+        _ => {}
+    }
+    if numeric_date(claims, "nbf")?.is_some_and(|nbf| now + leeway < nbf) {
+        return Err("JWT is not valid yet".into());
+    }
+
+    // RFC 7519 section 4.1.3: a token with an audience that does not include us is rejected
+    if let Some(aud) = claims.get("aud") {
+        let audiences: Vec<&str> = match aud {
+            serde_json::Value::String(audience) => vec![audience.as_str()],
+            serde_json::Value::Array(audiences) => audiences.iter().filter_map(serde_json::Value::as_str).collect(),
+            _ => return Err("JWT aud claim is not a string or array".into()),
+        };
+        if !validation.audience.as_deref().is_some_and(|expected| audiences.contains(&expected)) {
+            return Err("JWT audience does not match".into());
+        }
+    } else if validation.audience.is_some() {
+        return Err("JWT has no aud claim".into());
+    }
+
+    if let Some(issuer) = &validation.issuer {
+        if claims["iss"].as_str() != Some(issuer.as_str()) {
+            return Err("JWT issuer does not match".into());
+        }
+    }
+
+    Ok(())
+}
+
+pub fn decode(token: &str, keys: &[VerifyingKey], validation: &Validation) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    decode_at(token, keys, validation, Utc::now())
+}
+
+pub fn decode_at(token: &str, keys: &[VerifyingKey], validation: &Validation, now: DateTime<Utc>) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let payload = verify_signature(token, keys)?;
+    let claims: serde_json::Value = serde_json::from_slice(&payload).map_err(|_| "Malformed JWT claims")?;
+    if !claims.is_object() {
+        return Err("JWT claims must be a JSON object".into());
+    }
+
+    validate_claims(&claims, validation, now)?;
+    Ok(claims)
+}
@@ -0,0 +1,393 @@
+use crate::config::{EncryptionConfig, MasterKeySource};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{Engine as _, engine::general_purpose};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+pub const KEY_LEN: usize = 32;
+
+const KEYRING_FILE: &str = "keyring.json";
+const MASTER_KEY_FILE: &str = "master.key";
+
+// Encrypted data starts with a header: magic, format version, algorithm and the version
+// of the data key (big-endian u32), followed by nonce, ciphertext and tag. The header and
+// the storage key name are authenticated as associated data, so the header cannot be
+// altered and data stored under one name does not decrypt under another.
+const ENVELOPE_MAGIC: &[u8; 4] = b"DNEC";
+const FORMAT_VERSION: u8 = 1;
+const ALGORITHM_AES_256_GCM: u8 = 1;
+const HEADER_LEN: usize = 10;
+
+// Label the audit log HMAC key is derived from the master key with
+const AUDIT_KEY_LABEL: &[u8] = b"device-notifier audit log v1";
+
+// Before the envelope, data started with just the data key version
+const LEGACY_VERSION_LEN: usize = 4;
+
+// Argon2id cost for passphrase-derived master keys: 19 MiB and two passes, the OWASP
+// baseline. Stored in the keyring so it can be raised without breaking old keyrings.
+const ARGON2_MEMORY_KIB: u32 = 19 * 1024;
+const ARGON2_ITERATIONS: u32 = 2;
+const ARGON2_PARALLELISM: u32 = 1;
+
+pub fn random_bytes(len: usize) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut bytes = vec![0u8; len];
+    SystemRandom::new().fill(&mut bytes).map_err(|_| "Failed to generate random bytes")?;
+    Ok(bytes)
+}
+
+fn aead_key(key: &[u8]) -> Result<LessSafeKey, Box<dyn std::error::Error>> {
+    let key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| "Invalid encryption key")?;
+    Ok(LessSafeKey::new(key))
+}
+
+// AES-256-GCM with a random nonce, returned as nonce || ciphertext || tag
+pub fn seal(key: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut sealed = random_bytes(NONCE_LEN)?;
+    let nonce = Nonce::try_assume_unique_for_key(&sealed).map_err(|_| "Invalid nonce")?;
+
+    let mut ciphertext = plaintext.to_vec();
+    aead_key(key)?
+        .seal_in_place_append_tag(nonce, Aad::from(aad), &mut ciphertext)
+        .map_err(|_| "Encryption failed")?;
+
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+pub fn open(key: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if sealed.len() < NONCE_LEN + AES_256_GCM.tag_len() {
+        return Err("Encrypted data too short".into());
+    }
+
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| "Invalid nonce")?;
+
+    let mut plaintext = ciphertext.to_vec();
+    let len = aead_key(key)?
+        .open_in_place(nonce, Aad::from(aad), &mut plaintext)
+        .map_err(|_| "Decryption failed: wrong key or corrupted data")?
+        .len();
+
+    plaintext.truncate(len);
+    Ok(plaintext)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KdfParams {
+    salt: String,
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KeyringFile {
+    // Present when the master key is derived from a passphrase
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kdf: Option<KdfParams>,
+    current: u32,
+    // Data keys by version, each sealed with the master key
+    keys: BTreeMap<u32, String>,
+}
+
+fn envelope_aad(header: &[u8], context: &str) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.extend_from_slice(context.as_bytes());
+    aad
+}
+
+// Data written before the envelope format, which is rewritten in it on the next write
+pub fn is_legacy(data: &[u8]) -> bool {
+    !data.starts_with(ENVELOPE_MAGIC)
+}
+
+// Bound into each wrapped key so one version cannot be passed off as another
+fn wrap_aad(version: u32) -> Vec<u8> {
+    format!("device-notifier data key v{}", version).into_bytes()
+}
+
+// The data keys storage files are encrypted with. They are kept on disk wrapped with a
+// master key that comes from a passphrase or a keyfile, never from the keyring itself.
+pub struct KeyRing {
+    path: PathBuf,
+    master: Vec<u8>,
+    kdf: Option<KdfParams>,
+    current: u32,
+    keys: BTreeMap<u32, Vec<u8>>,
+}
+
+impl KeyRing {
+    // Loads the keyring in dir, creating it with a first data key if there is none
+    pub fn open(dir: &Path, config: &EncryptionConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = dir.join(KEYRING_FILE);
+        let existing = read_keyring(&path)?;
+
+        let kdf = match (config.master_key, &existing) {
+            (MasterKeySource::Keyfile, Some(KeyringFile { kdf: Some(_), .. })) => {
+                return Err("The keyring was created from a passphrase; set security.encryption.master_key = \"passphrase\"".into());
+            }
+            (MasterKeySource::Keyfile, _) => None,
+            (MasterKeySource::Passphrase, Some(file)) => Some(file.kdf.clone().ok_or(
+                "The keyring was created with a keyfile; set security.encryption.master_key = \"keyfile\""
+            )?),
+            (MasterKeySource::Passphrase, None) => Some(KdfParams {
+                salt: general_purpose::STANDARD.encode(random_bytes(16)?),
+                memory_kib: ARGON2_MEMORY_KIB,
+                iterations: ARGON2_ITERATIONS,
+                parallelism: ARGON2_PARALLELISM,
+            }),
+        };
+
+        let master = match &kdf {
+            Some(kdf) => {
+                let passphrase = match &config.passphrase {
+                    Some(passphrase) => passphrase.clone(),
+                    None => std::env::var(&config.passphrase_env)
+                        .map_err(|_| format!("{} must hold the storage passphrase", config.passphrase_env))?,
+                };
+                derive_master_key(&passphrase, kdf)?
+            }
+            None => {
+                let keyfile = config.keyfile.as_ref().map(PathBuf::from).unwrap_or_else(|| dir.join(MASTER_KEY_FILE));
+                load_keyfile(&keyfile, existing.is_none())?
+            }
+        };
+
+        let file = match existing {
+            Some(file) => file,
+            None => {
+                let keyring = Self {
+                    path: path.clone(),
+                    master,
+                    kdf,
+                    current: 1,
+                    keys: BTreeMap::from([(1, random_bytes(KEY_LEN)?)]),
+                };
+
+                return match create_file(&path, &serde_json::to_vec_pretty(&keyring.to_file()?)?) {
+                    Ok(()) => {
+                        info!("Created keyring {}", path.display());
+                        Ok(keyring)
+                    }
+                    // Another process created it first; use theirs
+                    Err(e) if e.kind() == ErrorKind::AlreadyExists => Self::open(dir, config),
+                    Err(e) => Err(format!("Could not write {}: {}", path.display(), e).into()),
+                };
+            }
+        };
+
+        let mut keys = BTreeMap::new();
+        for (version, wrapped) in &file.keys {
+            let wrapped = general_purpose::STANDARD.decode(wrapped)?;
+            let key = open(&master, &wrap_aad(*version), &wrapped)
+                .map_err(|_| format!("Could not unwrap data key v{}: wrong passphrase or master key", version))?;
+            keys.insert(*version, key);
+        }
+        if !keys.contains_key(&file.current) {
+            return Err(format!("{} has no data key v{}", path.display(), file.current).into());
+        }
+
+        Ok(Self { path, master, kdf: file.kdf, current: file.current, keys })
+    }
+
+    pub fn current_version(&self) -> u32 {
+        self.current
+    }
+
+    // Key for the audit log's HMAC chain. It comes from the master key rather than a data
+    // key, so the chain stays verifiable across key rotations.
+    pub fn audit_key(&self) -> Vec<u8> {
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &self.master);
+        ring::hmac::sign(&key, AUDIT_KEY_LABEL).as_ref().to_vec()
+    }
+
+    // context names what the data is, normally its storage key, and has to be given
+    // again to decrypt it
+    pub fn encrypt(&self, data: &[u8], context: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut envelope = ENVELOPE_MAGIC.to_vec();
+        envelope.push(FORMAT_VERSION);
+        envelope.push(ALGORITHM_AES_256_GCM);
+        envelope.extend_from_slice(&self.current.to_be_bytes());
+
+        let aad = envelope_aad(&envelope, context);
+        envelope.extend(seal(&self.keys[&self.current], &aad, data)?);
+        Ok(envelope)
+    }
+
+    pub fn decrypt(&self, data: &[u8], context: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        if is_legacy(data) {
+            return self.decrypt_legacy(data);
+        }
+        if data.len() < HEADER_LEN {
+            return Err("Encrypted data too short".into());
+        }
+
+        let (header, sealed) = data.split_at(HEADER_LEN);
+        if header[4] != FORMAT_VERSION {
+            return Err(format!("Unsupported encryption format version {}", header[4]).into());
+        }
+        if header[5] != ALGORITHM_AES_256_GCM {
+            return Err(format!("Unsupported encryption algorithm {}", header[5]).into());
+        }
+
+        let version = u32::from_be_bytes(header[6..HEADER_LEN].try_into()?);
+        open(self.key(version)?, &envelope_aad(header, context), sealed)
+    }
+
+    // Blobs from before the envelope: data key version (big-endian), then nonce, ciphertext
+    // and tag, with no associated data
+    fn decrypt_legacy(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        if data.len() < LEGACY_VERSION_LEN {
+            return Err("Encrypted data too short".into());
+        }
+
+        let (version, sealed) = data.split_at(LEGACY_VERSION_LEN);
+        open(self.key(u32::from_be_bytes(version.try_into()?))?, &[], sealed)
+    }
+
+    fn key(&self, version: u32) -> Result<&[u8], Box<dyn std::error::Error>> {
+        match self.keys.get(&version) {
+            Some(key) => Ok(key),
+            None => Err(format!("Unknown data key version {}", version).into()),
+        }
+    }
+
+    // Adds a data key and makes it current. Older keys stay until retire_old_keys so
+    // files written with them remain readable.
+    pub fn add_key(&mut self) -> Result<u32, Box<dyn std::error::Error>> {
+        let version = self.keys.keys().max().copied().unwrap_or(0) + 1;
+        self.keys.insert(version, random_bytes(KEY_LEN)?);
+        self.current = version;
+        self.save()?;
+        Ok(version)
+    }
+
+    pub fn retire_old_keys(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let current = self.current;
+        self.keys.retain(|version, _| *version == current);
+        self.save()
+    }
+
+    fn to_file(&self) -> Result<KeyringFile, Box<dyn std::error::Error>> {
+        let mut keys = BTreeMap::new();
+        for (version, key) in &self.keys {
+            keys.insert(*version, general_purpose::STANDARD.encode(seal(&self.master, &wrap_aad(*version), key)?));
+        }
+
+        Ok(KeyringFile { kdf: self.kdf.clone(), current: self.current, keys })
+    }
+
+    fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        replace_file(&self.path, &serde_json::to_vec_pretty(&self.to_file()?)?)
+    }
+}
+
+fn read_keyring(path: &Path) -> Result<Option<KeyringFile>, Box<dyn std::error::Error>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(serde_json::from_slice(&contents).map_err(|e| format!("Invalid keyring {}: {}", path.display(), e))?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("Could not read {}: {}", path.display(), e).into()),
+    }
+}
+
+fn derive_master_key(passphrase: &str, kdf: &KdfParams) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let salt = general_purpose::STANDARD.decode(&kdf.salt)?;
+    let params = Params::new(kdf.memory_kib, kdf.iterations, kdf.parallelism, Some(KEY_LEN))
+        .map_err(|e| format!("Invalid key derivation parameters: {}", e))?;
+
+    let mut key = vec![0u8; KEY_LEN];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+        .map_err(|e| format!("Key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+// The keyfile holds the base64 master key. It is only generated alongside a new keyring:
+// a keyring whose keyfile went missing cannot be opened with a fresh one.
+fn load_keyfile(path: &Path, create: bool) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => {
+            check_permissions(path)?;
+            let key = general_purpose::STANDARD.decode(contents.trim())
+                .map_err(|_| format!("Master key file {} is not valid base64", path.display()))?;
+            if key.len() != KEY_LEN {
+                return Err(format!("Master key file {} must hold a {}-byte key", path.display(), KEY_LEN).into());
+            }
+            Ok(key)
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound && create => {
+            let key = random_bytes(KEY_LEN)?;
+            match create_file(path, format!("{}\n", general_purpose::STANDARD.encode(&key)).as_bytes()) {
+                Ok(()) => {
+                    info!("Generated master key file {}", path.display());
+                    Ok(key)
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => load_keyfile(path, false),
+                Err(e) => Err(format!("Could not write {}: {}", path.display(), e).into()),
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            Err(format!("Master key file {} is missing; the keyring cannot be opened without it", path.display()).into())
+        }
+        Err(e) => Err(format!("Could not read {}: {}", path.display(), e).into()),
+    }
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(format!("Master key file {} is accessible to other users (mode {:o}); run chmod 600 on it", path.display(), mode & 0o777).into());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    Ok(())
+}
+
+// Writes to a temporary file readable only by the owner, so the target never holds a
+// partial write and is never briefly world-readable
+fn write_temp(path: &Path, contents: &[u8]) -> std::io::Result<PathBuf> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let temp = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(&temp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    Ok(temp)
+}
+
+// Fails with AlreadyExists rather than overwrite, so concurrent first starts agree on a key
+fn create_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let temp = write_temp(path, contents)?;
+    let result = fs::hard_link(&temp, path);
+    let _ = fs::remove_file(&temp);
+    result
+}
+
+pub fn replace_file(path: &Path, contents: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let temp = write_temp(path, contents)?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
@@ -1,32 +1,82 @@
 use std::sync::Arc;
-use tokio::sync::RwLock;
 use tracing::{info, error, warn};
-use tracing_subscriber;
 
 mod config;
 mod discord;
 mod events;
 mod security;
+mod keystore;
 mod storage;
 mod system;
 mod commands;
+mod server;
+mod tls;
+// The agent only uses the relay client; the server half is the relay binary's
+#[allow(dead_code)]
+mod relay;
+mod signing;
+mod jwt;
+mod replay;
+mod process_guard;
+mod reload;
+mod identity;
+mod sessions;
+mod auth_log;
+mod brute_force;
+mod outbox;
+mod notifier;
+mod sinks;
+mod routing;
+mod dedup;
+mod health;
+mod network;
+mod control;
+mod cli;
+
+#[cfg(test)]
+#[path = "../../tests/unit_tests.rs"]
+mod unit_tests;
 
 use config::Config;
-use discord::DiscordClient;
+use discord::{DiscordClient, DiscordEvent, EventType};
 use events::EventMonitor;
 use security::SecurityManager;
 use storage::SecureStorage;
 use system::SystemManager;
 use commands::CommandExecutor;
+use process_guard::ProcessGuard;
+use reload::ConfigReloader;
+use identity::DeviceIdentity;
+use outbox::Outbox;
+use notifier::{NotificationHub, Notifier};
+use dedup::DedupNotifier;
+use control::{ControlServer, ControlState};
+use cli::Command;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = cli::parse(std::env::args().skip(1))?;
+    if let Some(config_dir) = cli.config_dir {
+        Config::set_config_dir(config_dir);
+    }
+
+    match cli.command {
+        Command::Run => run(cli.overrides).await,
+        command => {
+            // Keep stdout for the command's own output
+            tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+            cli::execute(command, &cli.overrides).await
+        }
+    }
+}
+
+async fn run(overrides: Vec<(String, String)>) -> Result<(), Box<dyn std::error::Error>> {
     // Initialize logging
     tracing_subscriber::fmt::init();
     info!("Device Notifier Agent starting...");
 
     // Load configuration
-    let config = Config::load()?;
+    let config = Config::load_with_overrides(&overrides)?;
     info!("Configuration loaded successfully");
 
     // Check for emergency disable
@@ -35,33 +85,84 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
+    // Every subsystem shares this handle so reloads apply everywhere at once
+    let config = config.into_shared();
+
     // Initialize secure storage
-    let storage = Arc::new(SecureStorage::new()?);
+    let mut storage = SecureStorage::new()?;
+    storage.initialize(config.clone(), SecurityManager::new(config.clone())?).await?;
+    let storage = Arc::new(storage);
     info!("Secure storage initialized");
 
     // Initialize security manager
-    let security = Arc::new(SecurityManager::new(&config)?);
+    let security = Arc::new(SecurityManager::new(config.clone())?);
     info!("Security manager initialized");
 
+    // Load or create the persistent device identity
+    let identity = Arc::new(DeviceIdentity::load_or_create(&storage).await?);
+    info!("Device id {} (fingerprint {})", identity.device_id(), identity.fingerprint());
+
+    // Events are queued in storage and delivered in order once Discord is reachable
+    let outbox = Arc::new(Outbox::new(storage.clone()));
+    if let Err(e) = outbox.load().await {
+        warn!("Undelivered events from the previous run could not be restored: {}", e);
+    }
+
     // Initialize Discord client
-    let discord = Arc::new(DiscordClient::new(&config)?);
+    let discord = Arc::new(DiscordClient::new(config.clone(), identity.clone(), outbox.clone())?);
     info!("Discord client initialized");
 
+    // Discord plus any sinks configured under [notifiers], behind deduplication and
+    // the low-severity digest
+    let hub: Arc<dyn Notifier> = Arc::new(NotificationHub::new(config.clone(), discord.clone()));
+    let dedup = Arc::new(DedupNotifier::new(config.clone(), hub));
+    let notifier: Arc<dyn Notifier> = dedup.clone();
+
+    let digest_handle = tokio::spawn({
+        let dedup = dedup.clone();
+        async move {
+            dedup.run().await;
+        }
+    });
+
+    let outbox_handle = tokio::spawn({
+        let discord = discord.clone();
+        let outbox = outbox.clone();
+        async move {
+            outbox.run(discord).await;
+        }
+    });
+
     // Initialize system manager
     let system = Arc::new(SystemManager::new()?);
     info!("System manager initialized");
 
+    // Initialize process guard
+    let process_guard = Arc::new(ProcessGuard::new(
+        config.clone(),
+        system.clone(),
+        storage.clone(),
+        notifier.clone(),
+        identity.clone(),
+    ));
+    info!("Process guard initialized");
+
     // Initialize command executor
     let executor = Arc::new(CommandExecutor::new(
         system.clone(),
         security.clone(),
         storage.clone(),
+        process_guard.clone(),
+        notifier.clone(),
+        identity.clone(),
+        config.clone(),
     ));
     info!("Command executor initialized");
 
     // Initialize event monitor
     let event_monitor = Arc::new(EventMonitor::new(
-        discord.clone(),
+        notifier.clone(),
+        identity.clone(),
         storage.clone(),
         config.clone(),
     ));
@@ -88,30 +189,109 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     });
 
+    // Reload configuration when config.toml changes or on SIGHUP
+    let reloader = Arc::new(ConfigReloader::new(
+        config.clone(),
+        Config::get_config_dir()?.join("config.toml"),
+        overrides,
+    ));
+    let reload_handle = tokio::spawn({
+        let reloader = reloader.clone();
+        async move {
+            if let Err(e) = reloader.run().await {
+                error!("Configuration reloader failed: {}", e);
+            }
+        }
+    });
+
+    // Start enforcing application rules
+    let guard_handle = tokio::spawn({
+        let process_guard = process_guard.clone();
+        let config_updates = reloader.subscribe();
+        async move {
+            process_guard.run(config_updates).await;
+        }
+    });
+
     // Start heartbeat
     let heartbeat_handle = tokio::spawn({
-        let discord = discord.clone();
+        let notifier = notifier.clone();
+        let identity = identity.clone();
         let config = config.clone();
         async move {
             loop {
                 tokio::time::sleep(tokio::time::Duration::from_secs(300)).await; // 5 minutes
-                if let Err(e) = discord.send_heartbeat().await {
-                    warn!("Failed to send heartbeat: {}", e);
+
+                let heartbeat = {
+                    let config = config.read().await;
+                    config.features.heartbeat_enabled.then(|| {
+                        DiscordEvent::new(&config, &identity, EventType::Heartbeat, None, Some("System heartbeat".to_string()))
+                    })
+                };
+
+                if let Some(heartbeat) = heartbeat {
+                    if let Err(e) = notifier.notify(&heartbeat).await.map_err(|e| e.to_string()) {
+                        warn!("Failed to send heartbeat: {}", e);
+                    }
                 }
             }
         }
     });
 
+    // Local socket for the device-notifier CLI
+    let shutdown = Arc::new(tokio::sync::Notify::new());
+    let control_state = Arc::new(ControlState {
+        config: config.clone(),
+        storage: storage.clone(),
+        identity: identity.clone(),
+        executor: executor.clone(),
+        notifier: notifier.clone(),
+        outbox: outbox.clone(),
+        reloader: reloader.clone(),
+        started_at: chrono::Utc::now(),
+        shutdown: shutdown.clone(),
+    });
+    let control_path = control::socket_path()?;
+    let control_handle = match ControlServer::bind(&control_path, control_state).await {
+        Ok(server) => Some(tokio::spawn(server.serve())),
+        Err(e) => {
+            warn!("Control socket unavailable, the CLI cannot reach this agent: {}", e);
+            None
+        }
+    };
+
     info!("Agent started successfully. Waiting for events...");
 
-    // Wait for shutdown signal
-    tokio::signal::ctrl_c().await?;
-    info!("Shutdown signal received, stopping agent...");
+    // Wait for shutdown signal, or an emergency disable from the CLI
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            info!("Shutdown signal received, stopping agent...");
+        }
+        _ = shutdown.notified() => warn!("Emergency disable requested, stopping agent..."),
+    }
 
     // Graceful shutdown
+    if let Err(e) = event_monitor.stop().await {
+        warn!("Failed to stop event monitoring: {}", e);
+    }
     event_handle.abort();
     command_handle.abort();
     heartbeat_handle.abort();
+    guard_handle.abort();
+    reload_handle.abort();
+    outbox_handle.abort();
+    digest_handle.abort();
+    if let Some(control_handle) = control_handle {
+        control_handle.abort();
+        #[cfg(unix)]
+        let _ = std::fs::remove_file(&control_path);
+    }
+
+    // Don't lose events held back for the next digest
+    if let Err(e) = dedup.flush_digest().await {
+        warn!("Failed to send the pending event digest: {}", e);
+    }
 
     info!("Agent stopped successfully");
     Ok(())
@@ -0,0 +1,405 @@
+use crate::config::{NetworkConfig, ProbeConfig, ProbeKind};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::process::Command;
+
+// Wi-Fi networks this device has joined before, so only new ones are flagged
+pub const KNOWN_SSIDS_KEY: &str = "known_ssids";
+
+// Routers rarely listen on a fixed port, so the gateway probe connects to the DNS port
+// and counts a refused connection as reachable: something answered. Many routers drop
+// the connection silently instead, so when it times out the gateway still counts as
+// reachable if the neighbor (ARP) table holds its hardware address, which the attempt
+// itself makes the kernel revalidate.
+const GATEWAY_PROBE_PORT: u16 = 53;
+
+// Why a probe or lookup gave no answer. Failed means the network did not respond;
+// Unavailable means the check itself could not run (a missing `route` or `arp` binary,
+// an unreadable /proc file), which says nothing about the network.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProbeError {
+    Failed(String),
+    Unavailable(String),
+}
+
+impl std::fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProbeError::Failed(reason) | ProbeError::Unavailable(reason) => f.write_str(reason),
+        }
+    }
+}
+
+pub fn probe_label(probe: &ProbeConfig) -> String {
+    match &probe.target {
+        Some(target) => format!("{} {}", probe.kind.as_str(), target),
+        None => probe.kind.as_str().to_string(),
+    }
+}
+
+pub fn check(config: &NetworkConfig) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    if config.enabled && (config.interval_seconds == 0 || config.timeout_seconds == 0) {
+        errors.push("network: interval_seconds and timeout_seconds must be greater than zero".to_string());
+    }
+
+    for (index, probe) in config.probes.iter().enumerate() {
+        let problem = match (&probe.kind, probe.target.as_deref()) {
+            (ProbeKind::Gateway, Some(_)) => Some("gateway probes take no target".to_string()),
+            (ProbeKind::Gateway, None) => None,
+            (_, None) => Some(format!("{} probes require a target", probe.kind.as_str())),
+            (ProbeKind::Tcp, Some(target)) => match target.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => None,
+                _ => Some(format!("invalid target {}, expected host:port", target)),
+            },
+            (ProbeKind::Dns, Some(_)) => None,
+            (ProbeKind::Http, Some(target)) => match reqwest::Url::parse(target) {
+                Ok(url) if url.scheme() == "http" || url.scheme() == "https" => None,
+                _ => Some(format!("invalid url {}", target)),
+            },
+        };
+
+        if let Some(problem) = problem {
+            errors.push(format!("network.probes[{}]: {}", index, problem));
+        }
+    }
+
+    errors
+}
+
+// Without configured probes only the default gateway is checked, which needs no
+// traffic beyond the local network
+pub fn probes(config: &NetworkConfig) -> Vec<ProbeConfig> {
+    if config.probes.is_empty() {
+        return vec![ProbeConfig { kind: ProbeKind::Gateway, target: None }];
+    }
+
+    config.probes.clone()
+}
+
+pub async fn run_probe(probe: &ProbeConfig, timeout: Duration) -> Result<(), ProbeError> {
+    let target = probe.target.clone().unwrap_or_default();
+    let failed = |e: &dyn std::fmt::Display| ProbeError::Failed(e.to_string());
+
+    let probe = async {
+        match probe.kind {
+            ProbeKind::Tcp => TcpStream::connect(&target).await.map(|_| ()).map_err(|e| failed(&e)),
+            ProbeKind::Dns => {
+                let mut addresses = tokio::net::lookup_host((target.as_str(), 0)).await.map_err(|e| failed(&e))?;
+                match addresses.next() {
+                    Some(_) => Ok(()),
+                    None => Err(ProbeError::Failed(format!("{} has no addresses", target))),
+                }
+            }
+            ProbeKind::Http => {
+                let response = reqwest::Client::new().get(&target).send().await.map_err(|e| failed(&e))?;
+                if !response.status().is_success() {
+                    return Err(ProbeError::Failed(format!("HTTP {}", response.status())));
+                }
+                Ok(())
+            }
+            ProbeKind::Gateway => {
+                let gateway = default_gateway().await?;
+                // Half the timeout, leaving the rest for the neighbor table lookup
+                let error = match tokio::time::timeout(timeout / 2, TcpStream::connect((gateway, GATEWAY_PROBE_PORT))).await {
+                    Ok(Ok(_)) => return Ok(()),
+                    Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => return Ok(()),
+                    Ok(Err(e)) => e.to_string(),
+                    Err(_) => "timed out".to_string(),
+                };
+
+                if has_neighbor_entry(gateway).await {
+                    return Ok(());
+                }
+                Err(ProbeError::Failed(format!("gateway {} unreachable: {}", gateway, error)))
+            }
+        }
+    };
+
+    tokio::time::timeout(timeout, probe).await.map_err(|_| ProbeError::Failed("timed out".to_string()))?
+}
+
+pub async fn default_gateway() -> Result<IpAddr, ProbeError> {
+    let no_route = || ProbeError::Failed("no default route".to_string());
+
+    #[cfg(target_os = "linux")]
+    {
+        let routes = tokio::fs::read_to_string("/proc/net/route").await
+            .map_err(|e| ProbeError::Unavailable(format!("/proc/net/route: {}", e)))?;
+        parse_proc_net_route(&routes).map(IpAddr::V4).ok_or_else(no_route)
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        let output = command_output("route", &["-n", "get", "default"]).await?;
+        parse_route_get(&output).ok_or_else(no_route)
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        let output = command_output("route", &["print", "-4", "0.0.0.0"]).await?;
+        parse_route_print(&output).ok_or_else(no_route)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    {
+        let _ = no_route;
+        Err(ProbeError::Unavailable("default gateway lookup is not supported on this platform".to_string()))
+    }
+}
+
+// /proc/net/route lists the gateway as little-endian hex next to a 00000000 destination
+pub fn parse_proc_net_route(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            return None;
+        }
+
+        let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+        (gateway != 0).then(|| Ipv4Addr::from(gateway.swap_bytes()))
+    })
+}
+
+// `route -n get default` on macOS
+#[cfg_attr(not(any(test, target_os = "macos")), allow(dead_code))]
+pub fn parse_route_get(output: &str) -> Option<IpAddr> {
+    output.lines()
+        .find_map(|line| line.trim().strip_prefix("gateway:"))
+        .and_then(|gateway| gateway.trim().parse().ok())
+}
+
+// `route print -4 0.0.0.0` on Windows: destination, netmask, gateway, interface, metric
+#[cfg_attr(not(any(test, target_os = "windows")), allow(dead_code))]
+pub fn parse_route_print(output: &str) -> Option<IpAddr> {
+    output.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            ["0.0.0.0", "0.0.0.0", gateway, ..] => gateway.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+async fn has_neighbor_entry(gateway: IpAddr) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        match tokio::fs::read_to_string("/proc/net/arp").await {
+            Ok(table) => parse_proc_net_arp(&table, gateway),
+            Err(_) => false,
+        }
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "windows"))]
+    {
+        let address = gateway.to_string();
+        #[cfg(target_os = "macos")]
+        let args = ["-n", address.as_str()];
+        #[cfg(target_os = "windows")]
+        let args = ["-a", address.as_str()];
+
+        match command_output("arp", &args).await {
+            Ok(output) => parse_arp_output(&output, gateway),
+            Err(_) => false,
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    {
+        let _ = gateway;
+        false
+    }
+}
+
+// /proc/net/arp: IP address, HW type, flags, HW address, mask, device. Flag 0x2 marks
+// a resolved entry; failed lookups keep a zero hardware address.
+pub fn parse_proc_net_arp(table: &str, address: IpAddr) -> bool {
+    table.lines().skip(1).any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [ip, _, flags, hw_address, ..] => {
+                ip.parse::<IpAddr>().ok() == Some(address)
+                    && u32::from_str_radix(flags.trim_start_matches("0x"), 16).is_ok_and(|flags| flags & 0x2 != 0)
+                    && *hw_address != "00:00:00:00:00:00"
+            }
+            _ => false,
+        }
+    })
+}
+
+// `arp -n <ip>` on macOS ("? (192.168.1.1) at 0:11:22:33:44:55 on en0") or `arp -a <ip>`
+// on Windows ("192.168.1.1  00-11-22-33-44-55  dynamic"). Unresolved entries show
+// "(incomplete)" or no line at all.
+#[cfg_attr(not(any(test, target_os = "macos", target_os = "windows")), allow(dead_code))]
+pub fn parse_arp_output(output: &str, address: IpAddr) -> bool {
+    let ip = address.to_string();
+    let bracketed = format!("({})", ip);
+
+    output.lines().any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [_, host, "at", hw_address, ..] => *host == bracketed && *hw_address != "(incomplete)",
+            [host, hw_address, ..] => *host == ip && hw_address.split('-').count() == 6,
+            _ => false,
+        }
+    })
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetworkState {
+    // Addresses per interface, without loopback and link-local ones
+    pub addresses: BTreeMap<String, Vec<IpAddr>>,
+    pub ssid: Option<String>,
+}
+
+pub fn is_vpn_interface(name: &str) -> bool {
+    let name = name.to_lowercase();
+    ["tun", "tap", "wg", "utun", "ppp", "ipsec", "tailscale", "zt"].iter().any(|prefix| name.starts_with(prefix))
+        || name.contains("vpn")
+        || name.contains("wireguard")
+}
+
+// An SSID that cannot be looked up is taken from previous rather than reported as a
+// network left or joined
+pub async fn current_state(previous: Option<&NetworkState>) -> Result<NetworkState, Box<dyn std::error::Error>> {
+    let mut addresses: BTreeMap<String, Vec<IpAddr>> = BTreeMap::new();
+
+    for interface in if_addrs::get_if_addrs()? {
+        if interface.is_loopback() || interface.is_link_local() {
+            continue;
+        }
+        addresses.entry(interface.name.clone()).or_default().push(interface.ip());
+    }
+    for ips in addresses.values_mut() {
+        ips.sort();
+    }
+
+    let ssid = match current_ssid().await {
+        Ok(ssid) => ssid,
+        Err(e) => {
+            tracing::warn!("Wi-Fi network lookup failed, assuming it is unchanged: {}", e);
+            previous.and_then(|previous| previous.ssid.clone())
+        }
+    };
+
+    Ok(NetworkState { addresses, ssid })
+}
+
+// Commands that run and report no network mean Wi-Fi is off or not connected; only a
+// lookup that could not run at all is an error
+async fn current_ssid() -> Result<Option<String>, ProbeError> {
+    #[cfg(target_os = "linux")]
+    {
+        // iwgetid exits non-zero when not connected
+        let iwgetid = command_output("iwgetid", &["-r"]).await;
+        if let Ok(output) = &iwgetid {
+            let ssid = output.trim();
+            if !ssid.is_empty() {
+                return Ok(Some(ssid.to_string()));
+            }
+        }
+        match command_output("nmcli", &["-t", "-f", "active,ssid", "dev", "wifi"]).await {
+            Ok(output) => Ok(parse_nmcli_ssid(&output)),
+            Err(ProbeError::Unavailable(e)) if matches!(iwgetid, Err(ProbeError::Unavailable(_))) => Err(ProbeError::Unavailable(e)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        let output = command_output("networksetup", &["-getairportnetwork", "en0"]).await?;
+        Ok(output.trim().strip_prefix("Current Wi-Fi Network: ").map(str::to_string))
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        // netsh fails when the WLAN service is not running, i.e. there is no Wi-Fi
+        match command_output("netsh", &["wlan", "show", "interfaces"]).await {
+            Ok(output) => Ok(parse_netsh_ssid(&output)),
+            Err(ProbeError::Failed(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    {
+        Ok(None)
+    }
+}
+
+// `nmcli -t -f active,ssid dev wifi` prints one "active:ssid" line per visible network
+pub fn parse_nmcli_ssid(output: &str) -> Option<String> {
+    output.lines()
+        .find_map(|line| line.strip_prefix("yes:"))
+        .filter(|ssid| !ssid.is_empty())
+        .map(|ssid| ssid.replace("\\:", ":"))
+}
+
+// `netsh wlan show interfaces`, where the "SSID" line must not be confused with "BSSID"
+#[cfg_attr(not(any(test, target_os = "windows")), allow(dead_code))]
+pub fn parse_netsh_ssid(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        (key.trim() == "SSID" && !value.trim().is_empty()).then(|| value.trim().to_string())
+    })
+}
+
+// A program that cannot be started or does not finish is Unavailable; one that exits
+// with an error has answered, so that is Failed
+pub async fn command_output(program: &str, args: &[&str]) -> Result<String, ProbeError> {
+    let output = tokio::time::timeout(Duration::from_secs(5), Command::new(program).args(args).output())
+        .await
+        .map_err(|_| ProbeError::Unavailable(format!("{} timed out", program)))?
+        .map_err(|e| ProbeError::Unavailable(format!("{}: {}", program, e)))?;
+
+    if !output.status.success() {
+        return Err(ProbeError::Failed(format!("{} exited with {}", program, output.status)));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+fn format_addresses(addresses: &[IpAddr]) -> String {
+    addresses.iter().map(IpAddr::to_string).collect::<Vec<_>>().join(", ")
+}
+
+// Describes what changed between two samples, one line per change. known_ssids holds
+// the Wi-Fi networks joined before; joining any other is called out.
+pub fn describe_changes(previous: &NetworkState, current: &NetworkState, known_ssids: &[String]) -> Vec<String> {
+    let mut changes = Vec::new();
+
+    for (name, addresses) in &current.addresses {
+        let kind = if is_vpn_interface(name) { "VPN interface" } else { "Interface" };
+        match previous.addresses.get(name) {
+            None if is_vpn_interface(name) => changes.push(format!("VPN interface {} up ({})", name, format_addresses(addresses))),
+            None => changes.push(format!("Interface {} connected ({})", name, format_addresses(addresses))),
+            Some(before) if before != addresses => changes.push(format!(
+                "{} {} address changed from {} to {}",
+                kind, name, format_addresses(before), format_addresses(addresses)
+            )),
+            Some(_) => {}
+        }
+    }
+
+    for name in previous.addresses.keys().filter(|name| !current.addresses.contains_key(*name)) {
+        if is_vpn_interface(name) {
+            changes.push(format!("VPN interface {} down", name));
+        } else {
+            changes.push(format!("Interface {} disconnected", name));
+        }
+    }
+
+    if previous.ssid != current.ssid {
+        match (&previous.ssid, &current.ssid) {
+            (_, Some(ssid)) if !known_ssids.contains(ssid) => changes.push(format!("Joined new Wi-Fi network {}", ssid)),
+            (_, Some(ssid)) => changes.push(format!("Joined Wi-Fi network {}", ssid)),
+            (Some(ssid), None) => changes.push(format!("Left Wi-Fi network {}", ssid)),
+            (None, None) => {}
+        }
+    }
+
+    changes
+}
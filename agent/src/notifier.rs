@@ -0,0 +1,140 @@
+use crate::config::{NotifierConfig, SharedConfig};
+use crate::discord::{DiscordClient, DiscordEvent};
+use crate::routing;
+use crate::sinks;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{warn, debug};
+
+// A destination for events. Callers decide which events are worth sending (consent,
+// feature flags); which destinations receive them is up to routing.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    fn name(&self) -> &str;
+    async fn notify(&self, event: &DiscordEvent) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+// Plain text body for sinks without a richer format; the title goes separately
+pub fn render_text(event: &DiscordEvent) -> String {
+    let mut lines = vec![format!("Device: {}", event.device_alias)];
+
+    if let Some(user) = &event.user_local {
+        lines.push(format!("User: {}", user));
+    }
+    lines.push(format!("Time: {}", event.timestamp.format("%Y-%m-%d %H:%M:%S UTC")));
+    if let Some(notes) = &event.notes {
+        lines.push(notes.clone());
+    }
+
+    lines.join("\n")
+}
+
+struct SinkCache {
+    built_from: HashMap<String, NotifierConfig>,
+    sinks: Vec<Arc<dyn Notifier>>,
+}
+
+// Fans each event out to the destinations routing picks among Discord and the sinks
+// under [notifiers]. The sinks are rebuilt whenever that section changes, so reloads
+// add and remove them live.
+pub struct NotificationHub {
+    config: SharedConfig,
+    discord: Arc<DiscordClient>,
+    sinks: RwLock<Option<SinkCache>>,
+}
+
+impl NotificationHub {
+    pub fn new(config: SharedConfig, discord: Arc<DiscordClient>) -> Self {
+        Self {
+            config,
+            discord,
+            sinks: RwLock::new(None),
+        }
+    }
+
+    async fn sinks(&self, notifiers: HashMap<String, NotifierConfig>) -> Vec<Arc<dyn Notifier>> {
+        let mut sinks: Vec<Arc<dyn Notifier>> = vec![self.discord.clone()];
+
+        let mut cache = self.sinks.write().await;
+        match cache.as_ref() {
+            Some(cached) if cached.built_from == notifiers => sinks.extend(cached.sinks.iter().cloned()),
+            _ => {
+                let built = Self::build_sinks(&notifiers);
+                sinks.extend(built.iter().cloned());
+                *cache = Some(SinkCache { built_from: notifiers, sinks: built });
+            }
+        }
+
+        sinks
+    }
+
+    fn build_sinks(notifiers: &HashMap<String, NotifierConfig>) -> Vec<Arc<dyn Notifier>> {
+        let mut names: Vec<&String> = notifiers.keys().collect();
+        names.sort();
+
+        let mut sinks = Vec::new();
+        for name in names {
+            let settings = &notifiers[name];
+            if !settings.enabled {
+                continue;
+            }
+
+            match sinks::build(name, settings) {
+                Ok(notifier) => sinks.push(notifier),
+                Err(e) => warn!("Notifier {} disabled: {}", name, e),
+            }
+        }
+
+        sinks
+    }
+}
+
+#[async_trait]
+impl Notifier for NotificationHub {
+    fn name(&self) -> &str {
+        "hub"
+    }
+
+    // Sinks are notified concurrently so a slow one doesn't hold up the others;
+    // the error lists every sink that failed
+    async fn notify(&self, event: &DiscordEvent) -> Result<(), Box<dyn std::error::Error>> {
+        let (route, notifiers) = {
+            let config = self.config.read().await;
+            (routing::route(&config, event), config.notifiers.clone())
+        };
+
+        if route.destinations.is_empty() {
+            debug!("{:?} event has no destinations, dropping it", event.event_type);
+            return Ok(());
+        }
+
+        let deliveries: Vec<_> = self.sinks(notifiers).await
+            .into_iter()
+            .filter(|sink| route.destinations.iter().any(|destination| destination == sink.name()))
+            .map(|sink| {
+                let event = event.clone();
+                tokio::spawn(async move {
+                    let result = sink.notify(&event).await.map_err(|e| e.to_string());
+                    (sink.name().to_string(), result)
+                })
+            })
+            .collect();
+
+        let mut failures = Vec::new();
+        for delivery in deliveries {
+            match delivery.await {
+                Ok((name, Ok(()))) => debug!("Notified {} of {:?} event", name, event.event_type),
+                Ok((name, Err(e))) => failures.push(format!("{}: {}", name, e)),
+                Err(e) => failures.push(e.to_string()),
+            }
+        }
+
+        if !failures.is_empty() {
+            return Err(failures.join("; ").into());
+        }
+
+        Ok(())
+    }
+}
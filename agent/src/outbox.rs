@@ -0,0 +1,199 @@
+use crate::discord::{Delivery, DiscordClient, DiscordEvent};
+use crate::storage::SecureStorage;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use tracing::{info, warn, error, debug};
+
+const STORAGE_KEY: &str = "outbox";
+const MAX_QUEUED_EVENTS: usize = 1000;
+const INITIAL_BACKOFF_SECONDS: u64 = 2;
+const MAX_BACKOFF_SECONDS: u64 = 300;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedEvent {
+    pub id: String,
+    pub event: DiscordEvent,
+    pub enqueued_at: DateTime<Utc>,
+    pub attempts: u32,
+    pub next_attempt_at: DateTime<Utc>,
+}
+
+// Every Discord event goes through this queue, which is kept encrypted in SecureStorage
+// so events raised while offline survive restarts. Events are delivered strictly in
+// order; a failing event holds back the ones behind it until it is delivered.
+pub struct Outbox {
+    storage: Arc<SecureStorage>,
+    queue: Mutex<VecDeque<QueuedEvent>>,
+    wake: Notify,
+}
+
+impl Outbox {
+    pub fn new(storage: Arc<SecureStorage>) -> Self {
+        Self {
+            storage,
+            queue: Mutex::new(VecDeque::new()),
+            wake: Notify::new(),
+        }
+    }
+
+    // Restores events left over from the previous run
+    pub async fn load(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        let stored: VecDeque<QueuedEvent> = match self.storage.retrieve_encrypted_data(STORAGE_KEY).await? {
+            Some(data) => serde_json::from_slice(&data)?,
+            None => return Ok(0),
+        };
+
+        let mut queue = self.queue.lock().await;
+        let restored = stored.len();
+
+        // Anything enqueued before load() runs is newer than the stored events
+        let newer = std::mem::replace(&mut *queue, stored);
+        queue.extend(newer);
+        drop(queue);
+
+        if restored > 0 {
+            info!("Restored {} undelivered events", restored);
+            self.wake.notify_one();
+        }
+
+        Ok(restored)
+    }
+
+    pub async fn enqueue(&self, event: DiscordEvent) -> Result<(), Box<dyn std::error::Error>> {
+        let mut queue = self.queue.lock().await;
+
+        if queue.len() >= MAX_QUEUED_EVENTS {
+            if let Some(dropped) = queue.pop_front() {
+                warn!("Outbox full, dropping oldest event {:?} from {}", dropped.event.event_type, dropped.enqueued_at);
+            }
+        }
+
+        let now = Utc::now();
+        queue.push_back(QueuedEvent {
+            id: uuid::Uuid::new_v4().to_string(),
+            event,
+            enqueued_at: now,
+            attempts: 0,
+            next_attempt_at: now,
+        });
+
+        self.persist(&queue).await?;
+        drop(queue);
+
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    pub async fn len(&self) -> usize {
+        self.queue.lock().await.len()
+    }
+
+    #[allow(dead_code)]
+    pub async fn pending(&self) -> Vec<QueuedEvent> {
+        self.queue.lock().await.iter().cloned().collect()
+    }
+
+    pub async fn run(&self, discord: Arc<DiscordClient>) {
+        loop {
+            match self.flush(&discord).await.map_err(|e| e.to_string()) {
+                Ok(None) => self.wake.notified().await,
+                Ok(Some(wait)) => {
+                    // A new event can't jump the queue, but waking re-checks the head
+                    tokio::select! {
+                        _ = tokio::time::sleep(wait) => {}
+                        _ = self.wake.notified() => {}
+                    }
+                }
+                Err(e) => {
+                    error!("Outbox error: {}", e);
+                    tokio::time::sleep(Duration::from_secs(INITIAL_BACKOFF_SECONDS)).await;
+                }
+            }
+        }
+    }
+
+    // Delivers queued events in order until the queue is empty (None) or the head has
+    // to wait (Some(delay)) because of a failure or a rate limit
+    pub async fn flush(&self, discord: &DiscordClient) -> Result<Option<Duration>, Box<dyn std::error::Error>> {
+        loop {
+            let head = match self.queue.lock().await.front().cloned() {
+                Some(head) => head,
+                None => return Ok(None),
+            };
+
+            let now = Utc::now();
+            if head.next_attempt_at > now {
+                return Ok(Some((head.next_attempt_at - now).to_std().unwrap_or_default()));
+            }
+
+            let delivery = discord.deliver(&head.event).await.map_err(|e| e.to_string());
+
+            match delivery {
+                Ok(Delivery::Sent { reset_after }) => {
+                    self.remove(&head.id).await?;
+                    debug!("Delivered {:?} after {} attempts", head.event.event_type, head.attempts + 1);
+
+                    // The bucket is empty; wait for it to refill rather than earn a 429
+                    if let Some(reset_after) = reset_after {
+                        return Ok(Some(reset_after));
+                    }
+                }
+                Ok(Delivery::RateLimited(retry_after)) => {
+                    warn!("Discord rate limit hit, retrying in {:.1}s", retry_after.as_secs_f64());
+                    self.reschedule(&head.id, retry_after, false).await?;
+                    return Ok(Some(retry_after));
+                }
+                Ok(Delivery::Rejected(status)) => {
+                    // Retrying a request Discord refuses would block the queue forever
+                    error!("Discord rejected {:?} event with {}, dropping it", head.event.event_type, status);
+                    self.remove(&head.id).await?;
+                }
+                Ok(Delivery::Skipped) => {
+                    debug!("Discord delivery disabled, dropping queued {:?} event", head.event.event_type);
+                    self.remove(&head.id).await?;
+                }
+                Err(e) => {
+                    let backoff = backoff(head.attempts + 1);
+                    warn!("Failed to deliver {:?} event (attempt {}), retrying in {}s: {}",
+                        head.event.event_type, head.attempts + 1, backoff.as_secs(), e);
+                    self.reschedule(&head.id, backoff, true).await?;
+                    return Ok(Some(backoff));
+                }
+            }
+        }
+    }
+
+    async fn remove(&self, id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut queue = self.queue.lock().await;
+        queue.retain(|queued| queued.id != id);
+        self.persist(&queue).await
+    }
+
+    async fn reschedule(&self, id: &str, delay: Duration, failed: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut queue = self.queue.lock().await;
+
+        if let Some(queued) = queue.iter_mut().find(|queued| queued.id == id) {
+            if failed {
+                queued.attempts += 1;
+            }
+            queued.next_attempt_at = Utc::now() + chrono::Duration::from_std(delay)?;
+        }
+
+        self.persist(&queue).await
+    }
+
+    async fn persist(&self, queue: &VecDeque<QueuedEvent>) -> Result<(), Box<dyn std::error::Error>> {
+        let data = serde_json::to_vec(queue)?;
+        self.storage.store_encrypted_data(STORAGE_KEY, &data).await
+    }
+}
+
+// 2s, 4s, 8s, ... capped at five minutes
+pub fn backoff(attempts: u32) -> Duration {
+    let seconds = INITIAL_BACKOFF_SECONDS.saturating_mul(1u64 << attempts.saturating_sub(1).min(16));
+    Duration::from_secs(seconds.min(MAX_BACKOFF_SECONDS))
+}
@@ -0,0 +1,502 @@
+use crate::config::{AppRule, BruteForceConfig, SharedConfig};
+use crate::discord::{DiscordEvent, EventType};
+use crate::identity::DeviceIdentity;
+use crate::notifier::Notifier;
+use crate::storage::{SecureStorage, LOCAL_PASSWORD};
+use crate::system::{ProcessInfo, SystemManager};
+use chrono::{DateTime, Utc};
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex, RwLock};
+use tracing::{info, warn, error, debug};
+
+// Watches process launches and enforces Config.app_rules: blocked apps are killed,
+// password-gated apps are suspended until a permitted approval arrives.
+pub struct ProcessGuard {
+    config: SharedConfig,
+    system: Arc<SystemManager>,
+    storage: Arc<SecureStorage>,
+    notifier: Arc<dyn Notifier>,
+    identity: Arc<DeviceIdentity>,
+    matchers: RwLock<Option<Arc<Vec<AppMatcher>>>>,
+    known_pids: RwLock<Option<HashSet<u32>>>,
+    pending: RwLock<HashMap<u32, PendingLaunch>>,
+    local_attempts: Mutex<LocalAttempts>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingLaunch {
+    pub pid: u32,
+    pub app: String,
+    pub process_name: String,
+    pub requires_local_password: bool,
+    pub requires_remote_password: bool,
+    pub suspended_at: DateTime<Utc>,
+}
+
+pub enum Approval {
+    LocalPassword(String),
+    Remote { issuer: String, command_id: String },
+}
+
+// Failed local password approvals, counted across launches since each new process would
+// otherwise come with a fresh set of guesses. Uses the security.brute_force window:
+// max_failures within window_seconds lock local approvals for cooldown_seconds.
+#[derive(Debug, Default)]
+struct LocalAttempts {
+    failures: VecDeque<DateTime<Utc>>,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+impl LocalAttempts {
+    fn locked(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.locked_until.filter(|until| now < *until)
+    }
+
+    // Returns the failures within the window and, if this one started a lockout, when it ends
+    fn fail(&mut self, now: DateTime<Utc>, settings: &BruteForceConfig) -> (usize, Option<DateTime<Utc>>) {
+        let window = chrono::Duration::seconds(settings.window_seconds as i64);
+        self.failures.push_back(now);
+        while self.failures.front().is_some_and(|oldest| now - *oldest > window) {
+            self.failures.pop_front();
+        }
+
+        let failures = self.failures.len();
+        if (failures as u32) < settings.max_failures {
+            return (failures, None);
+        }
+
+        self.failures.clear();
+        let until = now + chrono::Duration::seconds(settings.cooldown_seconds as i64);
+        self.locked_until = Some(until);
+        (failures, Some(until))
+    }
+}
+
+impl ProcessGuard {
+    pub fn new(
+        config: SharedConfig,
+        system: Arc<SystemManager>,
+        storage: Arc<SecureStorage>,
+        notifier: Arc<dyn Notifier>,
+        identity: Arc<DeviceIdentity>,
+    ) -> Self {
+        Self {
+            config,
+            system,
+            storage,
+            notifier,
+            identity,
+            matchers: RwLock::new(None),
+            known_pids: RwLock::new(None),
+            pending: RwLock::new(HashMap::new()),
+            local_attempts: Mutex::new(LocalAttempts::default()),
+        }
+    }
+
+    pub async fn run(&self, mut config_updates: watch::Receiver<u64>) {
+        info!("Process guard started");
+        let mut interval = tokio::time::interval(Duration::from_secs(2));
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                Ok(()) = config_updates.changed() => {
+                    // Rebuilt from the new app_rules on the next scan
+                    *self.matchers.write().await = None;
+                    continue;
+                }
+            }
+
+            if let Err(e) = self.scan().await.map_err(|e| e.to_string()) {
+                error!("Process guard scan failed: {}", e);
+            }
+
+            self.expire_pending().await;
+        }
+    }
+
+    pub async fn scan(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let processes = self.system.list_processes().await?;
+        let matchers = self.matchers().await;
+
+        let previous = {
+            let mut known_pids = self.known_pids.write().await;
+            known_pids.replace(processes.keys().copied().collect())
+        };
+
+        // Forget launches whose process has exited
+        self.pending.write().await.retain(|pid, _| processes.contains_key(pid));
+
+        if matchers.is_empty() {
+            return Ok(());
+        }
+
+        for process in processes.values() {
+            let is_new = previous.as_ref().map(|pids| !pids.contains(&process.pid)).unwrap_or(true);
+            if !is_new {
+                continue;
+            }
+
+            if let Some(matcher) = matchers.iter().find(|matcher| matcher.matches(process)) {
+                // Already-running apps are only checked against the block list on startup
+                if previous.is_none() && !matcher.rule.blocked {
+                    continue;
+                }
+
+                self.enforce(&matcher.name, &matcher.rule, process).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn matchers(&self) -> Arc<Vec<AppMatcher>> {
+        if let Some(matchers) = self.matchers.read().await.as_ref() {
+            return matchers.clone();
+        }
+
+        let config = self.config.read().await;
+        let mut names: Vec<&String> = config.app_rules.keys().collect();
+        names.sort();
+
+        // Config::validate has already rejected rules that fail to compile
+        let matchers: Vec<AppMatcher> = names.into_iter()
+            .filter_map(|name| match AppMatcher::new(name, &config.app_rules[name]) {
+                Ok(matcher) => Some(matcher),
+                Err(e) => {
+                    error!("Ignoring app rule {}: {}", name, e);
+                    None
+                }
+            })
+            .collect();
+        drop(config);
+
+        debug!("Compiled {} app rules", matchers.len());
+        let matchers = Arc::new(matchers);
+        *self.matchers.write().await = Some(matchers.clone());
+        matchers
+    }
+
+    async fn enforce(&self, app: &str, rule: &AppRule, process: &ProcessInfo) {
+        if rule.blocked {
+            self.block(app, rule, process, "blocked by policy").await;
+            return;
+        }
+
+        if !rule.requires_local_password && !rule.requires_remote_password {
+            return;
+        }
+
+        if let Err(e) = self.system.suspend_process(process.pid).await.map_err(|e| e.to_string()) {
+            warn!("Cannot suspend {} (pid {}): {}, terminating instead", app, process.pid, e);
+            self.block(app, rule, process, "approval required but the process could not be suspended").await;
+            return;
+        }
+
+        let launch = PendingLaunch {
+            pid: process.pid,
+            app: app.to_string(),
+            process_name: process.name.clone(),
+            requires_local_password: rule.requires_local_password,
+            requires_remote_password: rule.requires_remote_password,
+            suspended_at: Utc::now(),
+        };
+        self.pending.write().await.insert(process.pid, launch);
+
+        info!("Suspended {} (pid {}) pending approval", app, process.pid);
+
+        let message = rule.custom_message.clone()
+            .unwrap_or_else(|| format!("{} requires approval before it can run", app));
+        let approvals = match (rule.requires_local_password, rule.requires_remote_password) {
+            (true, true) => "local password or remote approval",
+            (true, false) => "local password",
+            _ => "remote approval",
+        };
+
+        self.audit("app_approval_required", serde_json::json!({
+            "app": app,
+            "pid": process.pid,
+            "process_name": process.name,
+            "exe": process.exe,
+            "required_approvals": approvals,
+        })).await;
+
+        self.notify(
+            EventType::AppApprovalRequired,
+            app,
+            &format!("{} (pid {}, awaiting {})", message, process.pid, approvals),
+        ).await;
+    }
+
+    async fn block(&self, app: &str, rule: &AppRule, process: &ProcessInfo, reason: &str) {
+        match self.system.kill_process(process.pid).await.map_err(|e| e.to_string()) {
+            Ok(()) => info!("Terminated {} (pid {}): {}", app, process.pid, reason),
+            Err(e) => warn!("Failed to terminate {} (pid {}): {}", app, process.pid, e),
+        }
+
+        let message = rule.custom_message.clone()
+            .unwrap_or_else(|| format!("Launch of {} was blocked", app));
+
+        self.audit("app_blocked", serde_json::json!({
+            "app": app,
+            "pid": process.pid,
+            "process_name": process.name,
+            "exe": process.exe,
+            "reason": reason,
+        })).await;
+
+        self.notify(EventType::AppBlocked, app, &format!("{} (pid {}, {})", message, process.pid, reason)).await;
+    }
+
+    async fn expire_pending(&self) {
+        let timeout = chrono::Duration::seconds(self.config.read().await.security.app_approval_timeout_seconds as i64);
+        let now = Utc::now();
+
+        let expired: Vec<PendingLaunch> = {
+            let mut pending = self.pending.write().await;
+            let expired_pids: Vec<u32> = pending.values()
+                .filter(|launch| now - launch.suspended_at > timeout)
+                .map(|launch| launch.pid)
+                .collect();
+
+            expired_pids.iter().filter_map(|pid| pending.remove(pid)).collect()
+        };
+
+        for launch in expired {
+            if let Err(e) = self.system.kill_process(launch.pid).await.map_err(|e| e.to_string()) {
+                debug!("Failed to terminate expired launch {}: {}", launch.pid, e);
+            }
+
+            warn!("Approval for {} (pid {}) timed out", launch.app, launch.pid);
+
+            self.audit("app_approval_expired", serde_json::json!({
+                "app": launch.app,
+                "pid": launch.pid,
+                "process_name": launch.process_name,
+            })).await;
+
+            self.notify(
+                EventType::AppBlocked,
+                &launch.app,
+                &format!("Approval for pid {} timed out, process terminated", launch.pid),
+            ).await;
+        }
+    }
+
+    // Either permitted approval method resumes the launch
+    pub async fn approve(&self, pid: u32, approval: Approval) -> Result<(), Box<dyn std::error::Error>> {
+        let launch = self.pending.read().await.get(&pid).cloned()
+            .ok_or_else(|| format!("No launch awaiting approval for process {}", pid))?;
+
+        let (method, issuer) = match &approval {
+            Approval::LocalPassword(password) => {
+                if !launch.requires_local_password {
+                    return Err(format!("{} does not accept local password approval", launch.app).into());
+                }
+
+                let details = serde_json::json!({ "app": launch.app, "pid": pid, "method": "local_password" });
+                if !self.check_local_password(LOCAL_PASSWORD, password, "app_approval_failed", details, &launch.app).await? {
+                    return Err("Invalid local password".into());
+                }
+
+                ("local_password", None)
+            }
+            Approval::Remote { issuer, command_id } => {
+                if !launch.requires_remote_password {
+                    return Err(format!("{} does not accept remote approval", launch.app).into());
+                }
+
+                debug!("Remote approval for pid {} via command {}", pid, command_id);
+                ("remote", Some(issuer.clone()))
+            }
+        };
+
+        if self.pending.write().await.remove(&pid).is_none() {
+            return Err(format!("No launch awaiting approval for process {}", pid).into());
+        }
+
+        self.system.resume_process(pid).await?;
+        info!("Resumed {} (pid {}) after {} approval", launch.app, pid, method);
+
+        self.audit("app_approved", serde_json::json!({
+            "app": launch.app,
+            "pid": pid,
+            "method": method,
+            "issuer": issuer,
+        })).await;
+
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub async fn list_pending(&self) -> Vec<PendingLaunch> {
+        let pending = self.pending.read().await;
+        let mut launches: Vec<_> = pending.values().cloned().collect();
+        launches.sort_by_key(|launch| launch.suspended_at);
+        launches
+    }
+
+    // Password checks from the control socket share the approval lockout, so the CLI
+    // cannot be used to guess the local password without limit
+    pub async fn verify_password(&self, name: &str, password: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let details = serde_json::json!({ "name": name });
+        self.check_local_password(name, password, "password_check_failed", details, &format!("Password {}", name)).await
+    }
+
+    // Held while the password is checked so parallel guesses are counted in turn. Fails
+    // without checking while locked; details and subject describe the attempt in the
+    // audit log and the lockout notification.
+    async fn check_local_password(
+        &self,
+        name: &str,
+        password: &str,
+        audit_event: &str,
+        mut details: serde_json::Value,
+        subject: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut attempts = self.local_attempts.lock().await;
+        let now = Utc::now();
+
+        if let Some(until) = attempts.locked(now) {
+            details["locked_until"] = until.to_rfc3339().into();
+            self.audit(audit_event, details).await;
+            return Err(format!(
+                "Too many failed local approvals, try again in {}s",
+                (until - now).num_seconds().max(1)
+            ).into());
+        }
+
+        if !self.storage.verify_password(name, password).await? {
+            let settings = self.config.read().await.security.brute_force.clone();
+            let (failures, locked_until) = attempts.fail(now, &settings);
+            details["recent_failures"] = failures.into();
+            details["locked_until"] = locked_until.map(|until| until.to_rfc3339()).into();
+            self.audit(audit_event, details).await;
+
+            if let Some(until) = locked_until {
+                warn!("Local approvals locked until {} after {} failed passwords", until, failures);
+                self.notify(
+                    EventType::AppBlocked,
+                    subject,
+                    &format!("{} wrong local passwords, local approvals locked for {}s", failures, settings.cooldown_seconds),
+                ).await;
+            }
+            return Ok(false);
+        }
+
+        attempts.failures.clear();
+        Ok(true)
+    }
+
+    #[allow(dead_code)]
+    pub async fn set_local_password(&self, password: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.storage.set_password(LOCAL_PASSWORD, password).await
+    }
+
+    async fn audit(&self, event_type: &str, details: serde_json::Value) {
+        if let Err(e) = self.storage.log_audit_event(event_type, &details).await.map_err(|e| e.to_string()) {
+            error!("Failed to record {} audit event: {}", event_type, e);
+        }
+    }
+
+    async fn notify(&self, event_type: EventType, app: &str, details: &str) {
+        let user = self.system.get_current_user().await.unwrap_or(None);
+        let event = {
+            let config = self.config.read().await;
+            DiscordEvent::new(&config, &self.identity, event_type, user, Some(format!("{}: {}", app, details)))
+        };
+
+        if let Err(e) = self.notifier.notify(&event).await.map_err(|e| e.to_string()) {
+            warn!("Failed to send app event for {}: {}", app, e);
+        }
+    }
+}
+
+pub struct AppMatcher {
+    name: String,
+    rule: AppRule,
+    executable: Option<GlobMatcher>,
+    path: Option<GlobMatcher>,
+    pattern: Option<Regex>,
+}
+
+impl AppMatcher {
+    pub fn new(name: &str, rule: &AppRule) -> Result<Self, Box<dyn std::error::Error>> {
+        let executable = match &rule.executable {
+            Some(glob) => Some(
+                GlobBuilder::new(glob)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| format!("invalid executable glob: {}", e))?
+                    .compile_matcher(),
+            ),
+            None => None,
+        };
+
+        let path = match &rule.path {
+            Some(glob) => Some(
+                GlobBuilder::new(glob)
+                    .case_insensitive(cfg!(windows))
+                    .literal_separator(true)
+                    .build()
+                    .map_err(|e| format!("invalid path glob: {}", e))?
+                    .compile_matcher(),
+            ),
+            None => None,
+        };
+
+        let pattern = match &rule.pattern {
+            Some(pattern) => Some(Regex::new(pattern).map_err(|e| format!("invalid pattern: {}", e))?),
+            None => None,
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            rule: rule.clone(),
+            executable,
+            path,
+            pattern,
+        })
+    }
+
+    pub fn matches(&self, process: &ProcessInfo) -> bool {
+        let mut names = vec![process.name.clone()];
+        if let Some(file_name) = process.exe.as_ref().and_then(|exe| exe.file_name()) {
+            names.push(file_name.to_string_lossy().to_string());
+        }
+
+        if self.executable.is_none() && self.path.is_none() && self.pattern.is_none() {
+            return names.iter()
+                .any(|name| strip_exe_suffix(name).eq_ignore_ascii_case(&self.name));
+        }
+
+        let executable_matches = self.executable.as_ref()
+            .map(|glob| names.iter().any(|name| glob.is_match(name)))
+            .unwrap_or(true);
+
+        let path_matches = self.path.as_ref()
+            .map(|glob| process.exe.as_ref().map(|exe| glob.is_match(exe)).unwrap_or(false))
+            .unwrap_or(true);
+
+        let pattern_matches = self.pattern.as_ref()
+            .map(|pattern| {
+                names.iter().any(|name| pattern.is_match(name))
+                    || process.exe.as_ref().map(|exe| pattern.is_match(&exe.to_string_lossy())).unwrap_or(false)
+            })
+            .unwrap_or(true);
+
+        executable_matches && path_matches && pattern_matches
+    }
+}
+
+fn strip_exe_suffix(name: &str) -> &str {
+    match name.len().checked_sub(4).and_then(|split| name.get(split..).map(|suffix| (split, suffix))) {
+        Some((split, suffix)) if suffix.eq_ignore_ascii_case(".exe") => &name[..split],
+        _ => name,
+    }
+}
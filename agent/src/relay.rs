@@ -0,0 +1,485 @@
+use crate::tls;
+use hmac::{Hmac, Mac};
+use hyper::body::HttpBody;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, MutexGuard, Notify};
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, warn, debug};
+
+const MAX_BODY_BYTES: usize = 64 * 1024;
+const MAX_PENDING_COMMANDS: usize = 100;
+const MAX_STORED_RESPONSES: usize = 100;
+const MAX_WAIT_SECONDS: u64 = 60;
+
+// Connections that never finish the handshake would otherwise hold a task each
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Commands stay queued until the agent posts a response for them, so one whose poll
+// response was lost is delivered again after this long. After the agent's own command
+// age limit they could only be rejected, so they are dropped.
+const REDELIVER_AFTER: Duration = Duration::from_secs(5);
+const MAX_COMMAND_AGE: Duration = Duration::from_secs(300);
+
+// Mixed into each device's token so it cannot be mistaken for any other HMAC of the secret
+const DEVICE_TOKEN_LABEL: &str = "device-notifier relay device token\n";
+
+// Relay that holds commands for agents that cannot accept inbound connections.
+// Commands and responses are opaque JSON objects keyed by device id and command_id;
+// signature checks happen on the agent.
+//
+// The bot authenticates with the relay secret itself and may only queue commands and
+// collect responses. Each agent uses its own device token, which only reaches its own
+// mailbox and only to take commands and post responses.
+pub struct RelayServer {
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    state: Arc<RelayState>,
+}
+
+struct RelayState {
+    secret: String,
+    mailboxes: Mutex<HashMap<String, Mailbox>>,
+}
+
+// The token an agent puts in relay.token, derived from the relay secret so the relay
+// keeps no list of devices
+pub fn device_token(secret: &str, device_id: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(DEVICE_TOKEN_LABEL.as_bytes());
+    mac.update(device_id.as_bytes());
+    Ok(mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+// Loads the relay's certificate and key; both or neither must be given
+pub fn load_tls(cert_path: Option<&str>, key_path: Option<&str>) -> Result<Option<TlsAcceptor>, Box<dyn std::error::Error>> {
+    match (cert_path, key_path) {
+        (Some(cert_path), Some(key_path)) => Ok(Some(tls::load_acceptor(cert_path, key_path)?)),
+        (None, None) => Ok(None),
+        _ => Err("Both RELAY_TLS_CERT and RELAY_TLS_KEY must be set to enable TLS".into()),
+    }
+}
+
+struct PendingCommand {
+    command_id: String,
+    command: serde_json::Value,
+    queued_at: Instant,
+    delivered_at: Option<Instant>,
+}
+
+impl PendingCommand {
+    fn is_due(&self, now: Instant) -> bool {
+        self.delivered_at.is_none_or(|delivered_at| now >= delivered_at + REDELIVER_AFTER)
+    }
+}
+
+struct Mailbox {
+    pending: VecDeque<PendingCommand>,
+    responses: VecDeque<(String, serde_json::Value)>,
+    command_ready: Arc<Notify>,
+    response_ready: Arc<Notify>,
+}
+
+impl Mailbox {
+    fn new() -> Self {
+        Self {
+            pending: VecDeque::new(),
+            responses: VecDeque::new(),
+            command_ready: Arc::new(Notify::new()),
+            response_ready: Arc::new(Notify::new()),
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.pending.retain(|pending| now < pending.queued_at + MAX_COMMAND_AGE);
+    }
+
+    // When the earliest command that was delivered but not answered is due again
+    fn next_redelivery(&self) -> Option<Instant> {
+        self.pending.iter()
+            .filter_map(|pending| pending.delivered_at)
+            .min()
+            .map(|delivered_at| delivered_at + REDELIVER_AFTER)
+    }
+
+    // Nothing queued and nobody waiting, so the mailbox can go
+    fn is_idle(&self) -> bool {
+        self.pending.is_empty()
+            && self.responses.is_empty()
+            && Arc::strong_count(&self.command_ready) == 1
+            && Arc::strong_count(&self.response_ready) == 1
+    }
+}
+
+impl RelayServer {
+    pub async fn bind(addr: &str, secret: String, tls: Option<TlsAcceptor>) -> Result<Self, Box<dyn std::error::Error>> {
+        if secret.is_empty() {
+            return Err("Relay token must not be empty".into());
+        }
+
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        if tls.is_none() && !local_addr.ip().is_loopback() {
+            warn!("Relay bound to non-loopback address {} without TLS, tokens travel in cleartext", local_addr);
+        }
+        info!("Relay listening on {} ({})", local_addr, if tls.is_some() { "https" } else { "http" });
+
+        Ok(Self {
+            listener,
+            tls,
+            state: Arc::new(RelayState {
+                secret,
+                mailboxes: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub async fn serve(self) {
+        loop {
+            let (stream, peer) = match self.listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("Failed to accept relay connection: {}", e);
+                    continue;
+                }
+            };
+
+            let state = self.state.clone();
+            let tls = self.tls.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req| {
+                    let state = state.clone();
+                    async move { Ok::<_, hyper::Error>(Self::route(req, &state).await) }
+                });
+
+                let result = match tls {
+                    Some(acceptor) => match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => Http::new().serve_connection(stream, service).await,
+                        Ok(Err(e)) => {
+                            debug!("TLS handshake with {} failed: {}", peer, e);
+                            return;
+                        }
+                        Err(_) => {
+                            debug!("TLS handshake with {} timed out", peer);
+                            return;
+                        }
+                    },
+                    None => Http::new().serve_connection(stream, service).await,
+                };
+
+                if let Err(e) = result {
+                    debug!("Relay connection from {} closed with error: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn route(req: Request<Body>, state: &RelayState) -> Response<Body> {
+        let segments: Vec<String> = req.uri().path()
+            .trim_matches('/')
+            .split('/')
+            .map(|segment| segment.to_string())
+            .collect();
+        let wait = wait_duration(req.uri().query());
+
+        let unauthorized = || error_response(StatusCode::UNAUTHORIZED, "Unauthorized");
+
+        match (req.method().clone(), segments.iter().map(|s| s.as_str()).collect::<Vec<_>>().as_slice()) {
+            (Method::POST, ["v1", "devices", device_id, "commands"]) => {
+                if !Self::is_bot(&req, state) {
+                    return unauthorized();
+                }
+                Self::enqueue_command(req, state, device_id).await
+            }
+            (Method::GET, ["v1", "devices", device_id, "commands"]) => {
+                if !Self::is_device(&req, state, device_id) {
+                    return unauthorized();
+                }
+                Self::take_commands(state, device_id, wait).await
+            }
+            (Method::POST, ["v1", "devices", device_id, "responses"]) => {
+                if !Self::is_device(&req, state, device_id) {
+                    return unauthorized();
+                }
+                Self::store_response(req, state, device_id).await
+            }
+            (Method::GET, ["v1", "devices", device_id, "responses", command_id]) => {
+                if !Self::is_bot(&req, state) {
+                    return unauthorized();
+                }
+                Self::wait_for_response(state, device_id, command_id, wait).await
+            }
+            _ => error_response(StatusCode::NOT_FOUND, "Not found"),
+        }
+    }
+
+    fn is_bot(req: &Request<Body>, state: &RelayState) -> bool {
+        constant_time_eq(bearer_token(req).as_bytes(), state.secret.as_bytes())
+    }
+
+    fn is_device(req: &Request<Body>, state: &RelayState, device_id: &str) -> bool {
+        device_token(&state.secret, device_id)
+            .is_ok_and(|token| constant_time_eq(bearer_token(req).as_bytes(), token.as_bytes()))
+    }
+
+    async fn enqueue_command(req: Request<Body>, state: &RelayState, device_id: &str) -> Response<Body> {
+        let command = match read_json(req.into_body()).await {
+            Ok(command) => command,
+            Err(response) => return response,
+        };
+
+        let command_id = match command.get("command_id").and_then(|id| id.as_str()) {
+            Some(command_id) => command_id.to_string(),
+            None => return error_response(StatusCode::BAD_REQUEST, "Command is missing command_id"),
+        };
+
+        let mut mailboxes = state.mailboxes.lock().await;
+        let mailbox = mailboxes.entry(device_id.to_string()).or_insert_with(Mailbox::new);
+
+        let now = Instant::now();
+        mailbox.expire(now);
+        if mailbox.pending.len() >= MAX_PENDING_COMMANDS {
+            return error_response(StatusCode::TOO_MANY_REQUESTS, "Too many pending commands for device");
+        }
+
+        mailbox.pending.push_back(PendingCommand { command_id, command, queued_at: now, delivered_at: None });
+        mailbox.command_ready.notify_waiters();
+        debug!("Queued command for device {}", device_id);
+
+        json_response(StatusCode::ACCEPTED, &serde_json::json!({ "queued": true }))
+    }
+
+    // Returns the commands not delivered yet or due for redelivery; they stay queued
+    // until store_response sees their response
+    async fn take_commands(state: &RelayState, device_id: &str, wait: Duration) -> Response<Body> {
+        let deadline = Instant::now() + wait;
+
+        loop {
+            let mut mailboxes = state.mailboxes.lock().await;
+            let mailbox = mailboxes.entry(device_id.to_string()).or_insert_with(Mailbox::new);
+
+            let now = Instant::now();
+            mailbox.expire(now);
+            let commands: Vec<_> = mailbox.pending.iter_mut()
+                .filter(|pending| pending.is_due(now))
+                .map(|pending| {
+                    pending.delivered_at = Some(now);
+                    pending.command.clone()
+                })
+                .collect();
+            if !commands.is_empty() {
+                return json_response(StatusCode::OK, &commands);
+            }
+
+            let notify = mailbox.command_ready.clone();
+            let wake = mailbox.next_redelivery().map_or(deadline, |redelivery| redelivery.min(deadline));
+            if !Self::wait_on(notify, mailboxes, wake).await && Instant::now() >= deadline {
+                Self::prune(&mut *state.mailboxes.lock().await, device_id);
+                return json_response(StatusCode::OK, &Vec::<serde_json::Value>::new());
+            }
+        }
+    }
+
+    async fn store_response(req: Request<Body>, state: &RelayState, device_id: &str) -> Response<Body> {
+        let response = match read_json(req.into_body()).await {
+            Ok(response) => response,
+            Err(response) => return response,
+        };
+
+        let command_id = match response.get("command_id").and_then(|id| id.as_str()) {
+            Some(command_id) => command_id.to_string(),
+            None => return error_response(StatusCode::BAD_REQUEST, "Response is missing command_id"),
+        };
+
+        let mut mailboxes = state.mailboxes.lock().await;
+        let mailbox = mailboxes.entry(device_id.to_string()).or_insert_with(Mailbox::new);
+
+        // The response is the agent's acknowledgement
+        mailbox.pending.retain(|pending| pending.command_id != command_id);
+        mailbox.responses.retain(|(id, _)| *id != command_id);
+        mailbox.responses.push_back((command_id, response));
+        while mailbox.responses.len() > MAX_STORED_RESPONSES {
+            mailbox.responses.pop_front();
+        }
+        mailbox.response_ready.notify_waiters();
+
+        json_response(StatusCode::ACCEPTED, &serde_json::json!({ "stored": true }))
+    }
+
+    async fn wait_for_response(state: &RelayState, device_id: &str, command_id: &str, wait: Duration) -> Response<Body> {
+        let deadline = Instant::now() + wait;
+
+        loop {
+            let mut mailboxes = state.mailboxes.lock().await;
+            let mailbox = mailboxes.entry(device_id.to_string()).or_insert_with(Mailbox::new);
+
+            if let Some(index) = mailbox.responses.iter().position(|(id, _)| id == command_id) {
+                if let Some((_, response)) = mailbox.responses.remove(index) {
+                    Self::prune(&mut mailboxes, device_id);
+                    return json_response(StatusCode::OK, &response);
+                }
+            }
+
+            let notify = mailbox.response_ready.clone();
+            if !Self::wait_on(notify, mailboxes, deadline).await {
+                Self::prune(&mut *state.mailboxes.lock().await, device_id);
+                return Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
+                    .unwrap_or_else(|_| Response::new(Body::empty()));
+            }
+        }
+    }
+
+    // Waits for notify until the deadline and returns whether it fired. The waiter is
+    // registered before the mailbox lock is released, since notify_waiters only wakes
+    // waiters that already exist.
+    async fn wait_on(notify: Arc<Notify>, mailboxes: MutexGuard<'_, HashMap<String, Mailbox>>, deadline: Instant) -> bool {
+        let notified = notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        drop(mailboxes);
+
+        tokio::time::timeout_at(deadline, notified).await.is_ok()
+    }
+
+    // Every request names a device, so mailboxes are dropped once idle rather than kept
+    // for every device id ever polled
+    fn prune(mailboxes: &mut HashMap<String, Mailbox>, device_id: &str) {
+        if mailboxes.get(device_id).is_some_and(Mailbox::is_idle) {
+            mailboxes.remove(device_id);
+        }
+    }
+}
+
+// Agent-side client for pulling commands from a relay
+pub struct RelayClient {
+    http_client: reqwest::Client,
+    base_url: String,
+    token: String,
+    device_id: String,
+}
+
+impl RelayClient {
+    pub fn new(base_url: &str, token: &str, device_id: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let http_client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(MAX_WAIT_SECONDS + 15))
+            .build()?;
+
+        Ok(Self {
+            http_client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+            device_id: device_id.to_string(),
+        })
+    }
+
+    pub async fn fetch_commands(&self, wait: Duration) -> Result<Vec<serde_json::Value>, Box<dyn std::error::Error>> {
+        let url = format!(
+            "{}/v1/devices/{}/commands?wait={}",
+            self.base_url,
+            self.device_id,
+            wait.as_secs().min(MAX_WAIT_SECONDS)
+        );
+
+        let response = self.http_client
+            .get(url)
+            .bearer_auth(&self.token)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(format!("Relay returned HTTP {}", response.status()).into());
+        }
+
+        Ok(response.json().await?)
+    }
+
+    pub async fn post_response<T: Serialize>(&self, response: &T) -> Result<(), Box<dyn std::error::Error>> {
+        let url = format!("{}/v1/devices/{}/responses", self.base_url, self.device_id);
+
+        let result = self.http_client
+            .post(url)
+            .bearer_auth(&self.token)
+            .json(response)
+            .send()
+            .await?;
+
+        if !result.status().is_success() {
+            return Err(format!("Relay returned HTTP {}", result.status()).into());
+        }
+
+        Ok(())
+    }
+}
+
+fn bearer_token(req: &Request<Body>) -> &str {
+    req.headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or("")
+}
+
+fn wait_duration(query: Option<&str>) -> Duration {
+    let seconds = query
+        .unwrap_or("")
+        .split('&')
+        .filter_map(|pair| pair.strip_prefix("wait="))
+        .find_map(|value| value.parse::<u64>().ok())
+        .unwrap_or(0);
+
+    Duration::from_secs(seconds.min(MAX_WAIT_SECONDS))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn read_json(mut body: Body) -> Result<serde_json::Value, Response<Body>> {
+    let mut buffer = Vec::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| error_response(StatusCode::BAD_REQUEST, "Invalid request body"))?;
+        if buffer.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(error_response(StatusCode::PAYLOAD_TOO_LARGE, "Request body too large"));
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+
+    match serde_json::from_slice::<serde_json::Value>(&buffer) {
+        Ok(value) if value.is_object() => Ok(value),
+        _ => Err(error_response(StatusCode::BAD_REQUEST, "Expected a JSON object")),
+    }
+}
+
+fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    let body = serde_json::to_vec(value).unwrap_or_default();
+
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(body))
+        .unwrap_or_else(|_| Response::new(Body::empty()))
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, &serde_json::json!({ "error": message }))
+}
@@ -0,0 +1,148 @@
+use crate::config::{Config, SharedConfig};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tracing::{info, warn, error, debug};
+
+// Sections that are only read at startup
+const RESTART_REQUIRED_SECTIONS: &[&str] = &["api", "relay"];
+
+// Reloads config.toml when it changes on disk or the agent receives SIGHUP. The new
+// config is validated before it replaces the shared one, so a bad edit leaves the
+// running config untouched. Subscribers get the reload generation over a watch channel.
+pub struct ConfigReloader {
+    config: SharedConfig,
+    config_file: PathBuf,
+    overrides: Vec<(String, String)>,
+    updates: watch::Sender<u64>,
+}
+
+impl ConfigReloader {
+    pub fn new(config: SharedConfig, config_file: PathBuf, overrides: Vec<(String, String)>) -> Self {
+        let (updates, _) = watch::channel(0);
+
+        Self {
+            config,
+            config_file,
+            overrides,
+            updates,
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.updates.subscribe()
+    }
+
+    pub async fn run(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let file_name = self.config_file.file_name()
+            .ok_or("Config path has no file name")?
+            .to_os_string();
+        let watch_dir = self.config_file.parent()
+            .ok_or("Config path has no parent directory")?
+            .to_path_buf();
+        fs::create_dir_all(&watch_dir)?;
+
+        // Editors and Config::persist replace the file, so watch the directory
+        let file_tx = tx.clone();
+        let mut watcher = notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+            match result {
+                Ok(event) => {
+                    let touches_config = event.paths.iter()
+                        .any(|path| path.file_name() == Some(file_name.as_os_str()));
+
+                    if touches_config && !matches!(event.kind, EventKind::Access(_)) {
+                        let _ = file_tx.send("file change");
+                    }
+                }
+                Err(e) => warn!("Config watcher error: {}", e),
+            }
+        })?;
+        watcher.watch(&watch_dir, RecursiveMode::NonRecursive)?;
+        info!("Watching {} for configuration changes", self.config_file.display());
+
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut hangup = signal(SignalKind::hangup())?;
+            let signal_tx = tx.clone();
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    if signal_tx.send("SIGHUP").is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        drop(tx);
+
+        while let Some(trigger) = rx.recv().await {
+            // Editors often write several events per save
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            while rx.try_recv().is_ok() {}
+
+            debug!("Configuration reload triggered by {}", trigger);
+
+            if let Err(e) = self.reload().await.map_err(|e| e.to_string()) {
+                error!("Configuration reload rejected, keeping current config: {}", e);
+            }
+        }
+
+        drop(watcher);
+        Ok(())
+    }
+
+    // Returns the top-level sections that changed
+    pub async fn reload(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let new_config = Config::load_from(&self.config_file, &Config::process_environment(), &self.overrides)?;
+
+        let changed = {
+            let mut config = self.config.write().await;
+            let changed = changed_sections(&config, &new_config)?;
+
+            if !changed.is_empty() {
+                *config = new_config;
+            }
+
+            changed
+        };
+
+        if changed.is_empty() {
+            debug!("Configuration unchanged");
+            return Ok(changed);
+        }
+
+        for section in changed.iter().filter(|section| RESTART_REQUIRED_SECTIONS.contains(&section.as_str())) {
+            warn!("Changes to [{}] take effect after the agent restarts", section);
+        }
+
+        self.updates.send_modify(|generation| *generation += 1);
+        info!("Configuration reloaded, changed: {}", changed.join(", "));
+
+        Ok(changed)
+    }
+}
+
+fn changed_sections(current: &Config, new: &Config) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let current = serde_json::to_value(current)?;
+    let new = serde_json::to_value(new)?;
+
+    let (current, new) = match (current.as_object(), new.as_object()) {
+        (Some(current), Some(new)) => (current, new),
+        _ => return Err("Configuration did not serialize to an object".into()),
+    };
+
+    let mut changed: Vec<String> = new.iter()
+        .filter(|(section, value)| current.get(section.as_str()) != Some(value))
+        .map(|(section, _)| section.clone())
+        .collect();
+    changed.sort();
+
+    Ok(changed)
+}
@@ -0,0 +1,80 @@
+use crate::storage::SecureStorage;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{info, warn, debug};
+
+const STORAGE_KEY: &str = "replay_cache";
+const MAX_ENTRIES: usize = 10000;
+
+// Remembers command ids until their timestamps fall out of the acceptance window,
+// after which validate_command rejects them as stale anyway.
+pub struct ReplayCache {
+    storage: Arc<SecureStorage>,
+    retention: Duration,
+    seen: RwLock<Option<HashMap<String, DateTime<Utc>>>>,
+}
+
+impl ReplayCache {
+    pub fn new(storage: Arc<SecureStorage>, retention: Duration) -> Self {
+        Self {
+            storage,
+            retention,
+            seen: RwLock::new(None),
+        }
+    }
+
+    // Returns false if the command id has been seen before
+    pub async fn check_and_record(&self, command_id: &str, issued_at: DateTime<Utc>) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut seen = self.seen.write().await;
+
+        // Until the stored cache can be read nothing is accepted, since any command
+        // in it could be replayed
+        if seen.is_none() {
+            *seen = Some(self.load().await?);
+        }
+
+        let entries = seen.get_or_insert_with(HashMap::new);
+
+        let cutoff = Utc::now() - self.retention;
+        entries.retain(|_, timestamp| *timestamp > cutoff);
+
+        if entries.contains_key(command_id) {
+            warn!("Replayed command rejected: {}", command_id);
+            return Ok(false);
+        }
+
+        entries.insert(command_id.to_string(), issued_at);
+
+        while entries.len() > MAX_ENTRIES {
+            let oldest = entries.iter()
+                .min_by_key(|(_, timestamp)| **timestamp)
+                .map(|(id, _)| id.clone());
+
+            match oldest {
+                Some(id) => entries.remove(&id),
+                None => break,
+            };
+        }
+
+        let data = serde_json::to_vec(&*entries)?;
+        self.storage.store_encrypted_data(STORAGE_KEY, &data).await?;
+
+        debug!("Command recorded in replay cache: {}", command_id);
+        Ok(true)
+    }
+
+    async fn load(&self) -> Result<HashMap<String, DateTime<Utc>>, Box<dyn std::error::Error>> {
+        let data = match self.storage.retrieve_encrypted_data(STORAGE_KEY).await {
+            Ok(Some(data)) => data,
+            Ok(None) => return Ok(HashMap::new()),
+            Err(e) => return Err(format!("Failed to load replay cache: {}", e).into()),
+        };
+
+        let entries: HashMap<String, DateTime<Utc>> = serde_json::from_slice(&data)
+            .map_err(|e| format!("Replay cache is corrupt: {}", e))?;
+        info!("Loaded {} replay cache entries", entries.len());
+        Ok(entries)
+    }
+}
@@ -0,0 +1,92 @@
+use crate::config::{Config, RoutingRule};
+use crate::discord::{DiscordEvent, EventType};
+
+// Destination name of the Discord webhook in routing rules
+pub const DISCORD: &str = "discord";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    // Index into Config.routes of the rule that decided, None if no rule matched
+    pub rule: Option<usize>,
+    pub destinations: Vec<String>,
+}
+
+pub fn rule_label(index: usize, rule: &RoutingRule) -> String {
+    match &rule.name {
+        Some(name) => format!("routes[{}] ({})", index, name),
+        None => format!("routes[{}]", index),
+    }
+}
+
+pub fn matches(rule: &RoutingRule, event: &DiscordEvent, device_tags: &[String]) -> bool {
+    let severity = event.severity();
+
+    (rule.events.is_empty() || rule.events.contains(&event.event_type))
+        && rule.min_severity.is_none_or(|minimum| severity >= minimum)
+        && (rule.users.is_empty() || event.user_local.as_ref().is_some_and(|user| rule.users.contains(user)))
+        && (rule.device_tags.is_empty() || rule.device_tags.iter().any(|tag| device_tags.contains(tag)))
+}
+
+// The first matching rule narrows the destinations; without one every destination is
+// a candidate. Each destination's own events filter still applies either way.
+pub fn route(config: &Config, event: &DiscordEvent) -> Route {
+    let rule = config.routes.iter().position(|rule| matches(rule, event, &config.device.tags));
+
+    let mut names: Vec<&String> = config.notifiers.keys().collect();
+    names.sort();
+
+    let destinations = std::iter::once(DISCORD)
+        .chain(names.into_iter().map(String::as_str))
+        .filter(|name| rule.is_none_or(|index| config.routes[index].destinations.iter().any(|destination| destination == name)))
+        .filter(|name| accepts(config, name, &event.event_type))
+        .map(str::to_string)
+        .collect();
+
+    Route { rule, destinations }
+}
+
+fn accepts(config: &Config, destination: &str, event_type: &EventType) -> bool {
+    let events = if destination == DISCORD {
+        &config.discord.events
+    } else {
+        match config.notifiers.get(destination) {
+            Some(sink) if sink.enabled => &sink.events,
+            _ => return false,
+        }
+    };
+
+    events.is_empty() || events.contains(event_type)
+}
+
+// Explains where an event would be delivered, without sending anything
+pub fn dry_run(config: &Config, event: &DiscordEvent) -> String {
+    let route = route(config, event);
+
+    let mut lines = vec![format!(
+        "{:?} ({}) for {} on {}",
+        event.event_type,
+        event.severity().as_str(),
+        event.user_local.as_deref().unwrap_or("no user"),
+        config.device.alias
+    )];
+
+    lines.push(match route.rule {
+        Some(index) => format!("matched {}", rule_label(index, &config.routes[index])),
+        None => "no routing rule matched".to_string(),
+    });
+
+    if route.destinations.is_empty() {
+        lines.push("-> dropped".to_string());
+    }
+
+    let discord_ready = config.user_consent.discord_integration_enabled && config.discord.webhook_url.is_some();
+    for destination in &route.destinations {
+        if destination == DISCORD && !discord_ready {
+            lines.push(format!("-> {} (skipped: no consent or webhook_url)", destination));
+        } else {
+            lines.push(format!("-> {}", destination));
+        }
+    }
+
+    lines.join("\n")
+}
@@ -1,119 +1,201 @@
-use crate::config::Config;
-use ring::aead::{self, BoundKey, Nonce, UnboundKey, AES_256_GCM};
+use crate::config::{Config, SharedConfig};
+use crate::jwt;
+use crate::keystore::{self, KeyRing};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
 use ring::rand::{SecureRandom, SystemRandom};
 use ring::digest::{Context, SHA256};
 use base64::{Engine as _, engine::general_purpose};
-use tracing::{info, warn, error, debug};
+use tracing::info;
+use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+// Argon2id cost for new password hashes: 19 MiB and two passes, the OWASP baseline.
+// Hashes made with anything weaker are replaced when the password is next verified.
+const PASSWORD_MEMORY_KIB: u32 = 19 * 1024;
+const PASSWORD_ITERATIONS: u32 = 2;
+const PASSWORD_PARALLELISM: u32 = 1;
+
+// Compares without stopping at the first difference, so timing reveals nothing about
+// how much of a secret matched
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+// Whether a stored password hash predates the current algorithm or parameters
+pub fn password_needs_rehash(hash: &str) -> bool {
+    let hash = match PasswordHash::new(hash) {
+        Ok(hash) => hash,
+        Err(_) => return true,
+    };
+    let params = match Params::try_from(&hash) {
+        Ok(params) => params,
+        Err(_) => return true,
+    };
+
+    hash.algorithm != Algorithm::Argon2id.ident()
+        || hash.version != Some(Version::V0x13 as u32)
+        || params.m_cost() < PASSWORD_MEMORY_KIB
+        || params.t_cost() < PASSWORD_ITERATIONS
+        || params.p_cost() < PASSWORD_PARALLELISM
+}
+
 pub struct SecurityManager {
-    config: Arc<RwLock<Config>>,
-    encryption_key: Arc<RwLock<Option<Vec<u8>>>>,
+    config: SharedConfig,
+    keys: Arc<RwLock<Option<KeyRing>>>,
     rng: SystemRandom,
 }
 
 impl SecurityManager {
-    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn new(config: SharedConfig) -> Result<Self, Box<dyn std::error::Error>> {
         let rng = SystemRandom::new();
         
         Ok(Self {
-            config: Arc::new(RwLock::new(config.clone())),
-            encryption_key: Arc::new(RwLock::new(None)),
+            config,
+            keys: Arc::new(RwLock::new(None)),
             rng,
         })
     }
 
+    #[allow(dead_code)]
     pub async fn initialize_encryption(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut encryption_key = self.encryption_key.write().await;
-        
-        if encryption_key.is_none() {
-            let key = self.generate_encryption_key().await?;
-            *encryption_key = Some(key);
-            info!("Encryption key initialized");
+        let dir = Config::get_config_dir()?.join("keys");
+        self.open_keyring(&dir).await
+    }
+
+    pub async fn open_keyring(&self, dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let mut keys = self.keys.write().await;
+        
+        if keys.is_none() {
+            let encryption = self.config.read().await.security.encryption.clone();
+            // A passphrase master key takes a deliberately slow Argon2id derivation
+            let dir = dir.to_path_buf();
+            let keyring = tokio::task::spawn_blocking(move || KeyRing::open(&dir, &encryption).map_err(|e| e.to_string())).await??;
+            info!("Encryption keys loaded (data key v{})", keyring.current_version());
+            *keys = Some(keyring);
         }
         
         Ok(())
     }
 
-    async fn generate_encryption_key(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let mut key = vec![0u8; 32]; // 256-bit key for AES-256-GCM
-        self.rng.fill(&mut key)?;
-        Ok(key)
+    // context is bound to the ciphertext as associated data; decryption with any other
+    // context fails
+    pub async fn encrypt_data(&self, data: &[u8], context: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let keys = self.keys.read().await;
+        keys.as_ref().ok_or("Encryption key not initialized")?.encrypt(data, context)
     }
 
-    pub async fn encrypt_data(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let encryption_key = self.encryption_key.read().await;
-        let key = encryption_key.as_ref()
-            .ok_or("Encryption key not initialized")?;
-        
-        // Generate a random nonce
-        let mut nonce_bytes = [0u8; 12];
-        self.rng.fill(&mut nonce_bytes)?;
-        let nonce = Nonce::assume_unique_for_key(nonce_bytes);
-        
-        // Create the encryption key
-        let unbound_key = UnboundKey::new(&AES_256_GCM, key)?;
-        let mut key = aead::OpeningKey::new(unbound_key, nonce);
-        
-        // Encrypt the data
-        let mut encrypted_data = data.to_vec();
-        let tag = key.seal_in_place_append_tag(aead::Aad::empty(), &mut encrypted_data)?;
-        encrypted_data.extend_from_slice(tag.as_ref());
-        
-        // Prepend nonce to encrypted data
-        let mut result = nonce_bytes.to_vec();
-        result.extend_from_slice(&encrypted_data);
-        
-        Ok(result)
+    pub async fn decrypt_data(&self, encrypted_data: &[u8], context: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let keys = self.keys.read().await;
+        keys.as_ref().ok_or("Encryption key not initialized")?.decrypt(encrypted_data, context)
     }
 
-    pub async fn decrypt_data(&self, encrypted_data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        if encrypted_data.len() < 28 { // 12 bytes nonce + 16 bytes tag + at least 1 byte data
-            return Err("Encrypted data too short".into());
+    pub async fn audit_key(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let keys = self.keys.read().await;
+        Ok(keys.as_ref().ok_or("Encryption key not initialized")?.audit_key())
+    }
+
+    // Re-encrypts every file in storage_dir under a new data key, then drops the old keys.
+    // The new key is saved before any file is rewritten, so an interrupted rotation leaves
+    // every file readable. Returns the new key version and the number of files rewritten.
+    pub async fn rotate_keys(&self, storage_dir: &Path) -> Result<(u32, usize), Box<dyn std::error::Error>> {
+        let mut keys = self.keys.write().await;
+        let keyring = keys.as_mut().ok_or("Encryption key not initialized")?;
+        
+        let version = keyring.add_key()?;
+        let mut rotated = 0;
+        
+        if storage_dir.exists() {
+            for entry in std::fs::read_dir(storage_dir)? {
+                let path = entry?.path();
+                let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default().to_string();
+                
+                // Files are encrypted with their storage key, the file name, as context
+                let context = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default().to_string();
+                let reencrypt = |data: &[u8]| -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+                    let data = keyring.decrypt(data, &context)
+                        .map_err(|e| format!("Could not decrypt {}: {}", path.display(), e))?;
+                    keyring.encrypt(&data, &context)
+                };
+                
+                let contents = match extension.as_str() {
+                    "enc" => reencrypt(&std::fs::read(&path)?)?,
+                    // Append-only logs hold one base64 encrypted record per line
+                    "log" => {
+                        let mut lines = String::new();
+                        for line in std::fs::read_to_string(&path)?.lines().filter(|line| !line.is_empty()) {
+                            let record = reencrypt(&general_purpose::STANDARD.decode(line)?)?;
+                            lines.push_str(&general_purpose::STANDARD.encode(record));
+                            lines.push('\n');
+                        }
+                        lines.into_bytes()
+                    }
+                    _ => continue,
+                };
+                keystore::replace_file(&path, &contents)?;
+                rotated += 1;
+            }
         }
         
-        let encryption_key = self.encryption_key.read().await;
-        let key = encryption_key.as_ref()
-            .ok_or("Encryption key not initialized")?;
-        
-        // Extract nonce and encrypted data
-        let (nonce_bytes, encrypted_with_tag) = encrypted_data.split_at(12);
-        let nonce = Nonce::assume_unique_for_key(nonce_bytes.try_into()?);
-        
-        // Create the decryption key
-        let unbound_key = UnboundKey::new(&AES_256_GCM, key)?;
-        let mut key = aead::OpeningKey::new(unbound_key, nonce);
-        
-        // Decrypt the data
-        let mut decrypted_data = encrypted_with_tag.to_vec();
-        let decrypted_len = key.open_in_place(aead::Aad::empty(), &mut decrypted_data)?
-            .len();
-        
-        decrypted_data.truncate(decrypted_len);
-        Ok(decrypted_data)
+        keyring.retire_old_keys()?;
+        info!("Rotated to data key v{}, re-encrypted {} files", version, rotated);
+        Ok((version, rotated))
+    }
+
+    // Argon2id hash in PHC string format, which records the salt and parameters with it.
+    // Hashing and verifying run on the blocking pool so they do not stall the runtime.
+    pub async fn hash_password(&self, password: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let salt = self.generate_salt().await?;
+        let salt = SaltString::encode_b64(&salt).map_err(|e| format!("Invalid salt: {}", e))?;
+        
+        let params = Params::new(PASSWORD_MEMORY_KIB, PASSWORD_ITERATIONS, PASSWORD_PARALLELISM, None)
+            .map_err(|e| format!("Invalid password hashing parameters: {}", e))?;
+        let password = password.to_string();
+        let hash = tokio::task::spawn_blocking(move || {
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                .hash_password(password.as_bytes(), &salt)
+                .map(|hash| hash.to_string())
+                .map_err(|e| format!("Password hashing failed: {}", e))
+        }).await??;
+        
+        Ok(hash)
     }
 
-    pub async fn hash_password(&self, password: &str, salt: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    // Hashes with the parameters stored in the PHC string; the result is compared in
+    // constant time
+    pub async fn verify_password(&self, password: &str, hash: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let (password, hash) = (password.to_string(), hash.to_string());
+        
+        let valid = tokio::task::spawn_blocking(move || {
+            let hash = PasswordHash::new(&hash).map_err(|e| format!("Invalid password hash: {}", e))?;
+            match Argon2::default().verify_password(password.as_bytes(), &hash) {
+                Ok(()) => Ok(true),
+                Err(argon2::password_hash::Error::Password) => Ok(false),
+                Err(e) => Err(format!("Password verification failed: {}", e)),
+            }
+        }).await??;
+        
+        Ok(valid)
+    }
+
+    // Single SHA-256 of password || salt, which local passwords used before Argon2id
+    pub async fn verify_legacy_password(&self, password: &str, salt: &[u8], expected_hash: &str) -> Result<bool, Box<dyn std::error::Error>> {
         let mut context = Context::new(&SHA256);
         context.update(password.as_bytes());
         context.update(salt);
         
-        let digest = context.finish();
-        Ok(general_purpose::STANDARD.encode(digest.as_ref()))
-    }
-
-    pub async fn verify_password(&self, password: &str, salt: &[u8], expected_hash: &str) -> Result<bool, Box<dyn std::error::Error>> {
-        let computed_hash = self.hash_password(password, salt).await?;
-        Ok(computed_hash == expected_hash)
+        let expected_hash = general_purpose::STANDARD.decode(expected_hash)?;
+        Ok(constant_time_eq(context.finish().as_ref(), &expected_hash))
     }
 
     pub async fn generate_salt(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
         let mut salt = vec![0u8; 32];
-        self.rng.fill(&mut salt)?;
+        self.rng.fill(&mut salt).map_err(|_| "Failed to generate salt")?;
         Ok(salt)
     }
 
+    #[allow(dead_code)]
     pub async fn generate_hmac(&self, data: &str, secret: &str) -> Result<String, Box<dyn std::error::Error>> {
         use hmac::{Hmac, Mac};
         use sha2::Sha256;
@@ -125,67 +207,34 @@ impl SecurityManager {
         Ok(general_purpose::STANDARD.encode(result.into_bytes()))
     }
 
+    #[allow(dead_code)]
     pub async fn verify_hmac(&self, data: &str, secret: &str, expected_hmac: &str) -> Result<bool, Box<dyn std::error::Error>> {
         let computed_hmac = self.generate_hmac(data, secret).await?;
-        Ok(computed_hmac == expected_hmac)
+        Ok(constant_time_eq(computed_hmac.as_bytes(), expected_hmac.as_bytes()))
     }
 
+    #[allow(dead_code)]
     pub async fn generate_jwt_token(&self, payload: &serde_json::Value, secret: &str) -> Result<String, Box<dyn std::error::Error>> {
-        use hmac::{Hmac, Mac};
-        use sha2::Sha256;
-        
-        let header = serde_json::json!({
-            "alg": "HS256",
-            "typ": "JWT"
-        });
-        
-        let header_b64 = general_purpose::STANDARD.encode(header.to_string().as_bytes());
-        let payload_b64 = general_purpose::STANDARD.encode(payload.to_string().as_bytes());
-        
-        let data_to_sign = format!("{}.{}", header_b64, payload_b64);
-        
-        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
-        mac.update(data_to_sign.as_bytes());
-        
-        let signature = mac.finalize();
-        let signature_b64 = general_purpose::STANDARD.encode(signature.into_bytes());
-        
-        Ok(format!("{}.{}.{}", header_b64, payload_b64, signature_b64))
+        jwt::encode(payload, &jwt::SigningKey::Hs256(secret.as_bytes()))
     }
 
+    // Checks the signature and the exp, nbf and aud claims
+    #[allow(dead_code)]
     pub async fn verify_jwt_token(&self, token: &str, secret: &str) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
-        let parts: Vec<&str> = token.split('.').collect();
-        if parts.len() != 3 {
-            return Err("Invalid JWT token format".into());
-        }
-        
-        let (header_b64, payload_b64, signature_b64) = (parts[0], parts[1], parts[2]);
-        
-        // Verify signature
-        let data_to_verify = format!("{}.{}", header_b64, payload_b64);
-        let expected_signature = self.generate_hmac(&data_to_verify, secret).await?;
-        
-        if signature_b64 != expected_signature {
-            return Err("Invalid JWT signature".into());
-        }
-        
-        // Decode payload
-        let payload_bytes = general_purpose::STANDARD.decode(payload_b64)?;
-        let payload_str = String::from_utf8(payload_bytes)?;
-        let payload: serde_json::Value = serde_json::from_str(&payload_str)?;
-        
-        Ok(payload)
+        jwt::decode(token, &[jwt::VerifyingKey::Hs256(secret.as_bytes())], &jwt::Validation::default())
     }
 
+    #[allow(dead_code)]
     pub async fn generate_secure_random_string(&self, length: usize) -> Result<String, Box<dyn std::error::Error>> {
         let mut bytes = vec![0u8; length];
-        self.rng.fill(&mut bytes)?;
+        self.rng.fill(&mut bytes).map_err(|_| "Failed to generate random bytes")?;
         
         // Convert to base64 and truncate to desired length
         let base64_string = general_purpose::STANDARD.encode(&bytes);
         Ok(base64_string[..length].to_string())
     }
 
+    #[allow(dead_code)]
     pub async fn validate_file_integrity(&self, file_path: &str, expected_hash: &str) -> Result<bool, Box<dyn std::error::Error>> {
         use std::fs;
         use std::io::Read;
@@ -215,7 +264,7 @@ impl SecurityManager {
         
         for _ in 0..3 {
             let mut random_data = vec![0u8; file_size];
-            self.rng.fill(&mut random_data)?;
+            self.rng.fill(&mut random_data).map_err(|_| "Failed to generate random bytes")?;
             file.write_all(&random_data)?;
             file.flush()?;
         }
@@ -232,12 +281,14 @@ impl SecurityManager {
         Ok(())
     }
 
+    #[allow(dead_code)]
     pub async fn get_security_status(&self) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
         let config = self.config.read().await;
-        let encryption_key = self.encryption_key.read().await;
+        let keys = self.keys.read().await;
         
         Ok(serde_json::json!({
-            "encryption_initialized": encryption_key.is_some(),
+            "encryption_initialized": keys.is_some(),
+            "data_key_version": keys.as_ref().map(KeyRing::current_version),
             "hmac_secret_configured": config.security.hmac_secret.is_some(),
             "remote_commands_enabled": config.user_consent.remote_commands_enabled,
             "audit_logging_enabled": config.features.audit_logging,
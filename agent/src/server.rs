@@ -0,0 +1,230 @@
+use crate::commands::CommandExecutor;
+use crate::config::ApiConfig;
+use crate::discord::{DiscordClient, DiscordCommand};
+use crate::process_guard::Approval;
+use crate::tls;
+use hyper::body::HttpBody;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, warn, error, debug};
+
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+// Connections that never finish the handshake would otherwise hold a task each
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+struct LocalApproval {
+    pid: u32,
+    password: String,
+}
+
+pub struct CommandServer {
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    require_token: bool,
+    executor: Arc<CommandExecutor>,
+    discord: Arc<DiscordClient>,
+}
+
+impl CommandServer {
+    pub async fn bind(
+        api: &ApiConfig,
+        executor: Arc<CommandExecutor>,
+        discord: Arc<DiscordClient>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let addr: SocketAddr = api.bind_address.parse()?;
+
+        let tls = match (&api.tls_cert_path, &api.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => Some(tls::load_acceptor(cert_path, key_path)?),
+            (None, None) => None,
+            _ => return Err("Both api.tls_cert_path and api.tls_key_path must be set to enable TLS".into()),
+        };
+
+        if tls.is_none() && !addr.ip().is_loopback() {
+            warn!("Command API bound to non-loopback address {} without TLS", addr);
+        }
+
+        let listener = TcpListener::bind(addr).await?;
+        info!(
+            "Command API listening on {} ({})",
+            listener.local_addr()?,
+            if tls.is_some() { "https" } else { "http" }
+        );
+
+        Ok(Self {
+            listener,
+            tls,
+            require_token: api.require_token,
+            executor,
+            discord,
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub async fn serve(self) {
+        loop {
+            let (stream, peer) = match self.listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("Failed to accept command API connection: {}", e);
+                    continue;
+                }
+            };
+
+            let executor = self.executor.clone();
+            let discord = self.discord.clone();
+            let tls = self.tls.clone();
+            let require_token = self.require_token;
+
+            tokio::spawn(async move {
+                let service = service_fn(move |req| {
+                    let executor = executor.clone();
+                    let discord = discord.clone();
+                    async move { Ok::<_, hyper::Error>(Self::route(req, peer, require_token, &executor, &discord).await) }
+                });
+
+                let result = match tls {
+                    Some(acceptor) => match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => Http::new().serve_connection(stream, service).await,
+                        Ok(Err(e)) => {
+                            debug!("TLS handshake with {} failed: {}", peer, e);
+                            return;
+                        }
+                        Err(_) => {
+                            debug!("TLS handshake with {} timed out", peer);
+                            return;
+                        }
+                    },
+                    None => Http::new().serve_connection(stream, service).await,
+                };
+
+                if let Err(e) = result {
+                    debug!("Command API connection from {} closed with error: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn route(req: Request<Body>, peer: SocketAddr, require_token: bool, executor: &CommandExecutor, discord: &DiscordClient) -> Response<Body> {
+        // Local approvals are loopback-only and carry the local password instead
+        if require_token && !matches!(req.uri().path(), "/v1/health" | "/v1/apps/approve") {
+            let token = req.headers()
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "));
+
+            let result = match token {
+                Some(token) => executor.verify_api_token(token).await.map_err(|e| e.to_string()),
+                None => Err("missing bearer token".to_string()),
+            };
+            if let Err(e) = result {
+                warn!("Rejected command API request from {}: {}", peer, e);
+                return error_response(StatusCode::UNAUTHORIZED, "A valid bearer token is required");
+            }
+        }
+
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/v1/health") => json_response(StatusCode::OK, &serde_json::json!({ "status": "ok" })),
+            (&Method::POST, "/v1/commands") => Self::handle_command(req, executor, discord).await,
+            (&Method::POST, "/v1/apps/approve") => Self::handle_local_approval(req, peer, executor).await,
+            (_, "/v1/health") | (_, "/v1/commands") | (_, "/v1/apps/approve") => {
+                error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
+            }
+            _ => error_response(StatusCode::NOT_FOUND, "Not found"),
+        }
+    }
+
+    async fn handle_command(req: Request<Body>, executor: &CommandExecutor, discord: &DiscordClient) -> Response<Body> {
+        let body = match read_body(req.into_body()).await {
+            Ok(body) => body,
+            Err(status) => return error_response(status, "Invalid request body"),
+        };
+
+        let command: DiscordCommand = match serde_json::from_slice(&body) {
+            Ok(command) => command,
+            Err(e) => return error_response(StatusCode::BAD_REQUEST, &format!("Malformed command: {}", e)),
+        };
+
+        debug!("Command received over API: {}", command.command_id);
+
+        match executor.handle_command(discord, command).await {
+            Ok(response) => json_response(StatusCode::OK, &response),
+            Err(e) => {
+                error!("Failed to handle command: {}", e);
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, "Command handling failed")
+            }
+        }
+    }
+
+    // Local password approvals are only accepted from the device itself
+    async fn handle_local_approval(req: Request<Body>, peer: SocketAddr, executor: &CommandExecutor) -> Response<Body> {
+        if !peer.ip().is_loopback() {
+            warn!("Rejected local approval request from {}", peer);
+            return error_response(StatusCode::FORBIDDEN, "Local approvals must come from this device");
+        }
+
+        let body = match read_body(req.into_body()).await {
+            Ok(body) => body,
+            Err(status) => return error_response(status, "Invalid request body"),
+        };
+
+        let request: LocalApproval = match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(e) => return error_response(StatusCode::BAD_REQUEST, &format!("Malformed approval: {}", e)),
+        };
+
+        let result = executor.process_guard()
+            .approve(request.pid, Approval::LocalPassword(request.password))
+            .await
+            .map_err(|e| e.to_string());
+
+        match result {
+            Ok(()) => json_response(StatusCode::OK, &serde_json::json!({
+                "pid": request.pid,
+                "resumed": true,
+            })),
+            Err(e) => error_response(StatusCode::FORBIDDEN, &e),
+        }
+    }
+}
+
+async fn read_body(mut body: Body) -> Result<Vec<u8>, StatusCode> {
+    let mut buffer = Vec::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if buffer.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+
+    Ok(buffer)
+}
+
+fn json_response<T: serde::Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    let body = serde_json::to_vec(value).unwrap_or_default();
+
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(body))
+        .unwrap_or_else(|_| Response::new(Body::empty()))
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, &serde_json::json!({ "error": message }))
+}
@@ -0,0 +1,296 @@
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use tracing::debug;
+
+// glibc `struct utmp` as written on 64-bit Linux (32-bit time fields for compatibility)
+const UTMP_RECORD_SIZE: usize = 384;
+const UT_LINESIZE: usize = 32;
+const UT_NAMESIZE: usize = 32;
+const UT_HOSTSIZE: usize = 256;
+
+const BOOT_TIME: i16 = 2;
+const USER_PROCESS: i16 = 7;
+const DEAD_PROCESS: i16 = 8;
+
+const UTMP_PATHS: &[&str] = &["/run/utmp", "/var/run/utmp"];
+const WTMP_PATH: &str = "/var/log/wtmp";
+
+// How far back in wtmp to look for the logout record of a session that just ended
+const WTMP_TAIL_RECORDS: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct UtmpRecord {
+    pub kind: i16,
+    pub pid: u32,
+    pub line: String,
+    pub user: String,
+    pub host: String,
+    pub session: i32,
+    pub time: DateTime<Utc>,
+    pub addr: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Session {
+    pub username: String,
+    pub tty: String,
+    pub remote_host: Option<String>,
+    pub session_id: Option<u32>,
+    pub pid: u32,
+    pub login_time: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionEvent {
+    Login(Session),
+    Logout {
+        session: Session,
+        logout_time: DateTime<Utc>,
+    },
+}
+
+impl Session {
+    // A tty is reused by later logins, so the login time and pid are part of the key
+    pub fn key(&self) -> String {
+        format!("{}:{}:{}", self.tty, self.pid, self.login_time.timestamp())
+    }
+
+    // X and Wayland sessions record the display (":0") as their host
+    pub fn is_remote(&self) -> bool {
+        self.remote_host.as_deref().is_some_and(|host| !host.starts_with(':'))
+    }
+
+    pub fn describe(&self) -> String {
+        let mut description = self.tty.clone();
+
+        if let Some(host) = &self.remote_host {
+            description.push_str(&format!(" from {}", host));
+        }
+        if let Some(session_id) = self.session_id {
+            description.push_str(&format!(" (session {})", session_id));
+        }
+
+        description
+    }
+
+    fn from_record(record: &UtmpRecord) -> Self {
+        let remote_host = if !record.host.is_empty() {
+            Some(record.host.clone())
+        } else {
+            record.addr.map(|addr| addr.to_string())
+        };
+
+        Self {
+            username: record.user.clone(),
+            tty: record.line.clone(),
+            remote_host,
+            session_id: u32::try_from(record.session).ok().filter(|id| *id > 0),
+            pid: record.pid,
+            login_time: record.time,
+        }
+    }
+}
+
+pub fn is_supported() -> bool {
+    cfg!(target_os = "linux")
+}
+
+// Parses utmp or wtmp contents. A trailing partial record (a write in progress) is ignored.
+pub fn parse_records(data: &[u8]) -> Result<Vec<UtmpRecord>, Box<dyn std::error::Error>> {
+    let remainder = data.len() % UTMP_RECORD_SIZE;
+    if remainder != 0 {
+        debug!("Ignoring {} trailing bytes of a partial utmp record", remainder);
+    }
+
+    data.chunks_exact(UTMP_RECORD_SIZE).map(parse_record).collect()
+}
+
+fn parse_record(record: &[u8]) -> Result<UtmpRecord, Box<dyn std::error::Error>> {
+    let kind = i16::from_ne_bytes(record[0..2].try_into()?);
+    let pid = i32::from_ne_bytes(record[4..8].try_into()?);
+    let session = i32::from_ne_bytes(record[336..340].try_into()?);
+    let tv_sec = i32::from_ne_bytes(record[340..344].try_into()?);
+    let tv_usec = i32::from_ne_bytes(record[344..348].try_into()?);
+
+    let time = Utc.timestamp_opt(i64::from(tv_sec), (tv_usec.clamp(0, 999_999) as u32) * 1000)
+        .single()
+        .ok_or_else(|| format!("Invalid utmp timestamp: {}", tv_sec))?;
+
+    Ok(UtmpRecord {
+        kind,
+        pid: u32::try_from(pid).unwrap_or(0),
+        line: c_string(&record[8..8 + UT_LINESIZE]),
+        user: c_string(&record[44..44 + UT_NAMESIZE]),
+        host: c_string(&record[76..76 + UT_HOSTSIZE]),
+        session,
+        time,
+        addr: parse_addr(&record[348..364])?,
+    })
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+// ut_addr_v6 holds an IPv4 address in its first word and zeros elsewhere
+fn parse_addr(bytes: &[u8]) -> Result<Option<IpAddr>, Box<dyn std::error::Error>> {
+    let octets: [u8; 16] = bytes.try_into()?;
+
+    if octets.iter().all(|&byte| byte == 0) {
+        return Ok(None);
+    }
+    if octets[4..].iter().all(|&byte| byte == 0) {
+        return Ok(Some(IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))));
+    }
+
+    Ok(Some(IpAddr::V6(Ipv6Addr::from(octets))))
+}
+
+// utmp keeps one record per tty; logging out turns the USER_PROCESS record into a DEAD_PROCESS
+pub fn active_sessions(records: &[UtmpRecord]) -> Vec<Session> {
+    records.iter()
+        .filter(|record| record.kind == USER_PROCESS && !record.user.is_empty())
+        .map(Session::from_record)
+        .collect()
+}
+
+pub fn read_active_sessions() -> Result<Vec<Session>, Box<dyn std::error::Error>> {
+    if !is_supported() {
+        return Err("Session tracking is not supported on this platform".into());
+    }
+
+    let path = UTMP_PATHS.iter()
+        .map(Path::new)
+        .find(|path| path.exists())
+        .ok_or("No utmp file found")?;
+
+    Ok(active_sessions(&parse_records(&std::fs::read(path)?)?))
+}
+
+// Reads the last `max_records` records of a wtmp file without loading the whole log
+pub fn read_wtmp_tail(path: &Path, max_records: usize) -> Result<Vec<UtmpRecord>, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let length = file.metadata()?.len();
+    let whole_records = length - length % UTMP_RECORD_SIZE as u64;
+    let start = whole_records.saturating_sub((max_records * UTMP_RECORD_SIZE) as u64);
+
+    file.seek(SeekFrom::Start(start))?;
+    let mut data = Vec::with_capacity((whole_records - start) as usize);
+    file.take(whole_records - start).read_to_end(&mut data)?;
+
+    parse_records(&data)
+}
+
+// Replays wtmp into login and logout events. Sessions still open at a reboot are
+// closed at the boot time, which is what `last` reports as "crash".
+pub fn session_history(records: &[UtmpRecord]) -> Vec<SessionEvent> {
+    let mut open: HashMap<String, Session> = HashMap::new();
+    let mut events = Vec::new();
+
+    for record in records {
+        match record.kind {
+            USER_PROCESS if !record.user.is_empty() => {
+                let session = Session::from_record(record);
+                open.insert(session.tty.clone(), session.clone());
+                events.push(SessionEvent::Login(session));
+            }
+            DEAD_PROCESS => {
+                if let Some(session) = open.remove(&record.line) {
+                    events.push(SessionEvent::Logout {
+                        session,
+                        logout_time: record.time,
+                    });
+                }
+            }
+            BOOT_TIME => {
+                let mut crashed: Vec<Session> = open.drain().map(|(_, session)| session).collect();
+                crashed.sort_by_key(|session| session.login_time);
+
+                events.extend(crashed.into_iter().map(|session| SessionEvent::Logout {
+                    session,
+                    logout_time: record.time,
+                }));
+            }
+            _ => {}
+        }
+    }
+
+    events
+}
+
+// When a session disappears from utmp, wtmp has the time it actually ended
+pub fn logout_time(session: &Session) -> Option<DateTime<Utc>> {
+    let records = read_wtmp_tail(Path::new(WTMP_PATH), WTMP_TAIL_RECORDS)
+        .map_err(|e| debug!("Could not read {}: {}", WTMP_PATH, e))
+        .ok()?;
+
+    find_logout_time(&records, session)
+}
+
+pub fn find_logout_time(records: &[UtmpRecord], session: &Session) -> Option<DateTime<Utc>> {
+    session_history(records).into_iter().rev().find_map(|event| match event {
+        SessionEvent::Logout { session: ended, logout_time } if ended.key() == session.key() => Some(logout_time),
+        _ => None,
+    })
+}
+
+// Diffs successive utmp snapshots. The first snapshot is the baseline, so sessions that
+// were open before the agent started are not reported as new logins.
+#[derive(Debug, Default)]
+pub struct SessionTracker {
+    sessions: HashMap<String, Session>,
+    initialized: bool,
+}
+
+impl SessionTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, current: Vec<Session>, now: DateTime<Utc>) -> Vec<SessionEvent> {
+        let current: HashMap<String, Session> = current.into_iter()
+            .map(|session| (session.key(), session))
+            .collect();
+
+        let mut events = Vec::new();
+
+        if self.initialized {
+            let mut ended: Vec<&Session> = self.sessions.iter()
+                .filter(|(key, _)| !current.contains_key(*key))
+                .map(|(_, session)| session)
+                .collect();
+            ended.sort_by_key(|session| session.login_time);
+
+            events.extend(ended.into_iter().map(|session| SessionEvent::Logout {
+                session: session.clone(),
+                logout_time: now,
+            }));
+
+            let mut started: Vec<&Session> = current.iter()
+                .filter(|(key, _)| !self.sessions.contains_key(*key))
+                .map(|(_, session)| session)
+                .collect();
+            started.sort_by_key(|session| session.login_time);
+
+            events.extend(started.into_iter().map(|session| SessionEvent::Login(session.clone())));
+        }
+
+        self.sessions = current;
+        self.initialized = true;
+
+        events
+    }
+
+    #[allow(dead_code)]
+    pub fn sessions(&self) -> Vec<Session> {
+        let mut sessions: Vec<Session> = self.sessions.values().cloned().collect();
+        sessions.sort_by_key(|session| session.login_time);
+        sessions
+    }
+}
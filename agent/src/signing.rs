@@ -0,0 +1,144 @@
+use crate::discord::DiscordCommand;
+use base64::{Engine as _, engine::general_purpose};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+#[allow(dead_code)]
+pub const SIGNATURE_VERSION: u32 = 2;
+
+const ENVELOPE_TAG_V1: &str = "DNCMD-v1";
+const ENVELOPE_TAG_V2: &str = "DNCMD-v2";
+
+const API_TOKEN_KEY_LABEL: &str = "device-notifier api token v1";
+
+// Canonical signing envelope shared with the Discord bot (discord-bot/src/signing.ts).
+// Version 2 is one field per line:
+//
+//   DNCMD-v2
+//   <command_id>
+//   <command type>
+//   <device_id>
+//   <issuer>
+//   <issuer roles, sorted and comma separated, possibly empty>
+//   <nonce>
+//   <timestamp, unix milliseconds>
+//   <arguments as JSON with sorted keys and no whitespace>
+//
+// Version 1 is the same without the roles line and is still accepted for commands
+// that carry no roles. Golden vectors live in tests/fixtures/signing_vectors.json.
+pub fn canonical_payload(command: &DiscordCommand) -> Result<String, Box<dyn std::error::Error>> {
+    let fields = [
+        command.command_id.as_str(),
+        command.command.as_str(),
+        command.device_id.as_str(),
+        command.authorized_user.as_str(),
+        command.nonce.as_str(),
+    ];
+
+    if fields.iter().any(|field| field.is_empty() || field.contains('\n')) {
+        return Err("Command envelope fields must be non-empty single-line strings".into());
+    }
+
+    if command.roles.iter().any(|role| role.is_empty() || role.contains(',') || role.contains('\n')) {
+        return Err("Command roles must be non-empty and must not contain commas or newlines".into());
+    }
+
+    let mut arguments = String::new();
+    write_canonical_json(&serde_json::Value::Object(command.arguments.clone()), &mut arguments)?;
+
+    let timestamp = command.timestamp.timestamp_millis();
+
+    match command.version {
+        1 => {
+            if !command.roles.is_empty() {
+                return Err("Version 1 envelopes cannot carry roles".into());
+            }
+
+            Ok(format!(
+                "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
+                ENVELOPE_TAG_V1, fields[0], fields[1], fields[2], fields[3], fields[4], timestamp, arguments
+            ))
+        }
+        2 => {
+            let mut roles: Vec<&str> = command.roles.iter().map(|role| role.as_str()).collect();
+            roles.sort_unstable();
+
+            Ok(format!(
+                "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
+                ENVELOPE_TAG_V2, fields[0], fields[1], fields[2], fields[3], roles.join(","), fields[4], timestamp, arguments
+            ))
+        }
+        version => Err(format!("Unsupported signature version: {}", version).into()),
+    }
+}
+
+// HS256 key for command API bearer tokens, derived from the command signing secret so a
+// signature made with one key is never valid under the other. The bot derives it the
+// same way (apiTokenKey in discord-bot/src/signing.ts).
+pub fn api_token_key(secret: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(API_TOKEN_KEY_LABEL.as_bytes());
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+#[allow(dead_code)]
+pub fn sign_command(command: &DiscordCommand, secret: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let payload = canonical_payload(command)?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(payload.as_bytes());
+
+    Ok(general_purpose::STANDARD.encode(mac.finalize().into_bytes()))
+}
+
+pub fn verify_command(command: &DiscordCommand, secret: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let payload = canonical_payload(command)?;
+
+    let signature = match general_purpose::STANDARD.decode(&command.signature) {
+        Ok(signature) => signature,
+        Err(_) => return Ok(false),
+    };
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(payload.as_bytes());
+
+    // verify_slice compares in constant time
+    Ok(mac.verify_slice(&signature).is_ok())
+}
+
+// Floats are rejected because their textual form differs between serde_json and JavaScript
+fn write_canonical_json(value: &serde_json::Value, out: &mut String) -> Result<(), Box<dyn std::error::Error>> {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+
+            out.push('{');
+            for (i, key) in keys.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::to_string(key)?);
+                out.push(':');
+                write_canonical_json(&map[key.as_str()], out)?;
+            }
+            out.push('}');
+        }
+        serde_json::Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical_json(item, out)?;
+            }
+            out.push(']');
+        }
+        serde_json::Value::Number(number) if number.is_f64() => {
+            return Err("Command arguments must not contain floating point numbers".into());
+        }
+        other => out.push_str(&serde_json::to_string(other)?),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,634 @@
+use crate::config::{NotifierConfig, NotifierKind, SmtpTls};
+use crate::discord::{DiscordEvent, Severity};
+use crate::notifier::{self, Notifier};
+use async_trait::async_trait;
+use base64::{Engine as _, engine::general_purpose};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, RequestBuilder, Url};
+use std::sync::Arc;
+use std::time::Duration;
+use sysinfo::{System, SystemExt};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+const HTTP_TIMEOUT_SECONDS: u64 = 10;
+const SMTP_TIMEOUT_SECONDS: u64 = 30;
+const DEFAULT_NTFY_URL: &str = "https://ntfy.sh";
+const DEFAULT_SYSLOG_ADDRESS: &str = "unix:///dev/log";
+const SYSLOG_FACILITY_AUTH: u8 = 4;
+const APP_NAME: &str = "device-notifier";
+
+// Reports what a sink is missing without building it, for Config::validate
+pub fn check(config: &NotifierConfig, device_alias: &str) -> Result<(), String> {
+    check_settings(config)?;
+
+    // Each of these ends up in a message header, where a line break would start a new one
+    if config.kind == Some(NotifierKind::Email) {
+        let fields = config.from.iter().map(|from| ("from", from.as_str()))
+            .chain(config.to.iter().map(|to| ("to", to.as_str())))
+            .chain(std::iter::once(("device.alias", device_alias)));
+        for (field, value) in fields {
+            if value.contains(['\r', '\n']) {
+                return Err(format!("{} must not contain line breaks", field));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_settings(config: &NotifierConfig) -> Result<(), String> {
+    let kind = config.kind.ok_or("type is required")?;
+
+    let required = match kind {
+        NotifierKind::Webhook | NotifierKind::Slack => vec![("url", config.url.is_some())],
+        NotifierKind::Matrix => vec![
+            ("url", config.url.is_some()),
+            ("token", config.token.is_some()),
+            ("room_id", config.room_id.is_some()),
+        ],
+        NotifierKind::Ntfy => vec![("topic", config.topic.is_some())],
+        NotifierKind::Gotify => vec![("url", config.url.is_some()), ("token", config.token.is_some())],
+        NotifierKind::Email => vec![
+            ("smtp_host", config.smtp_host.is_some()),
+            ("from", config.from.is_some()),
+            ("to", !config.to.is_empty()),
+        ],
+        NotifierKind::Syslog => Vec::new(),
+    };
+
+    let missing: Vec<&str> = required.into_iter()
+        .filter(|(_, present)| !present)
+        .map(|(field, _)| field)
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("{} notifier requires {}", kind.as_str(), missing.join(", ")));
+    }
+
+    if let Some(url) = &config.url {
+        Url::parse(url).map_err(|e| format!("invalid url {}: {}", url, e))?;
+    }
+
+    match kind {
+        NotifierKind::Ntfy if config.priority.is_some_and(|priority| !(1..=5).contains(&priority)) => {
+            return Err("ntfy priority must be between 1 and 5".to_string());
+        }
+        NotifierKind::Gotify if config.priority.is_some_and(|priority| priority > 10) => {
+            return Err("gotify priority must be between 0 and 10".to_string());
+        }
+        NotifierKind::Email => {
+            if config.username.is_some() != config.password.is_some() {
+                return Err("username and password must be set together".to_string());
+            }
+            // Never send credentials in the clear
+            if config.username.is_some() && config.smtp_tls == SmtpTls::None {
+                return Err("SMTP authentication requires smtp_tls = \"starttls\" or \"tls\"".to_string());
+            }
+        }
+        NotifierKind::Syslog => {
+            SyslogTarget::parse(config.address.as_deref().unwrap_or(DEFAULT_SYSLOG_ADDRESS))?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+pub fn build(name: &str, config: &NotifierConfig) -> Result<Arc<dyn Notifier>, Box<dyn std::error::Error>> {
+    check_settings(config)?;
+
+    let name = name.to_string();
+    let url = config.url.as_deref().map(Url::parse).transpose()?;
+
+    let sink: Arc<dyn Notifier> = match config.kind.ok_or("type is required")? {
+        NotifierKind::Webhook => {
+            let mut headers = HeaderMap::new();
+            for (header, value) in &config.headers {
+                headers.insert(HeaderName::try_from(header.as_str())?, HeaderValue::try_from(value.as_str())?);
+            }
+
+            Arc::new(WebhookSink {
+                name,
+                client: http_client()?,
+                url: url.ok_or("url is required")?,
+                headers,
+            })
+        }
+        NotifierKind::Slack => Arc::new(SlackSink {
+            name,
+            client: http_client()?,
+            url: url.ok_or("url is required")?,
+        }),
+        NotifierKind::Matrix => Arc::new(MatrixSink {
+            name,
+            client: http_client()?,
+            homeserver: url.ok_or("url is required")?,
+            token: config.token.clone().ok_or("token is required")?,
+            room_id: config.room_id.clone().ok_or("room_id is required")?,
+        }),
+        NotifierKind::Ntfy => Arc::new(NtfySink {
+            name,
+            client: http_client()?,
+            url: match url {
+                Some(url) => url,
+                None => Url::parse(DEFAULT_NTFY_URL)?,
+            },
+            topic: config.topic.clone().ok_or("topic is required")?,
+            token: config.token.clone(),
+            priority: config.priority,
+        }),
+        NotifierKind::Gotify => Arc::new(GotifySink {
+            name,
+            client: http_client()?,
+            url: url.ok_or("url is required")?,
+            token: config.token.clone().ok_or("token is required")?,
+            priority: config.priority,
+        }),
+        NotifierKind::Email => Arc::new(EmailSink {
+            name,
+            host: config.smtp_host.clone().ok_or("smtp_host is required")?,
+            port: config.smtp_port.unwrap_or(match config.smtp_tls {
+                SmtpTls::None => 25,
+                SmtpTls::Starttls => 587,
+                SmtpTls::Tls => 465,
+            }),
+            tls: config.smtp_tls,
+            credentials: config.username.clone().zip(config.password.clone()),
+            from: config.from.clone().ok_or("from is required")?,
+            to: config.to.clone(),
+        }),
+        NotifierKind::Syslog => Arc::new(SyslogSink {
+            name,
+            target: SyslogTarget::parse(config.address.as_deref().unwrap_or(DEFAULT_SYSLOG_ADDRESS))?,
+            hostname: System::new().host_name().unwrap_or_else(|| "-".to_string()),
+        }),
+    };
+
+    Ok(sink)
+}
+
+fn http_client() -> Result<Client, reqwest::Error> {
+    Client::builder()
+        .timeout(Duration::from_secs(HTTP_TIMEOUT_SECONDS))
+        .build()
+}
+
+async fn send_request(request: RequestBuilder) -> Result<(), Box<dyn std::error::Error>> {
+    let response = request.send().await?;
+    let status = response.status();
+
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        let body: String = body.trim().chars().take(200).collect();
+        return Err(format!("HTTP {}: {}", status, body).into());
+    }
+
+    Ok(())
+}
+
+// POSTs the event as JSON, with a rendered title and text for convenience
+pub struct WebhookSink {
+    name: String,
+    client: Client,
+    url: Url,
+    headers: HeaderMap,
+}
+
+#[async_trait]
+impl Notifier for WebhookSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn notify(&self, event: &DiscordEvent) -> Result<(), Box<dyn std::error::Error>> {
+        let mut payload = serde_json::to_value(event)?;
+        payload["title"] = event.event_type.title().into();
+        payload["text"] = notifier::render_text(event).into();
+        payload["severity"] = event.severity().as_str().into();
+
+        send_request(self.client.post(self.url.clone()).headers(self.headers.clone()).json(&payload)).await
+    }
+}
+
+// Slack incoming webhook
+pub struct SlackSink {
+    name: String,
+    client: Client,
+    url: Url,
+}
+
+#[async_trait]
+impl Notifier for SlackSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn notify(&self, event: &DiscordEvent) -> Result<(), Box<dyn std::error::Error>> {
+        let payload = serde_json::json!({
+            "text": format!("*{}*\n{}", event.event_type.title(), notifier::render_text(event))
+        });
+
+        send_request(self.client.post(self.url.clone()).json(&payload)).await
+    }
+}
+
+// Posts an m.notice to a room the access token's user has joined
+pub struct MatrixSink {
+    name: String,
+    client: Client,
+    homeserver: Url,
+    token: String,
+    room_id: String,
+}
+
+#[async_trait]
+impl Notifier for MatrixSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn notify(&self, event: &DiscordEvent) -> Result<(), Box<dyn std::error::Error>> {
+        let transaction_id = uuid::Uuid::new_v4().to_string();
+
+        let mut url = self.homeserver.clone();
+        url.path_segments_mut()
+            .map_err(|_| "homeserver url cannot have a path")?
+            .pop_if_empty()
+            .extend(["_matrix", "client", "v3", "rooms", &self.room_id, "send", "m.room.message", &transaction_id]);
+
+        let payload = serde_json::json!({
+            "msgtype": "m.notice",
+            "body": format!("{}\n{}", event.event_type.title(), notifier::render_text(event))
+        });
+
+        send_request(self.client.put(url).bearer_auth(&self.token).json(&payload)).await
+    }
+}
+
+// Uses ntfy's JSON publishing so titles aren't limited to what fits in a header
+pub struct NtfySink {
+    name: String,
+    client: Client,
+    url: Url,
+    topic: String,
+    token: Option<String>,
+    priority: Option<u8>,
+}
+
+#[async_trait]
+impl Notifier for NtfySink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn notify(&self, event: &DiscordEvent) -> Result<(), Box<dyn std::error::Error>> {
+        let priority = self.priority.unwrap_or(match event.severity() {
+            Severity::Info => 2,
+            Severity::Notice => 3,
+            Severity::Warning => 4,
+            Severity::Critical => 5,
+        });
+
+        let payload = serde_json::json!({
+            "topic": self.topic,
+            "title": event.event_type.title(),
+            "message": notifier::render_text(event),
+            "priority": priority
+        });
+
+        let mut request = self.client.post(self.url.clone()).json(&payload);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+
+        send_request(request).await
+    }
+}
+
+pub struct GotifySink {
+    name: String,
+    client: Client,
+    url: Url,
+    token: String,
+    priority: Option<u8>,
+}
+
+#[async_trait]
+impl Notifier for GotifySink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn notify(&self, event: &DiscordEvent) -> Result<(), Box<dyn std::error::Error>> {
+        let priority = self.priority.unwrap_or(match event.severity() {
+            Severity::Info => 2,
+            Severity::Notice => 4,
+            Severity::Warning => 6,
+            Severity::Critical => 8,
+        });
+
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .map_err(|_| "gotify url cannot have a path")?
+            .pop_if_empty()
+            .push("message");
+
+        let payload = serde_json::json!({
+            "title": event.event_type.title(),
+            "message": notifier::render_text(event),
+            "priority": priority
+        });
+
+        send_request(self.client.post(url).header("X-Gotify-Key", &self.token).json(&payload)).await
+    }
+}
+
+trait SmtpStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> SmtpStream for T {}
+
+// A minimal SMTP client: one connection per message, optional STARTTLS or implicit
+// TLS, and AUTH PLAIN or LOGIN when credentials are configured
+pub struct EmailSink {
+    name: String,
+    host: String,
+    port: u16,
+    tls: SmtpTls,
+    credentials: Option<(String, String)>,
+    from: String,
+    to: Vec<String>,
+}
+
+impl EmailSink {
+    async fn send(&self, message: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let tcp = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let stream: Box<dyn SmtpStream> = match self.tls {
+            SmtpTls::Tls => Box::new(self.tls_connect(Box::new(tcp)).await?),
+            SmtpTls::None | SmtpTls::Starttls => Box::new(tcp),
+        };
+
+        let mut smtp = SmtpConnection::new(stream);
+        smtp.expect(&[220]).await?;
+        let mut extensions = smtp.command("EHLO localhost", &[250]).await?;
+
+        if self.tls == SmtpTls::Starttls {
+            if !extensions.iter().any(|extension| extension.eq_ignore_ascii_case("STARTTLS")) {
+                return Err(format!("{} does not offer STARTTLS", self.host).into());
+            }
+
+            smtp.command("STARTTLS", &[220]).await?;
+            smtp = SmtpConnection::new(Box::new(self.tls_connect(smtp.into_inner()).await?));
+            extensions = smtp.command("EHLO localhost", &[250]).await?;
+        }
+
+        if let Some((username, password)) = &self.credentials {
+            let mechanisms: Vec<String> = extensions.iter()
+                .filter_map(|extension| {
+                    let upper = extension.to_ascii_uppercase();
+                    upper.strip_prefix("AUTH").map(|rest| rest.trim_start_matches(['=', ' ']).to_string())
+                })
+                .flat_map(|rest| rest.split_whitespace().map(str::to_string).collect::<Vec<_>>())
+                .collect();
+
+            if mechanisms.iter().any(|mechanism| mechanism == "PLAIN") {
+                let credentials = general_purpose::STANDARD.encode(format!("\0{}\0{}", username, password));
+                smtp.command(&format!("AUTH PLAIN {}", credentials), &[235]).await?;
+            } else if mechanisms.iter().any(|mechanism| mechanism == "LOGIN") {
+                smtp.command("AUTH LOGIN", &[334]).await?;
+                smtp.command(&general_purpose::STANDARD.encode(username), &[334]).await?;
+                smtp.command(&general_purpose::STANDARD.encode(password), &[235]).await?;
+            } else {
+                return Err(format!("{} offers no supported AUTH mechanism", self.host).into());
+            }
+        }
+
+        smtp.command(&format!("MAIL FROM:<{}>", mailbox(&self.from)), &[250]).await?;
+        for recipient in &self.to {
+            smtp.command(&format!("RCPT TO:<{}>", mailbox(recipient)), &[250, 251]).await?;
+        }
+        smtp.command("DATA", &[354]).await?;
+        smtp.command(&format!("{}\r\n.", message), &[250]).await?;
+
+        // The message is accepted; a failed QUIT changes nothing
+        let _ = smtp.command("QUIT", &[221]).await;
+        Ok(())
+    }
+
+    async fn tls_connect(&self, stream: Box<dyn SmtpStream>) -> Result<tokio_rustls::client::TlsStream<Box<dyn SmtpStream>>, Box<dyn std::error::Error>> {
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+        }));
+
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let server_name = ServerName::try_from(self.host.as_str())?;
+
+        Ok(TlsConnector::from(Arc::new(config)).connect(server_name, stream).await?)
+    }
+
+    fn message(&self, event: &DiscordEvent) -> String {
+        // RFC 2047 encoding keeps anything but printable ASCII, line breaks included, out
+        // of the raw header
+        let subject = format!("[{}] {}", event.device_alias, event.event_type.title());
+        let subject = if subject.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+            subject
+        } else {
+            format!("=?UTF-8?B?{}?=", general_purpose::STANDARD.encode(subject))
+        };
+
+        // Base64 keeps the body 7-bit clean and free of lines starting with "."
+        let body = notifier::render_text(event).replace('\n', "\r\n");
+        let body = general_purpose::STANDARD.encode(body);
+        let body: Vec<&str> = body.as_bytes()
+            .chunks(76)
+            .map(|line| std::str::from_utf8(line).unwrap_or_default())
+            .collect();
+
+        [
+            format!("From: {}", self.from),
+            format!("To: {}", self.to.join(", ")),
+            format!("Subject: {}", subject),
+            format!("Date: {}", event.timestamp.to_rfc2822()),
+            format!("Message-ID: <{}@{}>", uuid::Uuid::new_v4(), APP_NAME),
+            "MIME-Version: 1.0".to_string(),
+            "Content-Type: text/plain; charset=utf-8".to_string(),
+            "Content-Transfer-Encoding: base64".to_string(),
+            String::new(),
+            body.join("\r\n"),
+        ].join("\r\n")
+    }
+}
+
+#[async_trait]
+impl Notifier for EmailSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn notify(&self, event: &DiscordEvent) -> Result<(), Box<dyn std::error::Error>> {
+        let message = self.message(event);
+
+        tokio::time::timeout(Duration::from_secs(SMTP_TIMEOUT_SECONDS), self.send(&message)).await
+            .map_err(|_| format!("SMTP session with {} timed out", self.host))?
+    }
+}
+
+// "Name <user@example.com>" -> "user@example.com"
+fn mailbox(address: &str) -> &str {
+    match (address.rfind('<'), address.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &address[start + 1..end],
+        _ => address.trim(),
+    }
+}
+
+struct SmtpConnection {
+    stream: BufStream<Box<dyn SmtpStream>>,
+}
+
+impl SmtpConnection {
+    fn new(stream: Box<dyn SmtpStream>) -> Self {
+        Self { stream: BufStream::new(stream) }
+    }
+
+    fn into_inner(self) -> Box<dyn SmtpStream> {
+        self.stream.into_inner()
+    }
+
+    async fn command(&mut self, line: &str, expected: &[u16]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        self.stream.write_all(format!("{}\r\n", line).as_bytes()).await?;
+        self.stream.flush().await?;
+        self.expect(expected).await
+    }
+
+    // Reads a possibly multi-line reply and returns its text lines
+    async fn expect(&mut self, expected: &[u16]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut lines = Vec::new();
+
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err("SMTP server closed the connection".into());
+            }
+
+            let line = line.trim_end();
+            let code: u16 = line.get(..3)
+                .and_then(|code| code.parse().ok())
+                .ok_or_else(|| format!("Malformed SMTP reply: {}", line))?;
+            lines.push(line.get(4..).unwrap_or_default().to_string());
+
+            if line.as_bytes().get(3) == Some(&b'-') {
+                continue;
+            }
+
+            if !expected.contains(&code) {
+                return Err(format!("SMTP server replied {} {}", code, lines.join(" ")).into());
+            }
+
+            return Ok(lines);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum SyslogTarget {
+    Udp(String),
+    Tcp(String),
+    Unix(String),
+}
+
+impl SyslogTarget {
+    fn parse(address: &str) -> Result<Self, String> {
+        if let Some(host) = address.strip_prefix("udp://") {
+            Ok(SyslogTarget::Udp(host.to_string()))
+        } else if let Some(host) = address.strip_prefix("tcp://") {
+            Ok(SyslogTarget::Tcp(host.to_string()))
+        } else if let Some(path) = address.strip_prefix("unix://") {
+            if cfg!(unix) {
+                Ok(SyslogTarget::Unix(path.to_string()))
+            } else {
+                Err("unix:// syslog addresses are only supported on Unix".to_string())
+            }
+        } else {
+            Err(format!("invalid syslog address {}, expected udp://, tcp:// or unix://", address))
+        }
+    }
+}
+
+// RFC 5424 messages over the network, the traditional "<pri>tag[pid]: msg" form to the
+// local socket, which is what syslog daemons and journald expect there
+pub struct SyslogSink {
+    name: String,
+    target: SyslogTarget,
+    hostname: String,
+}
+
+impl SyslogSink {
+    fn priority(event: &DiscordEvent) -> u8 {
+        let severity = match event.severity() {
+            Severity::Critical => 2,
+            Severity::Warning => 4,
+            Severity::Notice => 5,
+            Severity::Info => 6,
+        };
+
+        SYSLOG_FACILITY_AUTH * 8 + severity
+    }
+
+    fn text(event: &DiscordEvent) -> String {
+        format!("{}: {}", event.event_type.title(), notifier::render_text(event).replace('\n', "; "))
+    }
+
+    fn rfc5424(&self, event: &DiscordEvent) -> String {
+        format!(
+            "<{}>1 {} {} {} {} {:?} - {}",
+            Self::priority(event),
+            event.timestamp.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            self.hostname,
+            APP_NAME,
+            std::process::id(),
+            event.event_type,
+            Self::text(event)
+        )
+    }
+
+    fn local(event: &DiscordEvent) -> String {
+        format!("<{}>{}[{}]: {}", Self::priority(event), APP_NAME, std::process::id(), Self::text(event))
+    }
+}
+
+#[async_trait]
+impl Notifier for SyslogSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn notify(&self, event: &DiscordEvent) -> Result<(), Box<dyn std::error::Error>> {
+        match &self.target {
+            SyslogTarget::Udp(address) => {
+                let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+                socket.send_to(self.rfc5424(event).as_bytes(), address.as_str()).await?;
+            }
+            SyslogTarget::Tcp(address) => {
+                // RFC 6587 octet counting
+                let message = self.rfc5424(event);
+                let mut stream = TcpStream::connect(address.as_str()).await?;
+                stream.write_all(format!("{} {}", message.len(), message).as_bytes()).await?;
+                stream.shutdown().await?;
+            }
+            #[cfg(unix)]
+            SyslogTarget::Unix(path) => {
+                let socket = tokio::net::UnixDatagram::unbound()?;
+                socket.send_to(Self::local(event).as_bytes(), path).await?;
+            }
+            #[cfg(not(unix))]
+            SyslogTarget::Unix(_) => return Err("unix:// syslog addresses are only supported on Unix".into()),
+        }
+
+        Ok(())
+    }
+}
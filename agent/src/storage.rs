@@ -1,22 +1,191 @@
-use crate::config::Config;
-use crate::security::SecurityManager;
+use crate::config::{Config, SharedConfig};
+use crate::keystore;
+use crate::security::{self, SecurityManager};
+use base64::{Engine as _, engine::general_purpose};
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::collections::VecDeque;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tracing::{info, warn, error, debug};
+use tracing::{info, warn, debug};
 use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::io::Write;
 
+// Storage key of the audit log, which like every stored file is encrypted with its key
+// name as associated data
+const AUDIT_LOG_KEY: &str = "audit_log";
+
+// The audit log is only ever appended to, one encrypted entry per line in base64. The
+// whole log used to be a single encrypted blob under AUDIT_LOG_KEY.
+const AUDIT_LOG_FILE: &str = "audit_log.log";
+
+// Storage key of the newest entry's sequence number and hash, which is rewritten after
+// every append so that entries cut off the end of the log are noticed. Nothing outside the
+// storage directory records the head, so putting back an older copy of it along with the
+// log cut to match goes unnoticed.
+const AUDIT_HEAD_KEY: &str = "audit_head";
+
+// prev_hash of the first entry
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// Clearing the log appends this entry, linked to the last cleared one
+pub const AUDIT_LOG_CLEARED: &str = "audit_log_cleared";
+
+// Where the app approval password lived before the password store: a salted SHA-256
+const LEGACY_LOCAL_PASSWORD_KEY: &str = "local_password";
+
+// The password that approves password-gated apps on this device
+pub const LOCAL_PASSWORD: &str = "local";
+
+// Each password is stored as its Argon2id PHC string under password_<name>
+fn password_key(name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("Invalid password name {:?}: use letters, digits, - and _", name).into());
+    }
+    Ok(format!("password_{}", name))
+}
+
+#[derive(Deserialize)]
+struct LegacyPasswordRecord {
+    salt: String,
+    hash: String,
+}
+
+// Entries form a hash chain: prev_hash is the hash of the entry before and hmac signs
+// everything else under a key derived from the master key, so an entry cannot be edited,
+// dropped or moved without breaking the chain from that point on
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuditLogEntry {
+    #[serde(default)]
+    pub sequence: u64,
     pub timestamp: DateTime<Utc>,
     pub event_type: String,
     pub user: Option<String>,
     pub details: serde_json::Value,
     pub severity: LogSeverity,
     pub source: String,
+    #[serde(default)]
+    pub prev_hash: String,
+    #[serde(default)]
+    pub hmac: String,
+}
+
+// The first entry at which an audit log fails verification
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChainBreak {
+    // Line number in the log file, counted from 1
+    Unreadable { line: usize },
+    Tampered { sequence: u64 },
+    // Entries from expected on are gone; found is the next entry left, if any
+    Missing { expected: u64, found: Option<u64> },
+    // The entry does not follow the one stored before it
+    BrokenLink { sequence: u64 },
+}
+
+impl std::fmt::Display for ChainBreak {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChainBreak::Unreadable { line } => write!(f, "Audit log line {} cannot be decrypted or parsed", line),
+            ChainBreak::Tampered { sequence } => write!(f, "Audit log entry {} has been modified", sequence),
+            ChainBreak::Missing { expected, found: Some(found) } => write!(f, "Audit log entries {} to {} are missing", expected, found - 1),
+            ChainBreak::Missing { expected, found: None } => write!(f, "Audit log entries from {} on are missing", expected),
+            ChainBreak::BrokenLink { sequence } => write!(f, "Audit log entry {} does not follow the entry before it", sequence),
+        }
+    }
+}
+
+impl std::error::Error for ChainBreak {}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct AuditHead {
+    sequence: u64,
+    hash: String,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Everything the HMAC covers, in a fixed order
+fn signed_fields(entry: &AuditLogEntry) -> Result<Vec<u8>, serde_json::Error> {
+    serde_json::to_vec(&(
+        entry.sequence,
+        &entry.timestamp,
+        &entry.event_type,
+        &entry.user,
+        &entry.details,
+        &entry.severity,
+        &entry.source,
+        &entry.prev_hash,
+    ))
+}
+
+fn entry_hmac(entry: &AuditLogEntry, key: &[u8]) -> Result<Hmac<Sha256>, Box<dyn std::error::Error>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)?;
+    mac.update(&signed_fields(entry)?);
+    Ok(mac)
+}
+
+// What the next entry's prev_hash must be
+pub fn entry_hash(entry: &AuditLogEntry) -> Result<String, Box<dyn std::error::Error>> {
+    let mut hasher = Sha256::new();
+    hasher.update(signed_fields(entry)?);
+    hasher.update(entry.hmac.as_bytes());
+    Ok(hex(&hasher.finalize()))
+}
+
+// Numbers and links an entry to the one before it, None at the start of the log, and signs it
+pub fn chain_entry(entry: &mut AuditLogEntry, previous: Option<&AuditLogEntry>, key: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    match previous {
+        Some(previous) => {
+            entry.sequence = previous.sequence + 1;
+            entry.prev_hash = entry_hash(previous)?;
+        }
+        None => {
+            entry.sequence = 0;
+            entry.prev_hash = GENESIS_HASH.to_string();
+        }
+    }
+
+    entry.hmac = hex(&entry_hmac(entry, key)?.finalize().into_bytes());
+    Ok(())
+}
+
+// Checks entries in stored order and returns how many there are, or the first one that
+// was modified, removed or moved
+pub fn verify_audit_chain(entries: &[AuditLogEntry], key: &[u8]) -> Result<usize, ChainBreak> {
+    let mut previous: Option<&AuditLogEntry> = None;
+
+    for entry in entries {
+        let expected = entry_hmac(entry, key).map(|mac| hex(&mac.finalize().into_bytes()));
+        if !expected.is_ok_and(|expected| security::constant_time_eq(expected.as_bytes(), entry.hmac.as_bytes())) {
+            return Err(ChainBreak::Tampered { sequence: entry.sequence });
+        }
+
+        match previous {
+            None if entry.sequence == 0 && entry.prev_hash != GENESIS_HASH => {
+                return Err(ChainBreak::BrokenLink { sequence: entry.sequence });
+            }
+            None if entry.sequence == 0 => {}
+            None => return Err(ChainBreak::Missing { expected: 0, found: Some(entry.sequence) }),
+            Some(previous) => {
+                let expected = previous.sequence + 1;
+                if entry.sequence > expected {
+                    return Err(ChainBreak::Missing { expected, found: Some(entry.sequence) });
+                }
+                if entry.sequence != expected || entry_hash(previous).ok().as_deref() != Some(entry.prev_hash.as_str()) {
+                    return Err(ChainBreak::BrokenLink { sequence: entry.sequence });
+                }
+            }
+        }
+
+        previous = Some(entry);
+    }
+
+    Ok(entries.len())
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,28 +197,42 @@ pub enum LogSeverity {
 }
 
 pub struct SecureStorage {
-    config: Arc<RwLock<Config>>,
+    config: SharedConfig,
     security: Arc<SecurityManager>,
     audit_log: Arc<RwLock<VecDeque<AuditLogEntry>>>,
     max_log_entries: usize,
+    // Set by initialize_in; otherwise the storage directory under the config directory
+    storage_dir: Option<PathBuf>,
+    // Held for reading from encryption until the file is written, and for writing by key
+    // rotation, so nothing lands on disk under a key that rotation has retired
+    writes: RwLock<()>,
 }
 
 impl SecureStorage {
     pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
         Ok(Self {
-            config: Arc::new(RwLock::new(Config::default())),
-            security: Arc::new(SecurityManager::new(&Config::default())?),
+            config: Config::default().into_shared(),
+            security: Arc::new(SecurityManager::new(Config::default().into_shared())?),
             audit_log: Arc::new(RwLock::new(VecDeque::new())),
             max_log_entries: 10000,
+            storage_dir: None,
+            writes: RwLock::new(()),
         })
     }
 
-    pub async fn initialize(&mut self, config: Config, security: SecurityManager) -> Result<(), Box<dyn std::error::Error>> {
-        self.config = Arc::new(RwLock::new(config));
+    pub async fn initialize(&mut self, config: SharedConfig, security: SecurityManager) -> Result<(), Box<dyn std::error::Error>> {
+        let config_dir = Config::get_config_dir()?;
+        self.initialize_in(&config_dir, config, security).await
+    }
+
+    // Keeps keys and stored data under dir rather than the config directory
+    pub async fn initialize_in(&mut self, dir: &Path, config: SharedConfig, security: SecurityManager) -> Result<(), Box<dyn std::error::Error>> {
+        self.config = config;
         self.security = Arc::new(security);
+        self.storage_dir = Some(dir.join("storage"));
         
         // Initialize encryption
-        self.security.initialize_encryption().await?;
+        self.security.open_keyring(&dir.join("keys")).await?;
         
         // Load existing audit logs
         self.load_audit_logs().await?;
@@ -65,29 +248,145 @@ impl SecureStorage {
             return Ok(());
         }
 
-        let entry = AuditLogEntry {
+        let entry = self.new_audit_entry(event_type, details).await?;
+        let key = self.security.audit_key().await?;
+
+        // The lock is held until the entry is on disk, so entries are written in chain order
+        let mut audit_log = self.audit_log.write().await;
+        let entry = self.append_audit_entry(entry, audit_log.back(), &key).await?;
+        audit_log.push_back(entry.clone());
+        
+        // Only the newest entries are kept in memory; the file keeps them all
+        while audit_log.len() > self.max_log_entries {
+            audit_log.pop_front();
+        }
+        
+        debug!("Audit event logged: {} #{} - {}", event_type, entry.sequence, entry.timestamp);
+        Ok(())
+    }
+
+    async fn new_audit_entry(&self, event_type: &str, details: &serde_json::Value) -> Result<AuditLogEntry, Box<dyn std::error::Error>> {
+        Ok(AuditLogEntry {
+            sequence: 0,
             timestamp: Utc::now(),
             event_type: event_type.to_string(),
             user: self.get_current_user().await?,
             details: details.clone(),
             severity: self.determine_severity(event_type),
             source: "agent".to_string(),
+            prev_hash: String::new(),
+            hmac: String::new(),
+        })
+    }
+
+    // Chains the entry to previous and appends it to the log file
+    async fn append_audit_entry(&self, mut entry: AuditLogEntry, previous: Option<&AuditLogEntry>, key: &[u8]) -> Result<AuditLogEntry, Box<dyn std::error::Error>> {
+        chain_entry(&mut entry, previous, key)?;
+        let line = self.encrypt_audit_entry(&entry).await?;
+        
+        let storage_path = self.get_storage_path()?;
+        fs::create_dir_all(&storage_path)?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(storage_path.join(AUDIT_LOG_FILE))?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        self.store_audit_head(&entry).await?;
+        
+        Ok(entry)
+    }
+
+    async fn store_audit_head(&self, newest: &AuditLogEntry) -> Result<(), Box<dyn std::error::Error>> {
+        let head = AuditHead { sequence: newest.sequence, hash: entry_hash(newest)? };
+        self.store_encrypted_data(AUDIT_HEAD_KEY, &serde_json::to_vec(&head)?).await
+    }
+
+    async fn load_audit_head(&self) -> Result<Option<AuditHead>, Box<dyn std::error::Error>> {
+        match self.retrieve_encrypted_data(AUDIT_HEAD_KEY).await? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn encrypt_audit_entry(&self, entry: &AuditLogEntry) -> Result<String, Box<dyn std::error::Error>> {
+        let data = serde_json::to_vec(entry)?;
+        let encrypted = self.security.encrypt_data(&data, AUDIT_LOG_KEY).await?;
+        Ok(format!("{}\n", general_purpose::STANDARD.encode(encrypted)))
+    }
+
+    async fn decrypt_audit_line(&self, line: &str) -> Option<AuditLogEntry> {
+        let encrypted = general_purpose::STANDARD.decode(line).ok()?;
+        let data = self.security.decrypt_data(&encrypted, AUDIT_LOG_KEY).await.ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    // Entries in the log file in stored order. Unreadable lines are skipped; the first one
+    // is returned as its line number and the number of entries read before it.
+    async fn read_audit_file(&self) -> Result<(Vec<AuditLogEntry>, Option<(usize, usize)>), Box<dyn std::error::Error>> {
+        let log_file = self.get_storage_path()?.join(AUDIT_LOG_FILE);
+        let contents = match fs::read_to_string(&log_file) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), None)),
+            Err(e) => return Err(e.into()),
         };
+        
+        let mut entries = Vec::new();
+        let mut unreadable = None;
+        for (index, line) in contents.lines().enumerate().filter(|(_, line)| !line.is_empty()) {
+            match self.decrypt_audit_line(line).await {
+                Some(entry) => entries.push(entry),
+                None => {
+                    unreadable.get_or_insert((index + 1, entries.len()));
+                }
+            }
+        }
+        
+        Ok((entries, unreadable))
+    }
 
-        // Add to memory
-        let mut audit_log = self.audit_log.write().await;
-        audit_log.push_back(entry.clone());
+    // Verifies the log file against the chain and against the stored head and the newest
+    // entry this agent wrote, which catch entries cut off the end of the file. Returns the
+    // number of entries; a ChainBreak error names the first bad one.
+    pub async fn verify_audit_chain(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        let audit_log = self.audit_log.read().await;
+        let entries = self.read_verified_audit_file().await?;
         
-        // Maintain max size
-        while audit_log.len() > self.max_log_entries {
-            audit_log.pop_front();
+        if let Some(newest) = audit_log.back() {
+            let stored = entries.last().map(|entry| entry.sequence);
+            if stored.is_none_or(|stored| stored < newest.sequence) {
+                let expected = stored.map_or(0, |stored| stored + 1);
+                return Err(ChainBreak::Missing { expected, found: None }.into());
+            }
         }
+        
+        Ok(entries.len())
+    }
 
-        // Persist to disk
-        self.persist_audit_logs().await?;
+    // The log file's entries if they form an unbroken chain that reaches the stored head.
+    // The file may run one entry past the head if the agent stopped between the two writes.
+    async fn read_verified_audit_file(&self) -> Result<Vec<AuditLogEntry>, Box<dyn std::error::Error>> {
+        let key = self.security.audit_key().await?;
+        let (entries, unreadable) = self.read_audit_file().await?;
         
-        debug!("Audit event logged: {} - {}", event_type, entry.timestamp);
-        Ok(())
+        if let Some((line, before)) = unreadable {
+            verify_audit_chain(&entries[..before], &key)?;
+            return Err(ChainBreak::Unreadable { line }.into());
+        }
+        verify_audit_chain(&entries, &key)?;
+        
+        let head = match self.load_audit_head().await? {
+            Some(head) => head,
+            None => return Ok(entries),
+        };
+        match entries.iter().rev().find(|entry| entry.sequence == head.sequence) {
+            Some(entry) if entry_hash(entry)? == head.hash => Ok(entries),
+            Some(entry) => Err(ChainBreak::Tampered { sequence: entry.sequence }.into()),
+            None => {
+                let expected = entries.last().map_or(0, |entry| entry.sequence + 1);
+                Err(ChainBreak::Missing { expected, found: None }.into())
+            }
+        }
     }
 
     pub async fn get_audit_logs(&self, limit: Option<usize>, event_type: Option<&str>) -> Result<Vec<AuditLogEntry>, Box<dyn std::error::Error>> {
@@ -102,9 +401,8 @@ impl SecureStorage {
             audit_log.iter().cloned().collect()
         };
 
-        // Sort by timestamp (newest first)
-        filtered_logs.sort_by_key(|entry| entry.timestamp);
-        filtered_logs.reverse();
+        // Newest first
+        filtered_logs.sort_by_key(|entry| std::cmp::Reverse(entry.sequence));
 
         if let Some(limit) = limit {
             filtered_logs.truncate(limit);
@@ -143,24 +441,34 @@ impl SecureStorage {
         }
     }
 
+    // Clears the entries held in memory. The file keeps every entry, and gets a signed
+    // checkpoint so the log's history shows that and when it was cleared.
+    #[allow(dead_code)]
     pub async fn clear_audit_logs(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let key = self.security.audit_key().await?;
         let mut audit_log = self.audit_log.write().await;
-        audit_log.clear();
         
-        // Clear persisted logs
-        self.clear_persisted_logs().await?;
+        let details = serde_json::json!({
+            "cleared_through": audit_log.back().map(|entry| entry.sequence),
+            "cleared_entries": audit_log.len(),
+        });
+        let checkpoint = self.new_audit_entry(AUDIT_LOG_CLEARED, &details).await?;
+        let checkpoint = self.append_audit_entry(checkpoint, audit_log.back(), &key).await?;
+        
+        audit_log.clear();
+        audit_log.push_back(checkpoint);
         
         info!("Audit logs cleared");
         Ok(())
     }
 
     pub async fn store_encrypted_data(&self, key: &str, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-        let encrypted_data = self.security.encrypt_data(data).await?;
-        let storage_path = self.get_storage_path()?;
-        let file_path = storage_path.join(format!("{}.enc", key));
+        let _writing = self.writes.read().await;
+        let encrypted_data = self.security.encrypt_data(data, key).await?;
+        let file_path = self.get_storage_path()?.join(format!("{}.enc", key));
         
-        fs::create_dir_all(&storage_path)?;
-        fs::write(file_path, encrypted_data)?;
+        // A crash mid-write leaves the previous file rather than a truncated one
+        keystore::replace_file(&file_path, &encrypted_data)?;
         
         debug!("Encrypted data stored: {}", key);
         Ok(())
@@ -175,7 +483,13 @@ impl SecureStorage {
         }
         
         let encrypted_data = fs::read(file_path)?;
-        let decrypted_data = self.security.decrypt_data(&encrypted_data).await?;
+        let decrypted_data = self.security.decrypt_data(&encrypted_data, key).await?;
+        
+        // Data written before the versioned format is migrated the first time it is read
+        if keystore::is_legacy(&encrypted_data) {
+            self.store_encrypted_data(key, &decrypted_data).await?;
+            info!("Migrated {} to the current encryption format", key);
+        }
         
         Ok(Some(decrypted_data))
     }
@@ -184,6 +498,7 @@ impl SecureStorage {
         let storage_path = self.get_storage_path()?;
         let file_path = storage_path.join(format!("{}.enc", key));
         
+        let _writing = self.writes.read().await;
         if file_path.exists() {
             // Securely wipe the file
             self.security.secure_wipe_file(file_path.to_str().unwrap()).await?;
@@ -193,6 +508,109 @@ impl SecureStorage {
         Ok(())
     }
 
+    // Moves all stored data, the audit log included, to a fresh data key
+    pub async fn rotate_keys(&self) -> Result<(u32, usize), Box<dyn std::error::Error>> {
+        let storage_path = self.get_storage_path()?;
+        
+        // No audit entries or stored data may be written with the old key while files
+        // are rewritten
+        let audit_log = self.audit_log.write().await;
+        let writing = self.writes.write().await;
+        let (version, files) = self.security.rotate_keys(&storage_path).await?;
+        drop(writing);
+        drop(audit_log);
+        
+        self.log_audit_event("keys_rotated", &serde_json::json!({
+            "key_version": version,
+            "files": files,
+        })).await?;
+        
+        Ok((version, files))
+    }
+
+    pub async fn has_password(&self, name: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let key = password_key(name)?;
+        if self.retrieve_encrypted_data(&key).await?.is_some() {
+            return Ok(true);
+        }
+        Ok(name == LOCAL_PASSWORD && self.retrieve_encrypted_data(LEGACY_LOCAL_PASSWORD_KEY).await?.is_some())
+    }
+
+    // Only for passwords that are not set yet; replacing one goes through change_password
+    pub async fn set_password(&self, name: &str, password: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if self.has_password(name).await? {
+            return Err(format!("The {} password is already set; change it instead", name).into());
+        }
+        
+        self.save_password(name, password).await?;
+        self.log_audit_event("password_set", &serde_json::json!({ "name": name })).await?;
+        info!("Password {} set", name);
+        Ok(())
+    }
+
+    pub async fn change_password(&self, name: &str, current: &str, new: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.verify_password(name, current).await? {
+            self.log_audit_event("password_change_failed", &serde_json::json!({ "name": name })).await?;
+            return Err("The current password is incorrect".into());
+        }
+        
+        self.save_password(name, new).await?;
+        self.log_audit_event("password_changed", &serde_json::json!({ "name": name })).await?;
+        info!("Password {} changed", name);
+        Ok(())
+    }
+
+    // A correct password whose hash was made with older settings is rehashed with the
+    // current ones, so hashes get stronger without anyone having to reset a password
+    pub async fn verify_password(&self, name: &str, password: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let key = password_key(name)?;
+        let stored = self.retrieve_encrypted_data(&key).await?;
+        let hash = match stored {
+            Some(hash) => String::from_utf8(hash)?,
+            None if name == LOCAL_PASSWORD => return self.verify_legacy_local_password(password).await,
+            None => return Err(format!("No {} password has been set", name).into()),
+        };
+        
+        if !self.security.verify_password(password, &hash).await? {
+            return Ok(false);
+        }
+        
+        if security::password_needs_rehash(&hash) {
+            self.save_password(name, password).await?;
+            info!("Upgraded the hash of password {}", name);
+        }
+        Ok(true)
+    }
+
+    // Moves the approval password from its old SHA-256 record into the password store
+    // the first time it is entered correctly
+    async fn verify_legacy_local_password(&self, password: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let data = self.retrieve_encrypted_data(LEGACY_LOCAL_PASSWORD_KEY).await?
+            .ok_or_else(|| format!("No {} password has been set", LOCAL_PASSWORD))?;
+        let record: LegacyPasswordRecord = serde_json::from_slice(&data)?;
+        let salt = general_purpose::STANDARD.decode(&record.salt)?;
+        
+        if !self.security.verify_legacy_password(password, &salt, &record.hash).await? {
+            return Ok(false);
+        }
+        
+        self.save_password(LOCAL_PASSWORD, password).await?;
+        self.delete_encrypted_data(LEGACY_LOCAL_PASSWORD_KEY).await?;
+        info!("Migrated password {} to Argon2id", LOCAL_PASSWORD);
+        Ok(true)
+    }
+
+    async fn save_password(&self, name: &str, password: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if password.is_empty() {
+            return Err("The password must not be empty".into());
+        }
+        
+        let key = password_key(name)?;
+        let hash = self.security.hash_password(password).await?;
+        self.store_encrypted_data(&key, hash.as_bytes()).await
+    }
+
+    #[allow(dead_code)]
     pub async fn get_storage_stats(&self) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
         let audit_log = self.audit_log.read().await;
         let storage_path = self.get_storage_path()?;
@@ -220,55 +638,66 @@ impl SecureStorage {
     }
 
     async fn load_audit_logs(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let storage_path = self.get_storage_path()?;
-        let log_file = storage_path.join("audit_log.enc");
-        
-        if !log_file.exists() {
-            return Ok(());
-        }
+        self.migrate_audit_blob().await?;
         
-        let encrypted_data = fs::read(&log_file)?;
-        let decrypted_data = self.security.decrypt_data(&encrypted_data).await?;
+        // Appending to a broken chain would link new entries to whatever is left, hiding the break
+        let entries = self.read_verified_audit_file().await.map_err(|e| format!(
+            "Audit log failed verification: {}. Move {} and {}.enc out of {} to start a new log",
+            e, AUDIT_LOG_FILE, AUDIT_HEAD_KEY, self.get_storage_path().unwrap_or_default().display()
+        ))?;
         
-        let logs: Vec<AuditLogEntry> = serde_json::from_slice(&decrypted_data)?;
+        // Logs written before the head was stored get one now
+        if let Some(newest) = entries.last() {
+            if self.load_audit_head().await?.is_none() {
+                warn!("Audit log has no stored head, entries removed before now cannot be detected");
+                self.store_audit_head(newest).await?;
+            }
+        }
         
         let mut audit_log = self.audit_log.write().await;
-        for log in logs {
-            audit_log.push_back(log);
-        }
+        let skip = entries.len().saturating_sub(self.max_log_entries);
+        audit_log.extend(entries.into_iter().skip(skip));
         
         info!("Loaded {} audit log entries", audit_log.len());
         Ok(())
     }
 
-    async fn persist_audit_logs(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let audit_log = self.audit_log.read().await;
-        let logs: Vec<_> = audit_log.iter().cloned().collect();
-        
-        let json_data = serde_json::to_vec(&logs)?;
-        let encrypted_data = self.security.encrypt_data(&json_data).await?;
-        
+    // Moves entries from the single encrypted blob the log used to be into the append-only
+    // file, chaining them in their stored order
+    async fn migrate_audit_blob(&self) -> Result<(), Box<dyn std::error::Error>> {
         let storage_path = self.get_storage_path()?;
-        let log_file = storage_path.join("audit_log.enc");
-        
-        fs::create_dir_all(&storage_path)?;
-        fs::write(log_file, encrypted_data)?;
-        
-        Ok(())
-    }
-
-    async fn clear_persisted_logs(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let storage_path = self.get_storage_path()?;
-        let log_file = storage_path.join("audit_log.enc");
+        let blob = storage_path.join(format!("{}.enc", AUDIT_LOG_KEY));
+        if !blob.exists() {
+            return Ok(());
+        }
         
-        if log_file.exists() {
-            self.security.secure_wipe_file(log_file.to_str().unwrap()).await?;
+        // An earlier migration that stopped before removing the blob already wrote the file
+        let log_file = storage_path.join(AUDIT_LOG_FILE);
+        if !log_file.exists() {
+            let encrypted_data = fs::read(&blob)?;
+            let decrypted_data = self.security.decrypt_data(&encrypted_data, AUDIT_LOG_KEY).await?;
+            let legacy: Vec<AuditLogEntry> = serde_json::from_slice(&decrypted_data)?;
+            
+            let key = self.security.audit_key().await?;
+            let mut previous: Option<AuditLogEntry> = None;
+            let mut lines = String::new();
+            for mut entry in legacy {
+                chain_entry(&mut entry, previous.as_ref(), &key)?;
+                lines.push_str(&self.encrypt_audit_entry(&entry).await?);
+                previous = Some(entry);
+            }
+            keystore::replace_file(&log_file, lines.as_bytes())?;
+            
+            info!("Migrated the audit log to an append-only hash chain");
         }
         
-        Ok(())
+        self.delete_encrypted_data(AUDIT_LOG_KEY).await
     }
 
     fn get_storage_path(&self) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        if let Some(storage_dir) = &self.storage_dir {
+            return Ok(storage_dir.clone());
+        }
         let config_dir = Config::get_config_dir()?;
         Ok(config_dir.join("storage"))
     }
@@ -284,24 +713,13 @@ impl SecureStorage {
     fn determine_severity(&self, event_type: &str) -> LogSeverity {
         match event_type {
             "login" | "logout" | "heartbeat" => LogSeverity::Info,
-            "failed_auth" | "rate_limit_exceeded" => LogSeverity::Warning,
-            "command_executed" => LogSeverity::Security,
+            "failed_auth" | "rate_limit_exceeded" | "app_approval_expired" => LogSeverity::Warning,
+            "command_executed" | "command_rejected" | "command_denied" | "replay_rejected" | "replay_cache_unavailable" => LogSeverity::Security,
+            "app_blocked" | "app_approval_required" | "app_approved" | "app_approval_failed" => LogSeverity::Security,
+            "brute_force_detected" | "brute_force_response" | "keys_rotated" => LogSeverity::Security,
+            "password_set" | "password_changed" | "password_change_failed" | "password_check_failed" => LogSeverity::Security,
+            AUDIT_LOG_CLEARED => LogSeverity::Security,
             _ => LogSeverity::Info,
         }
     }
 }
-
-impl Drop for SecureStorage {
-    fn drop(&mut self) {
-        // Ensure logs are persisted on shutdown
-        if let Ok(()) = tokio::runtime::Handle::try_current() {
-            // We're in a tokio context, can spawn a task
-            let storage = self.clone();
-            tokio::spawn(async move {
-                if let Err(e) = storage.persist_audit_logs().await {
-                    error!("Failed to persist audit logs on shutdown: {}", e);
-                }
-            });
-        }
-    }
-}
@@ -1,10 +1,13 @@
+use crate::health::HealthSnapshot;
+use crate::sessions::{self, Session};
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tracing::{info, warn, error, debug};
-use sysinfo::{System, SystemExt, UserExt};
+use tracing::{info, debug};
+use sysinfo::{ComponentExt, CpuExt, DiskExt, Pid, PidExt, ProcessExt, Signal, System, SystemExt};
 use std::collections::HashMap;
-use std::time::{Duration, Instant};
-use chrono::{DateTime, Utc};
+use std::path::PathBuf;
+use std::time::Duration;
+use chrono::Utc;
 
 #[cfg(target_os = "windows")]
 use winapi::um::winuser::LockWorkStation;
@@ -14,15 +17,14 @@ use std::process::Command;
 
 pub struct SystemManager {
     system: Arc<RwLock<System>>,
-    last_users: Arc<RwLock<HashMap<String, UserInfo>>>,
     monitoring: Arc<RwLock<bool>>,
 }
 
 #[derive(Debug, Clone)]
-pub struct UserInfo {
-    pub username: String,
-    pub login_time: DateTime<Utc>,
-    pub is_active: bool,
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub name: String,
+    pub exe: Option<PathBuf>,
 }
 
 impl SystemManager {
@@ -31,7 +33,6 @@ impl SystemManager {
         
         Ok(Self {
             system: Arc::new(RwLock::new(system)),
-            last_users: Arc::new(RwLock::new(HashMap::new())),
             monitoring: Arc::new(RwLock::new(false)),
         })
     }
@@ -42,24 +43,6 @@ impl SystemManager {
         drop(monitoring);
 
         info!("System monitoring started");
-        
-        // Spawn monitoring task
-        let system = self.system.clone();
-        let last_users = self.last_users.clone();
-        let monitoring = self.monitoring.clone();
-        
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_secs(5));
-            
-            while *monitoring.read().await {
-                interval.tick().await;
-                
-                if let Err(e) = Self::check_user_changes(&system, &last_users).await {
-                    error!("Error checking user changes: {}", e);
-                }
-            }
-        });
-
         Ok(())
     }
 
@@ -71,49 +54,9 @@ impl SystemManager {
         Ok(())
     }
 
-    async fn check_user_changes(
-        system: &Arc<RwLock<System>>,
-        last_users: &Arc<RwLock<HashMap<String, UserInfo>>>
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut system = system.write().await;
-        system.refresh_users();
-        
-        let current_users: HashMap<String, UserInfo> = system
-            .users()
-            .iter()
-            .map(|user| {
-                let username = user.name().to_string();
-                let login_time = Utc::now(); // Note: sysinfo doesn't provide exact login time
-                let is_active = user.id() == system.current_user_id();
-                
-                (username.clone(), UserInfo {
-                    username,
-                    login_time,
-                    is_active,
-                })
-            })
-            .collect();
-
-        let mut last_users = last_users.write().await;
-        
-        // Check for new logins
-        for (username, user_info) in &current_users {
-            if !last_users.contains_key(username) {
-                info!("New user login detected: {}", username);
-                // This would trigger a Discord notification
-            }
-        }
-        
-        // Check for logouts
-        for (username, _) in last_users.iter() {
-            if !current_users.contains_key(username) {
-                info!("User logout detected: {}", username);
-                // This would trigger a Discord notification
-            }
-        }
-        
-        *last_users = current_users;
-        Ok(())
+    // Sessions from utmp, i.e. what `who` reports, with their real login times
+    pub async fn active_sessions(&self) -> Result<Vec<Session>, Box<dyn std::error::Error>> {
+        sessions::read_active_sessions()
     }
 
     pub async fn lock_screen(&self) -> Result<(), Box<dyn std::error::Error>> {
@@ -140,7 +83,20 @@ impl SystemManager {
             }
         }
         
-        #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+        #[cfg(target_os = "linux")]
+        {
+            // Asks logind to lock every session; desktop environments honour it
+            let output = std::process::Command::new("loginctl")
+                .args(["lock-sessions"])
+                .output()?;
+                
+            if !output.status.success() {
+                return Err(format!("Failed to lock Linux sessions: {}", 
+                    String::from_utf8_lossy(&output.stderr)).into());
+            }
+        }
+        
+        #[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "linux")))]
         {
             return Err("Screen locking not implemented for this platform".into());
         }
@@ -177,26 +133,21 @@ impl SystemManager {
         }
         
         #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+        return Err("User logout not implemented for this platform".into());
+        
+        #[cfg(any(target_os = "windows", target_os = "macos"))]
         {
-            return Err("User logout not implemented for this platform".into());
+            info!("User logged out successfully");
+            Ok(())
         }
-        
-        info!("User logged out successfully");
-        Ok(())
     }
 
     pub async fn get_system_info(&self) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
         let system = self.system.read().await;
-        let last_users = self.last_users.read().await;
-        
-        let mut users_info = Vec::new();
-        for (_, user_info) in last_users.iter() {
-            users_info.push(serde_json::json!({
-                "username": user_info.username,
-                "login_time": user_info.login_time.to_rfc3339(),
-                "is_active": user_info.is_active
-            }));
-        }
+        let sessions = self.active_sessions().await.unwrap_or_else(|e| {
+            debug!("Session list unavailable: {}", e);
+            Vec::new()
+        });
         
         Ok(serde_json::json!({
             "platform": std::env::consts::OS,
@@ -205,8 +156,8 @@ impl SystemManager {
             "os_version": system.os_version().unwrap_or_else(|| "Unknown".to_string()),
             "total_memory": system.total_memory(),
             "used_memory": system.used_memory(),
-            "cpu_count": system.cpu_count(),
-            "users": users_info,
+            "cpu_count": system.cpus().len(),
+            "users": sessions,
             "timestamp": Utc::now().to_rfc3339()
         }))
     }
@@ -219,6 +170,7 @@ impl SystemManager {
         Ok(username)
     }
 
+    #[allow(dead_code)]
     pub async fn is_screen_locked(&self) -> Result<bool, Box<dyn std::error::Error>> {
         // This is a simplified check - in a real implementation,
         // you'd use platform-specific APIs to check screen lock status
@@ -245,12 +197,14 @@ impl SystemManager {
         }
     }
 
+    #[allow(dead_code)]
     pub async fn get_uptime(&self) -> Result<Duration, Box<dyn std::error::Error>> {
         let system = self.system.read().await;
         let uptime = system.uptime();
         Ok(Duration::from_secs(uptime))
     }
 
+    #[allow(dead_code)]
     pub async fn get_memory_usage(&self) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
         let system = self.system.read().await;
         
@@ -263,6 +217,7 @@ impl SystemManager {
         }))
     }
 
+    #[allow(dead_code)]
     pub async fn get_cpu_usage(&self) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
         let system = self.system.read().await;
         
@@ -276,12 +231,96 @@ impl SystemManager {
         }
         
         Ok(serde_json::json!({
-            "cpu_count": system.cpu_count(),
+            "cpu_count": system.cpus().len(),
             "global_cpu_usage": system.global_cpu_info().cpu_usage(),
             "cores": cpu_usage,
             "timestamp": Utc::now().to_rfc3339()
         }))
     }
+
+    // Refreshes everything the health checks need. CPU usage is measured between two
+    // refreshes, so the first snapshot after startup may report zero.
+    pub async fn health_snapshot(&self) -> Result<HealthSnapshot, Box<dyn std::error::Error>> {
+        let mut system = self.system.write().await;
+        system.refresh_cpu();
+        system.refresh_memory();
+        system.refresh_disks_list();
+        system.refresh_disks();
+        system.refresh_components_list();
+        system.refresh_components();
+        system.refresh_processes();
+
+        let percent = |used: u64, total: u64| if total == 0 { 0.0 } else { used as f64 / total as f64 * 100.0 };
+
+        let disks = system
+            .disks()
+            .iter()
+            .filter(|disk| disk.total_space() > 0)
+            .map(|disk| {
+                let used = disk.total_space().saturating_sub(disk.available_space());
+                (disk.mount_point().to_string_lossy().to_string(), percent(used, disk.total_space()))
+            })
+            .collect();
+
+        let temperatures = system
+            .components()
+            .iter()
+            .filter(|component| component.temperature().is_finite())
+            .map(|component| (component.label().to_string(), component.temperature() as f64))
+            .collect();
+
+        Ok(HealthSnapshot {
+            cpu_percent: system.global_cpu_info().cpu_usage() as f64,
+            memory_percent: percent(system.used_memory(), system.total_memory()),
+            swap_percent: (system.total_swap() > 0).then(|| percent(system.used_swap(), system.total_swap())),
+            load_per_core: system.load_average().one / system.cpus().len().max(1) as f64,
+            disks,
+            temperatures,
+            process_count: system.processes().len(),
+        })
+    }
+
+    pub async fn list_processes(&self) -> Result<HashMap<u32, ProcessInfo>, Box<dyn std::error::Error>> {
+        let mut system = self.system.write().await;
+        system.refresh_processes();
+
+        Ok(system
+            .processes()
+            .iter()
+            .map(|(pid, process)| {
+                let exe = process.exe();
+                (pid.as_u32(), ProcessInfo {
+                    pid: pid.as_u32(),
+                    name: process.name().to_string(),
+                    exe: if exe.as_os_str().is_empty() { None } else { Some(exe.to_path_buf()) },
+                })
+            })
+            .collect())
+    }
+
+    pub async fn kill_process(&self, pid: u32) -> Result<(), Box<dyn std::error::Error>> {
+        self.signal_process(pid, Signal::Kill).await
+    }
+
+    pub async fn suspend_process(&self, pid: u32) -> Result<(), Box<dyn std::error::Error>> {
+        self.signal_process(pid, Signal::Stop).await
+    }
+
+    pub async fn resume_process(&self, pid: u32) -> Result<(), Box<dyn std::error::Error>> {
+        self.signal_process(pid, Signal::Continue).await
+    }
+
+    async fn signal_process(&self, pid: u32, signal: Signal) -> Result<(), Box<dyn std::error::Error>> {
+        let system = self.system.read().await;
+        let process = system.process(Pid::from_u32(pid))
+            .ok_or_else(|| format!("Process {} not found", pid))?;
+
+        match process.kill_with(signal) {
+            Some(true) => Ok(()),
+            Some(false) => Err(format!("Failed to send {:?} to process {}", signal, pid).into()),
+            None => Err(format!("{:?} is not supported on this platform", signal).into()),
+        }
+    }
 }
 
 impl Drop for SystemManager {
@@ -0,0 +1,32 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+// Shared by the command API and the relay binary. Keys may be PKCS#8 or RSA PEM.
+pub fn load_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, Box<dyn std::error::Error>> {
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", cert_path).into());
+    }
+
+    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?))?;
+    if keys.is_empty() {
+        keys = rustls_pemfile::rsa_private_keys(&mut BufReader::new(File::open(key_path)?))?;
+    }
+
+    let key = keys.into_iter().next()
+        .ok_or_else(|| format!("No private key found in {}", key_path))?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, PrivateKey(key))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
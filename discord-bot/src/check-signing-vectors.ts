@@ -0,0 +1,26 @@
+import fs from 'fs';
+import path from 'path';
+import { canonicalPayload, signCommand } from './signing';
+
+// Checks the bot's signer against the agent's golden vectors
+const vectorsPath = path.resolve(__dirname, '../../tests/fixtures/signing_vectors.json');
+const vectors = JSON.parse(fs.readFileSync(vectorsPath, 'utf8'));
+
+let failures = 0;
+
+for (const vector of vectors) {
+    const { signature, ...command } = vector.command;
+    const canonical = canonicalPayload(command);
+    const computed = signCommand(command, vector.secret);
+    
+    if (canonical !== vector.canonical || computed !== vector.signature || signature !== vector.signature) {
+        console.error(`Signing vector mismatch for command ${command.command_id}`);
+        failures++;
+    }
+}
+
+if (failures > 0) {
+    process.exit(1);
+}
+
+console.log(`${vectors.length} signing vectors verified`);
@@ -1,8 +1,9 @@
-import { Client, GatewayIntentBits, Partials, Collection, Message, EmbedBuilder, ActionRowBuilder, ButtonBuilder, ButtonStyle } from 'discord.js';
+import { Client, GatewayIntentBits, Partials, Collection, Message, EmbedBuilder, ActionRowBuilder, ButtonBuilder, ButtonStyle, GuildMember, APIInteractionGuildMember } from 'discord.js';
 import { config } from 'dotenv';
 import winston from 'winston';
 import axios from 'axios';
 import crypto from 'crypto';
+import { SIGNATURE_VERSION, SignedCommand, apiToken, signCommand } from './signing';
 
 // Load environment variables
 config();
@@ -30,6 +31,20 @@ const BOT_TOKEN = process.env.DISCORD_BOT_TOKEN;
 const ALLOWED_USERS = process.env.ALLOWED_USERS?.split(',') || [];
 const ALLOWED_ROLES = process.env.ALLOWED_ROLES?.split(',') || [];
 const HMAC_SECRET = process.env.HMAC_SECRET || 'default-secret-change-in-production';
+const DEVICE_ENDPOINTS = parseDeviceEndpoints(process.env.DEVICE_ENDPOINTS || '');
+const DEVICE_IDS = parseDeviceEndpoints(process.env.DEVICE_IDS || '');
+const RELAY_URL = process.env.RELAY_URL?.replace(/\/+$/, '');
+const RELAY_TOKEN = process.env.RELAY_TOKEN || '';
+const COMMAND_TIMEOUT_MS = (parseInt(process.env.COMMAND_TIMEOUT_SECONDS || '30', 10) || 30) * 1000;
+
+// Agent-side CommandType names
+const COMMAND_TYPES: Record<string, string> = {
+    lock: 'Lock',
+    logout: 'Logout',
+    ping: 'Ping',
+    status: 'Status',
+    approve: 'ApproveApp'
+};
 
 if (!BOT_TOKEN) {
     logger.error('DISCORD_BOT_TOKEN is required');
@@ -68,7 +83,7 @@ const lockCommand: Command = {
         const deviceAlias = args[0] || 'default';
         
         try {
-            const command = createSignedCommand('lock', deviceAlias);
+            const command = createSignedCommand('lock', deviceAlias, message.author.id, allowedRoleIds(message.member));
             const response = await sendCommandToDevice(deviceAlias, command);
             
             const embed = new EmbedBuilder()
@@ -99,7 +114,7 @@ const statusCommand: Command = {
         const deviceAlias = args[0] || 'default';
         
         try {
-            const command = createSignedCommand('status', deviceAlias);
+            const command = createSignedCommand('status', deviceAlias, message.author.id, allowedRoleIds(message.member));
             const response = await sendCommandToDevice(deviceAlias, command);
             
             if (response.success) {
@@ -136,7 +151,7 @@ const pingCommand: Command = {
         const deviceAlias = args[0] || 'default';
         
         try {
-            const command = createSignedCommand('ping', deviceAlias);
+            const command = createSignedCommand('ping', deviceAlias, message.author.id, allowedRoleIds(message.member));
             const response = await sendCommandToDevice(deviceAlias, command);
             
             const embed = new EmbedBuilder()
@@ -187,6 +202,39 @@ const logoutCommand: Command = {
     }
 };
 
+const approveCommand: Command = {
+    name: 'approve',
+    description: 'Approve a suspended application launch',
+    usage: '!approve <device_alias> <pid>',
+    requiresAuth: true,
+    execute: async (message: Message, args: string[]) => {
+        const deviceAlias = args[0];
+        const pid = parseInt(args[1] || '', 10);
+        
+        if (!deviceAlias || !Number.isInteger(pid) || pid <= 0) {
+            await message.reply('Usage: !approve <device_alias> <pid>');
+            return;
+        }
+        
+        try {
+            const command = createSignedCommand('approve', deviceAlias, message.author.id, allowedRoleIds(message.member), { pid });
+            const response = await sendCommandToDevice(deviceAlias, command);
+            
+            const embed = new EmbedBuilder()
+                .setTitle(response.success ? '🔓 Launch Approved' : '❌ Approval Failed')
+                .setDescription(response.message)
+                .setColor(response.success ? 0x00ff00 : 0xff0000)
+                .setTimestamp();
+            
+            await message.reply({ embeds: [embed] });
+            
+        } catch (error) {
+            logger.error('Approve command failed:', error);
+            await message.reply('❌ Failed to send approval to device');
+        }
+    }
+};
+
 const helpCommand: Command = {
     name: 'help',
     description: 'Show available commands',
@@ -201,6 +249,7 @@ const helpCommand: Command = {
                 { name: '!status [device]', value: 'Get device status', inline: true },
                 { name: '!ping [device]', value: 'Test device connectivity', inline: true },
                 { name: '!logout [device]', value: 'Logout current user', inline: true },
+                { name: '!approve <device> <pid>', value: 'Approve a suspended app launch', inline: true },
                 { name: '!help', value: 'Show this help message', inline: true }
             )
             .setColor(0x0088ff);
@@ -214,6 +263,7 @@ commands.set('lock', lockCommand);
 commands.set('status', statusCommand);
 commands.set('ping', pingCommand);
 commands.set('logout', logoutCommand);
+commands.set('approve', approveCommand);
 commands.set('help', helpCommand);
 
 // Bot event handlers
@@ -255,7 +305,7 @@ client.on('interactionCreate', async (interaction) => {
         const deviceAlias = interaction.customId.replace('confirm_logout_', '');
         
         try {
-            const command = createSignedCommand('logout', deviceAlias);
+            const command = createSignedCommand('logout', deviceAlias, interaction.user.id, allowedRoleIds(interaction.member));
             const response = await sendCommandToDevice(deviceAlias, command);
             
             const embed = new EmbedBuilder()
@@ -296,56 +346,114 @@ function isAuthorized(message: Message): boolean {
     return false;
 }
 
+// Roles the agent should consider; the agent maps these to command allowlists
+function allowedRoleIds(member: GuildMember | APIInteractionGuildMember | null): string[] {
+    if (!member) {
+        return [];
+    }
+    
+    const roleIds = Array.isArray(member.roles) ? member.roles : [...member.roles.cache.keys()];
+    return roleIds.filter(roleId => ALLOWED_ROLES.includes(roleId));
+}
+
+// Parse "alias=https://host:port,alias2=..." into a lookup table
+function parseDeviceEndpoints(value: string): Record<string, string> {
+    const endpoints: Record<string, string> = {};
+    
+    for (const entry of value.split(',')) {
+        const separator = entry.indexOf('=');
+        const alias = entry.slice(0, separator).trim();
+        const url = entry.slice(separator + 1).trim();
+        if (separator > 0 && url) {
+            endpoints[alias] = url.replace(/\/+$/, '');
+        }
+    }
+    
+    return endpoints;
+}
+
 // Create signed command
-function createSignedCommand(commandType: string, deviceAlias: string): any {
-    const timestamp = Date.now();
-    const commandId = crypto.randomUUID();
+function createSignedCommand(
+    commandType: string,
+    deviceAlias: string,
+    issuerId: string,
+    roles: string[],
+    args: Record<string, unknown> = {}
+): SignedCommand {
+    const deviceId = DEVICE_IDS[deviceAlias];
+    if (!deviceId) {
+        throw new Error(`No device id configured for device ${deviceAlias}`);
+    }
+    
+    const unsigned = {
+        version: SIGNATURE_VERSION,
+        command: COMMAND_TYPES[commandType] || commandType,
+        command_id: crypto.randomUUID(),
+        device_id: deviceId,
+        authorized_user: issuerId,
+        roles,
+        nonce: crypto.randomBytes(16).toString('base64'),
+        timestamp: new Date().toISOString(),
+        arguments: args
+    };
+    
+    return { ...unsigned, signature: signCommand(unsigned, HMAC_SECRET) };
+}
+
+// Send command to the device's command API, or through the relay for devices behind NAT
+async function sendCommandToDevice(deviceAlias: string, command: SignedCommand): Promise<any> {
+    const endpoint = DEVICE_ENDPOINTS[deviceAlias];
+    if (!endpoint) {
+        if (RELAY_URL) {
+            return sendCommandViaRelay(command.device_id, command);
+        }
+        return { success: false, message: `No endpoint configured for device ${deviceAlias}` };
+    }
     
-    const payload = `${commandId}${deviceAlias}${timestamp}`;
-    const signature = crypto.createHmac('sha256', HMAC_SECRET)
-        .update(payload)
-        .digest('base64');
+    logger.info(`Sending command ${command.command_id} to device ${deviceAlias}`);
+    
+    const response = await axios.post(`${endpoint}/v1/commands`, command, {
+        headers: { Authorization: `Bearer ${apiToken(command.device_id, HMAC_SECRET)}` },
+        timeout: COMMAND_TIMEOUT_MS,
+        validateStatus: () => true
+    });
+    
+    if (response.data && typeof response.data.success === 'boolean') {
+        return response.data;
+    }
     
     return {
-        command: commandType,
-        command_id: commandId,
-        authorized_user: deviceAlias,
-        timestamp: new Date(timestamp).toISOString(),
-        signature: signature
+        success: false,
+        message: response.data?.error || `Device returned HTTP ${response.status}`
     };
 }
 
-// Send command to device (placeholder implementation)
-async function sendCommandToDevice(deviceAlias: string, command: any): Promise<any> {
-    // In a real implementation, this would send the command to the actual device
-    // For now, we'll simulate a response
+// Queue a command on the relay and wait for the agent to post its response
+async function sendCommandViaRelay(deviceId: string, command: SignedCommand): Promise<any> {
+    const headers = { Authorization: `Bearer ${RELAY_TOKEN}` };
+    const deviceUrl = `${RELAY_URL}/v1/devices/${encodeURIComponent(deviceId)}`;
     
-    logger.info(`Sending command to device ${deviceAlias}:`, command);
+    logger.info(`Queueing command ${command.command_id} on relay for device ${deviceId}`);
+    await axios.post(`${deviceUrl}/commands`, command, { headers, timeout: COMMAND_TIMEOUT_MS });
     
-    // Simulate network delay
-    await new Promise(resolve => setTimeout(resolve, 100));
-    
-    // Simulate response based on command type
-    switch (command.command) {
-        case 'lock':
-            return { success: true, message: 'Screen locked successfully' };
-        case 'status':
-            return {
-                success: true,
-                message: JSON.stringify({
-                    platform: 'Windows',
-                    version: '1.0.0',
-                    discord_connected: true,
-                    last_heartbeat: new Date().toISOString()
-                })
-            };
-        case 'ping':
-            return { success: true, message: 'Pong! Device is responsive' };
-        case 'logout':
-            return { success: true, message: 'User logged out successfully' };
-        default:
-            return { success: false, message: 'Unknown command' };
+    const deadline = Date.now() + COMMAND_TIMEOUT_MS;
+    while (Date.now() < deadline) {
+        const wait = Math.max(1, Math.min(30, Math.ceil((deadline - Date.now()) / 1000)));
+        const response = await axios.get(`${deviceUrl}/responses/${command.command_id}?wait=${wait}`, {
+            headers,
+            timeout: (wait + 5) * 1000,
+            validateStatus: () => true
+        });
+        
+        if (response.status === 200) {
+            return response.data;
+        }
+        if (response.status !== 204) {
+            return { success: false, message: `Relay returned HTTP ${response.status}` };
+        }
     }
+    
+    return { success: false, message: 'Timed out waiting for device response' };
 }
 
 // Error handling
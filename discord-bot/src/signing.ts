@@ -0,0 +1,104 @@
+import crypto from 'crypto';
+
+// Canonical command signing envelope, mirrored from agent/src/signing.rs.
+// Golden vectors: tests/fixtures/signing_vectors.json (npm run test:signing)
+export const SIGNATURE_VERSION = 2;
+
+const ENVELOPE_TAG_V1 = 'DNCMD-v1';
+const ENVELOPE_TAG_V2 = 'DNCMD-v2';
+
+const API_TOKEN_KEY_LABEL = 'device-notifier api token v1';
+
+export interface SignedCommand {
+    version: number;
+    command: string;
+    command_id: string;
+    device_id: string;
+    authorized_user: string;
+    roles?: string[];
+    nonce: string;
+    timestamp: string;
+    arguments: Record<string, unknown>;
+    signature: string;
+}
+
+export type UnsignedCommand = Omit<SignedCommand, 'signature'>;
+
+// Sorted keys, no whitespace; floats are rejected because their text form differs from serde_json
+function canonicalJson(value: unknown): string {
+    if (Array.isArray(value)) {
+        return `[${value.map(canonicalJson).join(',')}]`;
+    }
+    if (value !== null && typeof value === 'object') {
+        const entries = Object.keys(value as Record<string, unknown>).sort()
+            .map(key => `${JSON.stringify(key)}:${canonicalJson((value as Record<string, unknown>)[key])}`);
+        return `{${entries.join(',')}}`;
+    }
+    if (typeof value === 'number' && !Number.isInteger(value)) {
+        throw new Error('Command arguments must not contain floating point numbers');
+    }
+    return JSON.stringify(value);
+}
+
+export function canonicalPayload(command: UnsignedCommand): string {
+    const fields = [
+        command.command_id,
+        command.command,
+        command.device_id,
+        command.authorized_user,
+        command.nonce
+    ];
+    const roles = [...(command.roles || [])].sort();
+    
+    if (fields.some(field => !field || field.includes('\n'))) {
+        throw new Error('Command envelope fields must be non-empty single-line strings');
+    }
+    
+    if (roles.some(role => !role || role.includes(',') || role.includes('\n'))) {
+        throw new Error('Command roles must be non-empty and must not contain commas or newlines');
+    }
+    
+    const trailer = [
+        String(Date.parse(command.timestamp)),
+        canonicalJson(command.arguments || {})
+    ];
+    
+    switch (command.version) {
+        case 1:
+            if (roles.length > 0) {
+                throw new Error('Version 1 envelopes cannot carry roles');
+            }
+            return [ENVELOPE_TAG_V1, ...fields, ...trailer].join('\n');
+        case 2:
+            return [ENVELOPE_TAG_V2, ...fields.slice(0, 4), roles.join(','), fields[4], ...trailer].join('\n');
+        default:
+            throw new Error(`Unsupported signature version: ${command.version}`);
+    }
+}
+
+export function signCommand(command: UnsignedCommand, secret: string): string {
+    return crypto.createHmac('sha256', secret)
+        .update(canonicalPayload(command), 'utf8')
+        .digest('base64');
+}
+
+// HS256 key for command API tokens, so a token is never a valid command signature or
+// the other way round; mirrors api_token_key in agent/src/signing.rs
+export function apiTokenKey(secret: string): Buffer {
+    return crypto.createHmac('sha256', secret).update(API_TOKEN_KEY_LABEL, 'utf8').digest();
+}
+
+// HS256 JWT (RFC 7519) for the agent's command API, addressed to one device and
+// checked by agent/src/jwt.rs when api.require_token is set
+export function apiToken(deviceId: string, secret: string, ttlSeconds = 60): string {
+    const now = Math.floor(Date.now() / 1000);
+    const encode = (value: object) => Buffer.from(JSON.stringify(value)).toString('base64url');
+    const signingInput = `${encode({ alg: 'HS256', typ: 'JWT' })}.${encode({
+        iss: 'device-notifier-bot',
+        aud: deviceId,
+        iat: now,
+        exp: now + ttlSeconds
+    })}`;
+    const signature = crypto.createHmac('sha256', apiTokenKey(secret)).update(signingInput, 'utf8').digest('base64url');
+    return `${signingInput}.${signature}`;
+}